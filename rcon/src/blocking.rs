@@ -0,0 +1,222 @@
+//! A synchronous connection to the HLL server using RCON v2, for use without an async runtime.
+use std::{
+    io::{ErrorKind, Read, Write},
+    net::TcpStream,
+    time::Duration,
+};
+
+use tracing::{debug, instrument, trace};
+
+use crate::{
+    commands::*,
+    constants::{next_id, TCP_TIMEOUT},
+    credentials::RconCredentials,
+    framing::{apply_xor, check_response_id, decode_frame_header, encode_request},
+    messages::{RconRequest, RconResponse},
+    parsing::{gamestate::GameState, playerinfo::PlayerData, showlog::LogLine},
+    RconError,
+};
+
+/// An active, blocking connection to the Hell Let Loose server.
+///
+/// Offers the same commands as [`crate::client::RconClient`], sharing its serialisation
+/// and parsing, but every call blocks the current thread until the server responded.
+#[derive(Debug)]
+pub struct BlockingRconConnection {
+    /// A unique ID for this connection.
+    id: u64,
+
+    /// The underlying tcp stream.
+    tcp: TcpStream,
+
+    /// The xor key used for "encryption".
+    xor_key: Option<Vec<u8>>,
+
+    /// The auth token passed with every request.
+    auth_token: Option<String>,
+
+    /// The id of the next request, the server echoes it in its response.
+    next_request_id: u32,
+}
+
+impl BlockingRconConnection {
+    /// Creates a new connection and ensures it can authenticate on the server.
+    #[instrument(level = "debug", skip(credentials), err)]
+    pub fn new(credentials: &RconCredentials) -> Result<Self, RconError> {
+        debug!("Attempting to connect to {}", credentials.address);
+        let mut tcp = TcpStream::connect_timeout(&credentials.address, TCP_TIMEOUT)?;
+        tcp.set_read_timeout(Some(TCP_TIMEOUT))?;
+        tcp.set_write_timeout(Some(TCP_TIMEOUT))?;
+
+        // Discard the V1 xor bytes
+        let mut buffer = [0u8; 4];
+        _ = tcp.read(&mut buffer).map_err(map_io_error)?;
+
+        let mut this = Self {
+            id: next_id(),
+            tcp,
+            xor_key: None,
+            auth_token: None,
+            next_request_id: 1,
+        };
+
+        // Get the xor key
+        let connect_response = this.execute(server_connect_request())?;
+        this.xor_key = Some(parse_xor_key(connect_response)?);
+
+        // Get the auth token
+        let login_response = this.execute(login_request(credentials))?;
+        this.auth_token = Some(parse_auth_token(login_response)?);
+
+        Ok(this)
+    }
+
+    /// Send the command to the server and return the response from the server.
+    pub fn execute(&mut self, mut request: RconRequest) -> Result<RconResponse, RconError> {
+        trace!("Executing '{}' on #{}", request.name, self.id);
+
+        if let Some(auth_token) = &self.auth_token {
+            request.auth_token = auth_token.clone();
+        }
+
+        let request_id = self.next_request_id;
+        self.next_request_id = self.next_request_id.wrapping_add(1);
+
+        let frame = encode_request(request_id, request.serialize(), self.xor_key.as_deref());
+        self.tcp.write_all(&frame).map_err(map_io_error)?;
+
+        let mut header = [0u8; 8];
+        self.tcp.read_exact(&mut header).map_err(map_io_error)?;
+        let (response_id, length) = decode_frame_header(header)?;
+
+        let mut content = vec![0; length as usize];
+        self.tcp.read_exact(&mut content).map_err(map_io_error)?;
+        check_response_id(request_id, response_id)?;
+
+        apply_xor(&mut content, self.xor_key.as_deref());
+        RconResponse::decode(&content)
+    }
+
+    /// The id of this connection.
+    pub fn id(&self) -> u64 {
+        self.id
+    }
+
+    /// Get all the players on the server and their information.
+    pub fn fetch_players(&mut self) -> Result<Vec<PlayerData>, RconError> {
+        parse_players(self.execute(players_request())?)
+    }
+
+    /// Get the player data for a single player.
+    pub fn fetch_player(&mut self, id: String) -> Result<PlayerData, RconError> {
+        parse_player(self.execute(player_request(id))?)
+    }
+
+    /// Get the logs from the server which occurred within the `backtrack` window.
+    ///
+    /// The server only accepts whole minutes, as such the window is rounded up. Should
+    /// `filters` not be empty only logs containing any of the filters are returned.
+    pub fn fetch_showlog(
+        &mut self,
+        backtrack: Duration,
+        filters: &[&str],
+    ) -> Result<Vec<LogLine>, RconError> {
+        parse_showlog(self.execute(showlog_request(backtrack, filters))?)
+    }
+
+    /// Get the current game state from the server. Like its async counterpart not yet
+    /// supported over RCON v2.
+    pub fn fetch_gamestate(&mut self) -> Result<GameState, RconError> {
+        Err(RconError::Unsupported("fetch_gamestate"))
+    }
+
+    /// Broadcast a message to the entire server.
+    pub fn broadcast_message(&mut self, message: &str) -> Result<(), RconError> {
+        parse_success(self.execute(broadcast_request(message))?)
+    }
+
+    /// Send a message to an individual player.
+    pub fn individual_message(&mut self, id: &str, message: &str) -> Result<(), RconError> {
+        parse_success(self.execute(message_request(id, message))?)
+    }
+
+    /// Punish a player by killing them.
+    pub fn punish_player(&mut self, id: &str, reason: &str) -> Result<(), RconError> {
+        parse_success(self.execute(punish_request(id, reason))?)
+    }
+
+    /// Kick a player from the server.
+    pub fn kick_player(&mut self, id: &str, reason: &str) -> Result<(), RconError> {
+        parse_success(self.execute(kick_request(id, reason))?)
+    }
+
+    /// Ban a player from the server for the given amount of hours.
+    pub fn temp_ban_player(&mut self, id: &str, hours: u32, reason: &str) -> Result<(), RconError> {
+        parse_success(self.execute(temp_ban_request(id, hours, reason))?)
+    }
+
+    /// Lift a temporary ban of a player.
+    pub fn remove_temp_ban_player(&mut self, id: &str) -> Result<(), RconError> {
+        parse_success(self.execute(remove_temp_ban_request(id))?)
+    }
+}
+
+/// Blocking sockets report an elapsed read or write timeout as an io error.
+fn map_io_error(error: std::io::Error) -> RconError {
+    match error.kind() {
+        ErrorKind::WouldBlock | ErrorKind::TimedOut => RconError::TimeOut,
+        kind => RconError::IoError(kind),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::runtime::Runtime;
+
+    use crate::testing::mock_server;
+
+    use super::*;
+
+    #[test]
+    fn commands_are_answered() {
+        let runtime = Runtime::new().unwrap();
+        let credentials = runtime.block_on(mock_server());
+
+        let mut connection = BlockingRconConnection::new(&credentials).unwrap();
+        let response = connection
+            .execute(RconRequest::new("Echo", "body"))
+            .unwrap();
+        assert_eq!(response.name, "Echo");
+        assert_eq!(response.content_body, "body");
+
+        let other = BlockingRconConnection::new(&credentials).unwrap();
+        assert_ne!(connection.id(), other.id());
+    }
+
+    #[test]
+    fn responses_with_another_id_are_rejected() {
+        let runtime = Runtime::new().unwrap();
+        let credentials = runtime.block_on(mock_server());
+
+        let mut connection = BlockingRconConnection::new(&credentials).unwrap();
+        let result = connection.execute(RconRequest::new("WrongId", ""));
+        assert!(matches!(result, Err(RconError::ProtocolViolation(_))));
+    }
+
+    #[test]
+    fn silent_servers_time_out() {
+        let runtime = Runtime::new().unwrap();
+        let credentials = runtime.block_on(mock_server());
+
+        let mut connection = BlockingRconConnection::new(&credentials).unwrap();
+        let result = connection.execute(RconRequest::new("Silent", ""));
+        assert_eq!(result.unwrap_err(), RconError::TimeOut);
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn connecting_within_a_runtime_does_not_panic() {
+        let credentials = mock_server().await;
+        let connection = tokio::task::block_in_place(|| BlockingRconConnection::new(&credentials));
+        assert!(connection.is_ok());
+    }
+}
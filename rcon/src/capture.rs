@@ -0,0 +1,243 @@
+//! Capturing the raw traffic of RCON sessions and replaying it offline.
+//!
+//! A capture file is newline delimited JSON, each line a [`CaptureEntry`] holding a request
+//! and the body of its response after the xor has been removed, but before it is parsed.
+//! As such a capture reproduces parser failures exactly as they happened on the live server.
+use std::{
+    collections::{HashMap, VecDeque},
+    fs::{File, OpenOptions},
+    io::{BufRead, BufReader, BufWriter, Write},
+    path::Path,
+    sync::{Arc, Mutex},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use serde::{Deserialize, Serialize};
+use tracing::warn;
+
+use crate::{
+    client::RconClient,
+    credentials::RconCredentials,
+    messages::{RconRequest, RconResponse},
+    RconError,
+};
+
+/// A single request and the response the server sent back.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CaptureEntry {
+    /// Unix timestamp in milliseconds at which the response was received.
+    pub timestamp: u64,
+
+    /// The request as it was sent, without the auth token.
+    pub request: RconRequest,
+
+    /// The body of the response, decoded lossily as utf8.
+    pub response: String,
+}
+
+/// Writes every request and response of the connections it is set on to a capture file.
+/// Clones share the same file.
+#[derive(Debug, Clone)]
+pub struct CaptureRecorder {
+    writer: Arc<Mutex<BufWriter<File>>>,
+}
+
+impl CaptureRecorder {
+    /// Create the capture file at the given path, appending to it should it exist such that
+    /// restarts keep the traffic captured before.
+    pub fn create(path: impl AsRef<Path>) -> Result<Self, RconError> {
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        Ok(Self {
+            writer: Arc::new(Mutex::new(BufWriter::new(file))),
+        })
+    }
+
+    /// Append the request and raw response to the capture file.
+    /// Failing to write is logged but never fails the command itself.
+    pub fn record(&self, request: &RconRequest, response: &[u8]) {
+        let mut request = request.clone();
+        request.auth_token = String::new();
+        if request.name == "Login" {
+            request.content_body = String::new();
+        }
+
+        let entry = CaptureEntry {
+            timestamp: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_millis() as u64,
+            request,
+            response: String::from_utf8_lossy(response).into_owned(),
+        };
+
+        let mut writer = self.writer.lock().unwrap();
+        let res = serde_json::to_writer(&mut *writer, &entry)
+            .map_err(std::io::Error::from)
+            .and_then(|_| writer.write_all(b"\n"))
+            .and_then(|_| writer.flush());
+        if let Err(e) = res {
+            warn!("Failed to write to the capture file: {e}");
+        }
+    }
+}
+
+/// Read all the entries of a capture file.
+pub fn read_capture(path: impl AsRef<Path>) -> Result<Vec<CaptureEntry>, RconError> {
+    let reader = BufReader::new(File::open(path)?);
+    let mut entries = Vec::new();
+    for line in reader.lines() {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+
+        entries.push(serde_json::from_str(&line).map_err(|_| RconError::InvalidJson)?);
+    }
+
+    Ok(entries)
+}
+
+/// A client serving the responses of a capture instead of talking to a server.
+///
+/// Requests are matched by their name and body, admin logs by their name alone as the
+/// window they request depends on when they were sent. Recorded responses to the same
+/// request are served in capture order, once exhausted the last one is repeated.
+#[derive(Debug)]
+pub struct ReplayConnection {
+    id: u64,
+    responses: HashMap<(String, String), VecDeque<String>>,
+}
+
+impl ReplayConnection {
+    /// Create a client serving the given entries.
+    pub fn new(entries: impl IntoIterator<Item = CaptureEntry>) -> Self {
+        let mut responses: HashMap<_, VecDeque<_>> = HashMap::new();
+        for entry in entries {
+            responses
+                .entry(replay_key(entry.request))
+                .or_default()
+                .push_back(entry.response);
+        }
+
+        Self { id: 0, responses }
+    }
+
+    /// Create a client serving the entries of a capture file.
+    pub fn from_file(path: impl AsRef<Path>) -> Result<Self, RconError> {
+        Ok(Self::new(read_capture(path)?))
+    }
+}
+
+/// Replays can not connect to anything, create them with [`ReplayConnection::from_file`].
+impl RconClient for ReplayConnection {
+    async fn connect(_credentials: &RconCredentials) -> Result<Self, RconError> {
+        Err(RconError::Unsupported("connect"))
+    }
+
+    fn id(&self) -> u64 {
+        self.id
+    }
+
+    async fn execute(&mut self, request: RconRequest) -> Result<RconResponse, RconError> {
+        let recorded =
+            self.responses
+                .get_mut(&replay_key(request))
+                .ok_or(RconError::InvalidData(
+                    "The capture holds no response to the request.",
+                ))?;
+
+        let response = match recorded.len() {
+            1 => recorded[0].clone(),
+            _ => recorded.pop_front().unwrap_or_default(),
+        };
+
+        RconResponse::decode(response.as_bytes())
+    }
+}
+
+/// The key recorded responses are looked up by.
+fn replay_key(request: RconRequest) -> (String, String) {
+    match request.name.as_str() {
+        "AdminLog" => (request.name, String::new()),
+        _ => (request.name, request.content_body),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use serde_json::json;
+
+    use crate::commands::{player_request, showlog_request};
+
+    use super::*;
+
+    fn entry(request: RconRequest, content_body: &str) -> CaptureEntry {
+        let response = json!({
+            "statusCode": 200,
+            "statusMessage": "OK",
+            "version": 2,
+            "name": request.name,
+            "contentBody": content_body,
+        });
+        CaptureEntry {
+            timestamp: 0,
+            request,
+            response: response.to_string(),
+        }
+    }
+
+    async fn replay(connection: &mut ReplayConnection, request: RconRequest) -> String {
+        connection.execute(request).await.unwrap().content_body
+    }
+
+    #[tokio::test]
+    async fn admin_logs_are_served_in_order_regardless_of_their_window() {
+        let mut connection = ReplayConnection::new([
+            entry(showlog_request(Duration::from_secs(60), &[]), "first"),
+            entry(showlog_request(Duration::from_secs(120), &[]), "second"),
+        ]);
+
+        let request = || showlog_request(Duration::from_secs(300), &[]);
+        assert_eq!(replay(&mut connection, request()).await, "first");
+        assert_eq!(replay(&mut connection, request()).await, "second");
+        assert_eq!(replay(&mut connection, request()).await, "second");
+    }
+
+    #[test]
+    fn captures_are_appended_to() {
+        let path = std::env::temp_dir().join(format!("wise-capture-{}.ndjson", std::process::id()));
+        _ = std::fs::remove_file(&path);
+
+        for body in ["first", "second"] {
+            let recorder = CaptureRecorder::create(&path).unwrap();
+            recorder.record(&RconRequest::new("Echo", body), b"response");
+        }
+
+        let entries = read_capture(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        let bodies: Vec<_> = entries
+            .iter()
+            .map(|e| e.request.content_body.as_str())
+            .collect();
+        assert_eq!(bodies, ["first", "second"]);
+    }
+
+    #[tokio::test]
+    async fn other_requests_are_matched_by_their_body() {
+        let mut connection = ReplayConnection::new([
+            entry(player_request("a".to_string()), "player a"),
+            entry(player_request("b".to_string()), "player b"),
+        ]);
+
+        assert_eq!(
+            replay(&mut connection, player_request("b".to_string())).await,
+            "player b"
+        );
+        assert!(connection
+            .execute(player_request("c".to_string()))
+            .await
+            .is_err());
+    }
+}
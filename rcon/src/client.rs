@@ -0,0 +1,144 @@
+//! The commands shared by all kinds of connections to the HLL server.
+use std::{future::Future, time::Duration};
+
+use crate::{
+    capture::CaptureRecorder,
+    commands::*,
+    credentials::RconCredentials,
+    messages::{RconRequest, RconResponse},
+    parsing::{gamestate::GameState, playerinfo::PlayerData, showlog::LogLine},
+    ratelimit::RateLimiter,
+    RconError,
+};
+
+/// A client able to execute commands on the HLL server, regardless of the transport used.
+///
+/// Implementors have to provide how to connect and [`RconClient::execute`], the typed commands
+/// default to the RCON v2 requests. Transports speaking a different protocol override them.
+pub trait RconClient: Send + Sized + 'static {
+    /// Create a new connection and ensure it can authenticate on the server.
+    fn connect(
+        credentials: &RconCredentials,
+    ) -> impl Future<Output = Result<Self, RconError>> + Send;
+
+    /// A unique ID for this connection.
+    fn id(&self) -> u64;
+
+    /// Cheaply check whether the connection is still usable without a round trip to the server.
+    fn is_healthy(&self) -> bool {
+        true
+    }
+
+    /// Another handle sending its requests over the same connection, should the client be able
+    /// to have multiple requests in flight at once. [`None`] for all other clients.
+    fn share(&self) -> Option<Self> {
+        None
+    }
+
+    /// Limit the commands sent over this connection only. Ignored by clients without limits.
+    fn set_rate_limiter(&mut self, _limiter: Option<RateLimiter>) {}
+
+    /// Limit the commands sent over this connection together with others sharing the limiter.
+    /// Ignored by clients without limits.
+    fn set_shared_rate_limiter(&mut self, _limiter: Option<RateLimiter>) {}
+
+    /// Record every following request and its response to a capture file.
+    /// Ignored by clients unable to record.
+    fn set_recorder(&mut self, _recorder: Option<CaptureRecorder>) {}
+
+    /// Send the request to the server and return its response.
+    fn execute(
+        &mut self,
+        request: RconRequest,
+    ) -> impl Future<Output = Result<RconResponse, RconError>> + Send;
+
+    /// Get all the players on the server and their information.
+    fn fetch_players(&mut self) -> impl Future<Output = Result<Vec<PlayerData>, RconError>> + Send {
+        async move { parse_players(self.execute(players_request()).await?) }
+    }
+
+    /// Get the player data for a single player.
+    fn fetch_player(
+        &mut self,
+        id: String,
+    ) -> impl Future<Output = Result<PlayerData, RconError>> + Send {
+        async move { parse_player(self.execute(player_request(id)).await?) }
+    }
+
+    /// Get the logs from the server which occurred within the `backtrack` window.
+    ///
+    /// The server only accepts whole minutes, as such the window is rounded up. Should
+    /// `filters` not be empty only logs containing any of the filters are returned.
+    fn fetch_showlog(
+        &mut self,
+        backtrack: Duration,
+        filters: &[&str],
+    ) -> impl Future<Output = Result<Vec<LogLine>, RconError>> + Send {
+        let request = showlog_request(backtrack, filters);
+        async move { parse_showlog(self.execute(request).await?) }
+    }
+
+    /// Get the current game state from the server.
+    fn fetch_gamestate(&mut self) -> impl Future<Output = Result<GameState, RconError>> + Send {
+        async { Err(RconError::Unsupported("fetch_gamestate")) }
+    }
+
+    /// Broadcast a message to the entire server.
+    fn broadcast_message(
+        &mut self,
+        message: &str,
+    ) -> impl Future<Output = Result<(), RconError>> + Send {
+        let request = broadcast_request(message);
+        async move { parse_success(self.execute(request).await?) }
+    }
+
+    /// Send a message to an individual player.
+    fn individual_message(
+        &mut self,
+        id: &str,
+        message: &str,
+    ) -> impl Future<Output = Result<(), RconError>> + Send {
+        let request = message_request(id, message);
+        async move { parse_success(self.execute(request).await?) }
+    }
+
+    /// Punish a player by killing them.
+    fn punish_player(
+        &mut self,
+        id: &str,
+        reason: &str,
+    ) -> impl Future<Output = Result<(), RconError>> + Send {
+        let request = punish_request(id, reason);
+        async move { parse_success(self.execute(request).await?) }
+    }
+
+    /// Kick a player from the server.
+    fn kick_player(
+        &mut self,
+        id: &str,
+        reason: &str,
+    ) -> impl Future<Output = Result<(), RconError>> + Send {
+        let request = kick_request(id, reason);
+        async move { parse_success(self.execute(request).await?) }
+    }
+
+    /// Ban a player from the server for the given amount of hours.
+    fn temp_ban_player(
+        &mut self,
+        id: &str,
+        hours: u32,
+        reason: &str,
+    ) -> impl Future<Output = Result<(), RconError>> + Send {
+        let request = temp_ban_request(id, hours, reason);
+        async move { parse_success(self.execute(request).await?) }
+    }
+
+    /// Lift a temporary ban of a player.
+    fn remove_temp_ban_player(
+        &mut self,
+        id: &str,
+    ) -> impl Future<Output = Result<(), RconError>> + Send {
+        let request = remove_temp_ban_request(id);
+        async move { parse_success(self.execute(request).await?) }
+    }
+}
@@ -1,151 +1,287 @@
+use std::time::Duration;
+
+use base64::{prelude::BASE64_STANDARD, Engine};
+use serde::de::DeserializeOwned;
 use serde_json::{json, Value};
-use tracing::trace;
 
+#[cfg(feature = "async")]
+use crate::{
+    capture::CaptureRecorder, client::RconClient, connection::RconConnection,
+    multiplexed::MultiplexedConnection, ratelimit::RateLimiter,
+};
 use crate::{
-    connection::RconConnection,
-    messages::RconRequest,
+    credentials::RconCredentials,
+    messages::{escape_control_characters, RconRequest, RconResponse},
     parsing::{
-        gamestate::GameState,
         playerinfo::PlayerData,
-        showlog::{take_logline, LogLine},
+        showlog::{parse_loglines, LogLine},
     },
     RconError,
 };
 
-impl RconConnection {
-    /// Get all the players on the server and their information.
-    pub async fn fetch_players(&mut self) -> Result<Vec<PlayerData>, RconError> {
-        let response = self
-            .execute(RconRequest::with_body(
-                "ServerInformation",
-                json!({
-                    "Name": "players",
-                    "Value": ""
-                }),
-            ))
-            .await?;
-
-        let value: Value =
-            serde_json::from_str(&response.content_body).map_err(|_| RconError::InvalidJson)?;
-
-        serde_json::from_value(
-            value
-                .get("players")
-                .cloned()
-                .ok_or(RconError::InvalidJson)?,
-        )
-        .map_err(|_| RconError::InvalidJson)
+#[cfg(feature = "async")]
+impl RconClient for RconConnection {
+    async fn connect(credentials: &RconCredentials) -> Result<Self, RconError> {
+        RconConnection::new(credentials).await
     }
 
-    /// Get the player data for a single player.
-    pub async fn fetch_player(&mut self, id: String) -> Result<PlayerData, RconError> {
-        let response = self
-            .execute(RconRequest::with_body(
-                "ServerInformation",
-                json!({
-                    "Name": "player",
-                    "Value": id
-                }),
-            ))
-            .await?;
-
-        serde_json::from_str(&response.content_body).map_err(|_| RconError::InvalidJson)
-    }
-
-    /// Get the logs from the server.
-    pub async fn fetch_showlog(&mut self) -> Result<Vec<LogLine>, RconError> {
-        let response = self
-            .execute(RconRequest::with_body(
-                "AdminLog",
-                json!({
-                    "LogBackTrackTime": "60",
-                    "Filters": []
-                }),
-            ))
-            .await?;
-
-        let parsed: Value = serde_json::from_str(&response.content_body).unwrap();
-        let loglines = parsed
-            .get("entries")
-            .ok_or(RconError::InvalidJson)?
-            .as_array()
-            .ok_or(RconError::InvalidJson)?
-            .iter()
-            .filter_map(|v| {
-                v.get("message")
-                    .map(|v| v.as_str())
-                    .flatten()
-                    .map(|v| take_logline(v).ok().map(|v| v.1))
-                    .flatten()
-                    .flatten()
-            })
-            .collect::<Vec<_>>();
+    fn id(&self) -> u64 {
+        RconConnection::id(self)
+    }
 
-        Ok(loglines)
+    fn is_healthy(&self) -> bool {
+        RconConnection::is_healthy(self)
     }
 
-    /// Get the current game state from the server.
-    pub async fn fetch_gamestate(&mut self) -> Result<GameState, RconError> {
-        todo!()
+    fn set_rate_limiter(&mut self, limiter: Option<RateLimiter>) {
+        RconConnection::set_rate_limiter(self, limiter)
     }
 
-    /// Broadcast a message to the entire server.
-    pub async fn broadcast_message(&mut self, message: &str) -> Result<(), RconError> {
-        self.execute(RconRequest::new("ServerBroadcast", message))
-            .await?;
+    fn set_shared_rate_limiter(&mut self, limiter: Option<RateLimiter>) {
+        RconConnection::set_shared_rate_limiter(self, limiter)
+    }
 
-        Ok(())
+    fn set_recorder(&mut self, recorder: Option<CaptureRecorder>) {
+        RconConnection::set_recorder(self, recorder)
     }
 
-    /// Send a message to an individual player.
-    pub async fn individual_message(&mut self, id: &str, message: &str) -> Result<(), RconError> {
-        self.execute(RconRequest::with_body(
-            "MessagePlayer",
-            json!({
-                "PlayerId": id,
-                "Message": message
-            }),
-        ))
-        .await?;
+    async fn execute(&mut self, request: RconRequest) -> Result<RconResponse, RconError> {
+        RconConnection::execute(self, request).await
+    }
+}
 
-        Ok(())
+#[cfg(feature = "async")]
+impl RconClient for MultiplexedConnection {
+    async fn connect(credentials: &RconCredentials) -> Result<Self, RconError> {
+        Ok(RconConnection::new(credentials).await?.into_multiplexed())
     }
 
-    /// Punish a player by killing them.
-    pub async fn punish_player(&mut self, id: &str, reason: &str) -> Result<(), RconError> {
-        self.execute(RconRequest::with_body(
-            "PunishPlayer",
-            json!({
-                "PlayerId": id,
-                "Reason": reason
-            }),
-        ))
-        .await?;
+    fn id(&self) -> u64 {
+        MultiplexedConnection::id(self)
+    }
+
+    fn is_healthy(&self) -> bool {
+        !self.is_closed()
+    }
+
+    fn share(&self) -> Option<Self> {
+        Some(self.clone())
+    }
+
+    fn set_rate_limiter(&mut self, limiter: Option<RateLimiter>) {
+        MultiplexedConnection::set_rate_limiter(self, limiter)
+    }
 
-        Ok(())
+    fn set_shared_rate_limiter(&mut self, limiter: Option<RateLimiter>) {
+        MultiplexedConnection::set_shared_rate_limiter(self, limiter)
     }
 
-    /// Kick a player from the server.
-    pub async fn kick_player(&mut self, id: &str, reason: &str) -> Result<(), RconError> {
-        self.execute(RconRequest::with_body(
-            "Kick",
-            json!({
-                "PlayerId": id,
-                "Reason": reason
-            }),
-        ))
-        .await?;
+    fn set_recorder(&mut self, recorder: Option<CaptureRecorder>) {
+        MultiplexedConnection::set_recorder(self, recorder)
+    }
+
+    async fn execute(&mut self, request: RconRequest) -> Result<RconResponse, RconError> {
+        MultiplexedConnection::execute(self, request).await
+    }
+}
+
+// The requests and response parsing of the typed commands, shared by all kinds of connections.
+
+pub(crate) fn server_connect_request() -> RconRequest {
+    RconRequest::new("ServerConnect", "")
+}
+
+/// Get the xor key used for all following frames.
+pub(crate) fn parse_xor_key(response: RconResponse) -> Result<Vec<u8>, RconError> {
+    response.assert_ok(RconError::InvalidData(
+        "Server responded with failure status code on 'ServerConnect' command.",
+    ))?;
+
+    BASE64_STANDARD
+        .decode(&response.content_body)
+        .map_err(|_| RconError::InvalidData("Failed to decode xor key."))
+}
+
+pub(crate) fn login_request(credentials: &RconCredentials) -> RconRequest {
+    RconRequest::new("Login", credentials.password.clone())
+}
+
+/// Get the auth token passed with every following request.
+pub(crate) fn parse_auth_token(response: RconResponse) -> Result<String, RconError> {
+    response.assert_ok(RconError::InvalidPassword)?;
+    Ok(response.content_body)
+}
+
+pub(crate) fn players_request() -> RconRequest {
+    RconRequest::with_body(
+        "ServerInformation",
+        json!({
+            "Name": "players",
+            "Value": ""
+        }),
+    )
+}
+
+/// Parse the JSON within the content body of a response. Like the response itself, it holds
+/// control characters such as newlines in chat messages unescaped.
+fn parse_content_body<T: DeserializeOwned>(response: &RconResponse) -> Result<T, RconError> {
+    serde_json::from_str(&escape_control_characters(&response.content_body))
+        .map_err(|_| RconError::InvalidJson)
+}
+
+pub(crate) fn parse_players(response: RconResponse) -> Result<Vec<PlayerData>, RconError> {
+    let response = response.into_result()?;
+    let value: Value = parse_content_body(&response)?;
+
+    serde_json::from_value(
+        value
+            .get("players")
+            .cloned()
+            .ok_or(RconError::InvalidJson)?,
+    )
+    .map_err(|_| RconError::InvalidJson)
+}
+
+pub(crate) fn player_request(id: String) -> RconRequest {
+    RconRequest::with_body(
+        "ServerInformation",
+        json!({
+            "Name": "player",
+            "Value": id
+        }),
+    )
+}
+
+pub(crate) fn parse_player(response: RconResponse) -> Result<PlayerData, RconError> {
+    let response = response.into_result()?;
+    parse_content_body(&response)
+}
 
-        Ok(())
+pub(crate) fn showlog_request(backtrack: Duration, filters: &[&str]) -> RconRequest {
+    let minutes = backtrack.as_secs().div_ceil(60).max(1);
+    RconRequest::with_body(
+        "AdminLog",
+        json!({
+            "LogBackTrackTime": minutes.to_string(),
+            "Filters": filters
+        }),
+    )
+}
+
+pub(crate) fn parse_showlog(response: RconResponse) -> Result<Vec<LogLine>, RconError> {
+    let response = response.into_result()?;
+    let parsed: Value = parse_content_body(&response)?;
+    let messages = parsed
+        .get("entries")
+        .ok_or(RconError::InvalidJson)?
+        .as_array()
+        .ok_or(RconError::InvalidJson)?
+        .iter()
+        .filter_map(|v| v.get("message").and_then(|v| v.as_str()));
+
+    Ok(parse_loglines(messages))
+}
+
+pub(crate) fn broadcast_request(message: &str) -> RconRequest {
+    RconRequest::new("ServerBroadcast", message)
+}
+
+pub(crate) fn message_request(id: &str, message: &str) -> RconRequest {
+    RconRequest::with_body(
+        "MessagePlayer",
+        json!({
+            "PlayerId": id,
+            "Message": message
+        }),
+    )
+}
+
+pub(crate) fn punish_request(id: &str, reason: &str) -> RconRequest {
+    RconRequest::with_body(
+        "PunishPlayer",
+        json!({
+            "PlayerId": id,
+            "Reason": reason
+        }),
+    )
+}
+
+pub(crate) fn kick_request(id: &str, reason: &str) -> RconRequest {
+    RconRequest::with_body(
+        "Kick",
+        json!({
+            "PlayerId": id,
+            "Reason": reason
+        }),
+    )
+}
+
+/// The admin name temporary bans are issued under.
+pub(crate) const BAN_ADMIN_NAME: &str = "wise";
+
+pub(crate) fn temp_ban_request(id: &str, hours: u32, reason: &str) -> RconRequest {
+    RconRequest::with_body(
+        "TemporaryBan",
+        json!({
+            "PlayerId": id,
+            "Duration": hours,
+            "Reason": reason,
+            "AdminName": BAN_ADMIN_NAME
+        }),
+    )
+}
+
+pub(crate) fn remove_temp_ban_request(id: &str) -> RconRequest {
+    RconRequest::with_body(
+        "RemoveTemporaryBan",
+        json!({
+            "PlayerId": id
+        }),
+    )
+}
+
+/// Fail should the server not respond with success for commands without a response body.
+pub(crate) fn parse_success(response: RconResponse) -> Result<(), RconError> {
+    response.into_result().map(|_| ())
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::parsing::showlog::LogKind;
+
+    use super::*;
+
+    fn response(name: &str, content_body: &str) -> RconResponse {
+        RconResponse {
+            status_code: 200,
+            status_message: "OK".to_string(),
+            version: 2,
+            name: name.to_string(),
+            content_body: content_body.to_string(),
+        }
     }
 
-    /// Temporarily ban a player from the server.
-    pub async fn temp_ban(&mut self, id: String, message: String) -> Result<(), RconError> {
-        todo!()
+    #[test]
+    fn logs_with_raw_newlines_are_parsed() {
+        let content = "{\"entries\": [{\"message\": \"[1.2 sec (1718212472)] CHAT[Team][Player Name(Allies/11111111111111111)]: foo\nbar\"}]}";
+        let wire = serde_json::to_string(&response("AdminLog", content)).unwrap();
+        let response = RconResponse::decode(wire.as_bytes()).unwrap();
+
+        let logs = parse_showlog(response).unwrap();
+        assert_eq!(logs.len(), 1);
+        assert!(matches!(&logs[0].kind, LogKind::Chat { content, .. } if content == "foo\nbar"));
     }
 
-    /// Remove a temporary ban.
-    pub async fn remove_temp_ban(&mut self, id: String) -> Result<(), RconError> {
-        todo!()
+    #[test]
+    fn rejected_commands_are_not_parsed() {
+        let mut rejected = response("AdminLog", "");
+        rejected.status_code = 500;
+        assert!(matches!(
+            parse_showlog(rejected),
+            Err(RconError::ServerRejected {
+                status_code: 500,
+                ..
+            })
+        ));
     }
 }
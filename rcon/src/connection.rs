@@ -1,7 +1,6 @@
 //! A connection to the HLL server using RCON v2.
 use std::time::Duration;
 
-use base64::{prelude::BASE64_STANDARD, Engine};
 use tokio::{
     io::{self, AsyncReadExt, AsyncWriteExt},
     net::TcpStream,
@@ -9,7 +8,17 @@ use tokio::{
 };
 use tracing::{debug, instrument, trace};
 
-use crate::{constants::next_id, credentials::RconCredentials, messages::RconRequest, *};
+use crate::{
+    capture::CaptureRecorder,
+    commands::{login_request, parse_auth_token, parse_xor_key, server_connect_request},
+    constants::{next_id, TCP_TIMEOUT},
+    credentials::RconCredentials,
+    framing::{apply_xor, check_response_id, decode_frame_header, encode_request},
+    messages::RconRequest,
+    multiplexed::MultiplexedConnection,
+    ratelimit::RateLimiter,
+    *,
+};
 
 use super::messages::RconResponse;
 
@@ -27,6 +36,18 @@ pub struct RconConnection {
 
     /// The auth token passed with every request.
     auth_token: Option<String>,
+
+    /// The id of the next request, the server echoes it in its response.
+    next_request_id: u32,
+
+    /// Limits the commands sent over this connection only.
+    rate_limiter: Option<RateLimiter>,
+
+    /// Limits the commands sent over this and other connections.
+    shared_rate_limiter: Option<RateLimiter>,
+
+    /// Records every request and response once set.
+    recorder: Option<CaptureRecorder>,
 }
 
 impl RconConnection {
@@ -40,108 +61,208 @@ impl RconConnection {
         let mut buffer = [0u8; 4];
         _ = tcp.read(&mut buffer).await?;
 
-        let id = next_id().await;
+        let id = next_id();
         let mut this = Self {
             id,
             tcp,
             xor_key: None,
             auth_token: None,
+            next_request_id: 1,
+            rate_limiter: None,
+            shared_rate_limiter: None,
+            recorder: None,
         };
 
         // Get the xor key
-        let connect_response = this.execute(RconRequest::new("ServerConnect", "")).await?;
-        connect_response.assert_ok(RconError::InvalidData(
-            "Server responded with failure status code on 'ServerConnect' command.",
-        ))?;
-
-        let xor_key = BASE64_STANDARD
-            .decode(connect_response.content_body)
-            .map_err(|_| RconError::InvalidData("Failed to decode xor key."))?;
-        this.xor_key = Some(xor_key);
+        let connect_response = this.execute(server_connect_request()).await?;
+        this.xor_key = Some(parse_xor_key(connect_response)?);
 
         // Get the auth token
-        let login_response = this
-            .execute(RconRequest::new("Login", credentials.password.clone()))
-            .await?;
-        login_response.assert_ok(RconError::InvalidPassword)?;
-
-        let auth_token = login_response.content_body;
-        this.auth_token = Some(auth_token.to_string());
+        let login_response = this.execute(login_request(credentials)).await?;
+        this.auth_token = Some(parse_auth_token(login_response)?);
 
         Ok(this)
     }
 
     /// Send the command to the server and return the response from the server.
     pub async fn execute(&mut self, mut request: RconRequest) -> Result<RconResponse, RconError> {
+        self.wait_for_rate_limit(&request).await;
         trace!("Executing '{}' on #{}", request.name, self.id);
 
         if let Some(auth_token) = &self.auth_token {
             request.auth_token = auth_token.clone();
         }
 
-        self.write(request.serialize()).await?;
-        let response = self.read().await?;
-        Ok(response)
+        let request_id = self.next_request_id;
+        self.next_request_id = self.next_request_id.wrapping_add(1);
+
+        let xor_key = self.xor_key.as_deref();
+        self.tcp
+            .write_all(&encode_request(request_id, request.serialize(), xor_key))
+            .await?;
+
+        // A server going silent before even sending the header would block forever.
+        let (response_id, content) =
+            match timeout(TCP_TIMEOUT, read_raw_frame(&mut self.tcp, xor_key)).await {
+                Ok(res) => res?,
+                Err(_) => return Err(RconError::TimeOut),
+            };
+        check_response_id(request_id, response_id)?;
+
+        if let Some(recorder) = &self.recorder {
+            recorder.record(&request, &content);
+        }
+        RconResponse::decode(&content)
+    }
+
+    /// Limit the commands sent over this connection only.
+    pub fn set_rate_limiter(&mut self, limiter: Option<RateLimiter>) {
+        self.rate_limiter = limiter;
     }
 
-    /// Takes a buffer, applies the xor to it and writes it to the stream.
-    async fn write(&mut self, mut buffer: Vec<u8>) -> Result<(), RconError> {
-        self.apply_xor(&mut buffer);
-        self.tcp.write(&buffer).await?;
+    /// Limit the commands sent over this connection together with others sharing the limiter.
+    pub fn set_shared_rate_limiter(&mut self, limiter: Option<RateLimiter>) {
+        self.shared_rate_limiter = limiter;
+    }
 
-        Ok(())
+    /// Record every following request and its response to a capture file.
+    /// The connection handshake preceding this call is never recorded.
+    pub fn set_recorder(&mut self, recorder: Option<CaptureRecorder>) {
+        self.recorder = recorder;
     }
 
-    /// Read the next response from the server.
-    async fn read(&mut self) -> Result<RconResponse, RconError> {
-        let _header_id = read_exact_u32(&mut self.tcp).await?;
-        let header_length = read_exact_u32(&mut self.tcp).await?;
+    /// Wait until the rate limiters allow sending the request.
+    async fn wait_for_rate_limit(&self, request: &RconRequest) {
+        wait_for_rate_limits(
+            [&self.rate_limiter, &self.shared_rate_limiter],
+            &request.name,
+            self.id,
+        )
+        .await;
+    }
+
+    /// Cheaply check whether the connection is still usable without a round trip to the server.
+    /// A closed socket or unexpected data waiting to be read render the connection unusable.
+    pub fn is_healthy(&self) -> bool {
+        let mut buffer = [0u8; 1];
+        match self.tcp.try_read(&mut buffer) {
+            Err(e) => e.kind() == io::ErrorKind::WouldBlock,
+            Ok(_) => false,
+        }
+    }
+
+    /// The id of this connection.
+    pub fn id(&self) -> u64 {
+        self.id
+    }
 
-        let mut content = vec![0; header_length as usize];
-        self.read_with_timeout(&mut content).await?;
+    /// Turn this connection into one which can have multiple requests in flight at once.
+    /// The rate limiters and recorder are kept.
+    pub fn into_multiplexed(self) -> MultiplexedConnection {
+        let mut multiplexed = MultiplexedConnection::new(
+            self.id,
+            self.tcp,
+            self.xor_key,
+            self.auth_token,
+            self.next_request_id,
+        );
+        multiplexed.set_rate_limiter(self.rate_limiter);
+        multiplexed.set_shared_rate_limiter(self.shared_rate_limiter);
+        multiplexed.set_recorder(self.recorder);
+        multiplexed
+    }
+}
 
-        self.apply_xor(&mut content);
+/// Wait until all the given rate limiters allow sending the request.
+pub(crate) async fn wait_for_rate_limits(
+    limiters: [&Option<RateLimiter>; 2],
+    command: &str,
+    connection_id: u64,
+) {
+    let mut delay = Duration::ZERO;
+    for limiter in limiters.into_iter().flatten() {
+        delay += limiter.acquire().await;
+    }
 
-        let string = String::from_utf8_lossy(&content)
-            .replace("\r", "")
-            .replace("\n", "")
-            .replace("\t", "");
+    if !delay.is_zero() {
+        trace!(
+            "Rate limit delayed '{}' on #{} by {:?}",
+            command,
+            connection_id,
+            delay
+        );
+    }
+}
 
-        let response = serde_json::from_str(&string).unwrap();
+/// Read the next response and the id of its request from the stream, only removing the xor
+/// from its body.
+pub(crate) async fn read_raw_frame<R: AsyncReadExt + Unpin>(
+    reader: &mut R,
+    xor_key: Option<&[u8]>,
+) -> Result<(u32, Vec<u8>), RconError> {
+    let mut header = [0u8; 8];
+    reader.read_exact(&mut header).await?;
+    let (response_id, length) = decode_frame_header(header)?;
 
-        Ok(response)
+    let mut content = vec![0; length as usize];
+    match timeout(TCP_TIMEOUT, reader.read_exact(&mut content)).await {
+        Ok(res) => _ = res?,
+        Err(_) => return Err(RconError::TimeOut),
     }
 
-    /// Read from the tcp connection until a timeout. Returns true if the operation has timed out, false if not.
-    async fn read_with_timeout(&mut self, buffer: &mut [u8]) -> Result<(), RconError> {
-        match timeout(Duration::from_secs(3), self.tcp.read_exact(buffer)).await {
-            Ok(res) => {
-                res?;
-                Ok(())
-            }
-            Err(_) => Err(RconError::TimeOut),
-        }
+    apply_xor(&mut content, xor_key);
+    Ok((response_id, content))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Instant;
+
+    use crate::{constants::MAX_FRAME_LENGTH, testing::mock_server};
+
+    use super::*;
+
+    #[test]
+    fn oversized_frames_are_rejected() {
+        let mut header = [0u8; 8];
+        header[4..].copy_from_slice(&(MAX_FRAME_LENGTH + 1).to_le_bytes());
+        assert!(matches!(
+            decode_frame_header(header),
+            Err(RconError::ProtocolViolation(_))
+        ));
     }
 
-    /// Mutate the given buffer to apply the buffer.
-    fn apply_xor(&self, buffer: &mut [u8]) {
-        let Some(xor_key) = &self.xor_key else {
-            return;
-        };
+    #[tokio::test]
+    async fn server_answers_requests_in_turn() {
+        let credentials = mock_server().await;
+        let mut connection = RconConnection::new(&credentials).await.unwrap();
 
-        for i in 0..buffer.len() {
-            buffer[i] = buffer[i] ^ xor_key[i % xor_key.len()];
+        for body in ["first", "second"] {
+            let response = connection
+                .execute(RconRequest::new("Echo", body))
+                .await
+                .unwrap();
+            assert_eq!(response.content_body, body);
         }
     }
 
-    /// The id of this connection.
-    pub fn id(&self) -> u64 {
-        self.id
+    #[tokio::test]
+    async fn responses_with_another_id_are_rejected() {
+        let credentials = mock_server().await;
+        let mut connection = RconConnection::new(&credentials).await.unwrap();
+
+        let result = connection.execute(RconRequest::new("WrongId", "")).await;
+        assert!(matches!(result, Err(RconError::ProtocolViolation(_))));
     }
-}
 
-async fn read_exact_u32<R: AsyncReadExt + Unpin>(reader: &mut R) -> io::Result<u32> {
-    let mut buf = [0u8; 4];
-    reader.read_exact(&mut buf).await?;
-    Ok(u32::from_le_bytes(buf))
+    #[tokio::test]
+    async fn silent_servers_time_out() {
+        let credentials = mock_server().await;
+        let mut connection = RconConnection::new(&credentials).await.unwrap();
+
+        let started = Instant::now();
+        let result = connection.execute(RconRequest::new("Silent", "")).await;
+        assert!(matches!(result, Err(RconError::TimeOut)));
+        assert!(started.elapsed() < TCP_TIMEOUT * 2);
+    }
 }
@@ -0,0 +1,534 @@
+//! A connection to the HLL server using the legacy RCON v1 text protocol.
+use std::{
+    collections::HashMap,
+    time::{Duration, Instant},
+};
+
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::TcpStream,
+    time::timeout,
+};
+use tracing::{debug, instrument, trace};
+
+use crate::{
+    client::RconClient,
+    commands::BAN_ADMIN_NAME,
+    connection::wait_for_rate_limits,
+    constants::{next_id, BUFFER_LENGTH, MAX_FRAME_LENGTH, TCP_TIMEOUT},
+    credentials::RconCredentials,
+    framing::apply_xor,
+    messages::{RconRequest, RconResponse},
+    parsing::{
+        gamestate::GameState, playerids::parse_playerids, playerinfo::PlayerData,
+        showlog::parse_loglines, showlog::LogLine, Player,
+    },
+    ratelimit::RateLimiter,
+    RconError,
+};
+
+/// RCON v1 does not frame its responses, a response is complete once no more data
+/// arrives within this time.
+const RESPONSE_SETTLE_TIME: Duration = Duration::from_millis(150);
+
+/// RCON v1 has no command returning the info of all players, each takes its own command.
+/// Only this many players, those refreshed the longest ago, are refreshed per fetch while
+/// the others are returned as last seen, or by name and id alone if never seen.
+const PLAYERINFO_PER_FETCH: usize = 10;
+
+/// An active connection to a Hell Let Loose server only speaking RCON v1.
+#[derive(Debug)]
+pub struct RconV1Connection {
+    /// A unique ID for this connection.
+    id: u64,
+
+    /// The underlying tcp stream.
+    tcp: TcpStream,
+
+    /// The xor key sent by the server upon connecting.
+    xor_key: Vec<u8>,
+
+    /// Limits the commands sent over this connection only.
+    rate_limiter: Option<RateLimiter>,
+
+    /// Limits the commands sent over this and other connections.
+    shared_rate_limiter: Option<RateLimiter>,
+
+    /// The last info of each player online and when it was refreshed, by their name.
+    players: HashMap<String, (PlayerData, Instant)>,
+
+    /// Set once an error left the connection unusable.
+    broken: bool,
+}
+
+impl RconV1Connection {
+    /// Creates a new connection and ensures it can authenticate on the server.
+    #[instrument(level = "debug", skip(credentials), err)]
+    pub async fn new(credentials: &RconCredentials) -> Result<Self, RconError> {
+        debug!(
+            "Attempting to connect to {} using RCON v1",
+            credentials.address
+        );
+        let mut tcp = TcpStream::connect(credentials.address).await?;
+
+        let mut xor_key = vec![0u8; 4];
+        match timeout(TCP_TIMEOUT, tcp.read_exact(&mut xor_key)).await {
+            Ok(res) => _ = res?,
+            Err(_) => return Err(RconError::TimeOut),
+        }
+
+        let mut this = Self {
+            id: next_id(),
+            tcp,
+            xor_key,
+            rate_limiter: None,
+            shared_rate_limiter: None,
+            players: HashMap::new(),
+            broken: false,
+        };
+
+        let response = this
+            .execute_text(&format!("login {}", credentials.password))
+            .await?;
+        if response != "SUCCESS" {
+            return Err(RconError::InvalidPassword);
+        }
+
+        Ok(this)
+    }
+
+    /// Send a text command to the server and return its raw response.
+    pub async fn execute_text(&mut self, command: &str) -> Result<String, RconError> {
+        let result = self.send_text(command).await;
+        if result.as_ref().is_err_and(RconError::is_connection_broken) {
+            self.broken = true;
+        }
+
+        result
+    }
+
+    async fn send_text(&mut self, command: &str) -> Result<String, RconError> {
+        wait_for_rate_limits(
+            [&self.rate_limiter, &self.shared_rate_limiter],
+            command,
+            self.id,
+        )
+        .await;
+        trace!("Executing '{}' on v1 #{}", command, self.id);
+
+        let mut buffer = command.as_bytes().to_vec();
+        apply_xor(&mut buffer, Some(&self.xor_key));
+        self.tcp.write_all(&buffer).await?;
+
+        let mut response = self.read_response().await?;
+        apply_xor(&mut response, Some(&self.xor_key));
+
+        Ok(String::from_utf8_lossy(&response).into_owned())
+    }
+
+    /// Read until the server stops sending data.
+    async fn read_response(&mut self) -> Result<Vec<u8>, RconError> {
+        let mut response = vec![];
+        let mut chunk = vec![0u8; BUFFER_LENGTH];
+
+        // The first chunk may take a while, afterwards the response is assumed complete
+        // once the server stops sending.
+        let mut wait = TCP_TIMEOUT;
+        loop {
+            let read = match timeout(wait, self.tcp.read(&mut chunk)).await {
+                Ok(res) => res?,
+                Err(_) if response.is_empty() => return Err(RconError::TimeOut),
+                Err(_) => break,
+            };
+
+            if read == 0 {
+                if response.is_empty() {
+                    return Err(RconError::IoError(std::io::ErrorKind::UnexpectedEof));
+                }
+                break;
+            }
+
+            response.extend_from_slice(&chunk[..read]);
+            if response.len() > MAX_FRAME_LENGTH as usize {
+                return Err(RconError::ProtocolViolation(
+                    "Response exceeds the maximum length".to_string(),
+                ));
+            }
+            wait = RESPONSE_SETTLE_TIME;
+        }
+
+        Ok(response)
+    }
+
+    /// Execute a command which responds with either `SUCCESS` or `FAIL`.
+    async fn execute_success(&mut self, command: String) -> Result<(), RconError> {
+        let response = self.execute_text(&command).await?;
+        text_response(&command, response).into_result().map(|_| ())
+    }
+
+    /// Get the players by their name and id.
+    pub async fn fetch_playerids(&mut self) -> Result<Vec<Player>, RconError> {
+        parse_playerids(&self.execute_text("get playerids").await?)
+    }
+
+    /// RCON v1 addresses players by their name, find the player with the given id.
+    async fn find_player(&mut self, id: &str) -> Result<Player, RconError> {
+        self.fetch_playerids()
+            .await?
+            .into_iter()
+            .find(|p| p.id.to_string() == id)
+            .ok_or_else(|| RconError::ServerRejected {
+                status_code: 404,
+                status_message: "Player not found".to_string(),
+                command: "get playerids".to_string(),
+            })
+    }
+
+    /// The id of this connection.
+    pub fn id(&self) -> u64 {
+        self.id
+    }
+}
+
+impl RconClient for RconV1Connection {
+    async fn connect(credentials: &RconCredentials) -> Result<Self, RconError> {
+        RconV1Connection::new(credentials).await
+    }
+
+    fn id(&self) -> u64 {
+        self.id
+    }
+
+    fn is_healthy(&self) -> bool {
+        !self.broken
+    }
+
+    fn set_rate_limiter(&mut self, limiter: Option<RateLimiter>) {
+        self.rate_limiter = limiter;
+    }
+
+    fn set_shared_rate_limiter(&mut self, limiter: Option<RateLimiter>) {
+        self.shared_rate_limiter = limiter;
+    }
+
+    /// Executes the request as the text command `<name> <content body>`.
+    async fn execute(&mut self, request: RconRequest) -> Result<RconResponse, RconError> {
+        let command = match request.content_body.is_empty() {
+            true => request.name.clone(),
+            false => format!("{} {}", request.name, request.content_body),
+        };
+
+        let response = self.execute_text(&command).await?;
+        Ok(text_response(&request.name, response))
+    }
+
+    /// Refreshes at most [`PLAYERINFO_PER_FETCH`] players, those never seen before first.
+    /// Every player online is returned, those not refreshed as last seen or by their name
+    /// and id alone if never seen.
+    async fn fetch_players(&mut self) -> Result<Vec<PlayerData>, RconError> {
+        let online = self.fetch_playerids().await?;
+        self.players
+            .retain(|name, _| online.iter().any(|p| &p.name == name));
+
+        let mut stalest: Vec<_> = online
+            .iter()
+            .map(|p| (self.players.get(&p.name).map(|(_, at)| *at), &p.name))
+            .collect();
+        stalest.sort();
+
+        let mut left = vec![];
+        for (_, name) in stalest.into_iter().take(PLAYERINFO_PER_FETCH) {
+            let info = self.execute_text(&format!("playerinfo {name}")).await?;
+
+            // Players may leave in between both commands.
+            match PlayerData::parse_v1(&info) {
+                Ok(data) => _ = self.players.insert(name.clone(), (data, Instant::now())),
+                Err(_) => {
+                    self.players.remove(name);
+                    left.push(name);
+                }
+            }
+        }
+
+        Ok(online
+            .iter()
+            .filter(|p| !left.contains(&&p.name))
+            .map(|p| match self.players.get(&p.name) {
+                Some((data, _)) => data.clone(),
+                None => PlayerData::new_v1(p.name.clone(), p.id.to_string()),
+            })
+            .collect())
+    }
+
+    async fn fetch_player(&mut self, id: String) -> Result<PlayerData, RconError> {
+        let player = self.find_player(&id).await?;
+        let info = self
+            .execute_text(&format!("playerinfo {}", player.name))
+            .await?;
+        PlayerData::parse_v1(&info)
+    }
+
+    async fn fetch_showlog(
+        &mut self,
+        backtrack: Duration,
+        filters: &[&str],
+    ) -> Result<Vec<LogLine>, RconError> {
+        let minutes = backtrack.as_secs().div_ceil(60).max(1);
+        let response = self.execute_text(&format!("showlog {minutes}")).await?;
+
+        let lines = response
+            .split_inclusive('\n')
+            .filter(|l| filters.is_empty() || filters.iter().any(|f| l.contains(f)));
+        Ok(parse_loglines(lines))
+    }
+
+    async fn fetch_gamestate(&mut self) -> Result<GameState, RconError> {
+        GameState::parse(&self.execute_text("get gamestate").await?)
+    }
+
+    async fn broadcast_message(&mut self, message: &str) -> Result<(), RconError> {
+        self.execute_success(format!("broadcast {message}")).await
+    }
+
+    async fn individual_message(&mut self, id: &str, message: &str) -> Result<(), RconError> {
+        let player = self.find_player(id).await?;
+        self.execute_success(format!("message {} {message}", quote(&player.name)?))
+            .await
+    }
+
+    async fn punish_player(&mut self, id: &str, reason: &str) -> Result<(), RconError> {
+        let player = self.find_player(id).await?;
+        self.execute_success(format!("punish {} {reason}", quote(&player.name)?))
+            .await
+    }
+
+    async fn kick_player(&mut self, id: &str, reason: &str) -> Result<(), RconError> {
+        let player = self.find_player(id).await?;
+        self.execute_success(format!("kick {} {reason}", quote(&player.name)?))
+            .await
+    }
+
+    async fn temp_ban_player(
+        &mut self,
+        id: &str,
+        hours: u32,
+        reason: &str,
+    ) -> Result<(), RconError> {
+        let player = self.find_player(id).await?;
+        self.execute_success(format!(
+            "tempban {} {hours} {} {}",
+            quote(&player.name)?,
+            quote(reason)?,
+            quote(BAN_ADMIN_NAME)?
+        ))
+        .await
+    }
+
+    /// The text protocol lifts bans by the full ban entry only, which wise does not keep.
+    async fn remove_temp_ban_player(&mut self, _id: &str) -> Result<(), RconError> {
+        Err(RconError::Unsupported("remove_temp_ban_player"))
+    }
+}
+
+/// Quote an argument of a text command. The protocol knows no escaping, as such arguments
+/// containing quotes themselves, such as some player names, can not be passed.
+fn quote(argument: &str) -> Result<String, RconError> {
+    match argument.contains('"') {
+        true => Err(RconError::InvalidData(
+            "Arguments containing quotes can not be sent over RCON v1.",
+        )),
+        false => Ok(format!("\"{argument}\"")),
+    }
+}
+
+/// Wrap a text response into a [`RconResponse`], a `FAIL` is treated as a bad request.
+fn text_response(command: &str, response: String) -> RconResponse {
+    let (status_code, status_message) = match response.as_str() {
+        "FAIL" => (400, "FAIL"),
+        _ => (200, "SUCCESS"),
+    };
+
+    RconResponse {
+        status_code,
+        status_message: status_message.to_string(),
+        version: 1,
+        name: command.to_string(),
+        content_body: response,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, Mutex};
+
+    use tokio::net::TcpListener;
+
+    use crate::{parsing::player::PlayerId, RejectionKind};
+
+    use super::*;
+
+    const V1_XOR_KEY: &[u8] = b"wise";
+
+    /// A minimal RCON v1 server with the `players` online, recording the commands it received.
+    ///
+    /// The `close` command closes the connection instead of answering.
+    pub async fn mock_v1_server(
+        players: Vec<Player>,
+    ) -> (RconCredentials, Arc<Mutex<Vec<String>>>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        let commands = Arc::new(Mutex::new(vec![]));
+
+        let received = commands.clone();
+        tokio::spawn(async move {
+            while let Ok((tcp, _)) = listener.accept().await {
+                tokio::spawn(serve_v1(tcp, players.clone(), received.clone()));
+            }
+        });
+
+        let credentials = RconCredentials {
+            address,
+            password: "password".to_string(),
+        };
+        (credentials, commands)
+    }
+
+    async fn serve_v1(mut tcp: TcpStream, players: Vec<Player>, commands: Arc<Mutex<Vec<String>>>) {
+        tcp.write_all(V1_XOR_KEY).await.unwrap();
+
+        loop {
+            // The client waits for each response, as such every read is a single command.
+            let mut chunk = [0u8; 4096];
+            let mut command = match tcp.read(&mut chunk).await {
+                Ok(0) | Err(_) => return,
+                Ok(read) => chunk[..read].to_vec(),
+            };
+            apply_xor(&mut command, Some(V1_XOR_KEY));
+            let command = String::from_utf8(command).unwrap();
+            commands.lock().unwrap().push(command.clone());
+
+            let response = match command.as_str() {
+                "close" => return,
+                "get playerids" => {
+                    let ids: String = players
+                        .iter()
+                        .map(|p| format!("\t{} : {}", p.name, p.id))
+                        .collect();
+                    format!("{}{ids}\t", players.len())
+                }
+                _ => match command.strip_prefix("playerinfo ") {
+                    Some(name) => {
+                        let player = players.iter().find(|p| p.name == name).unwrap();
+                        format!(
+                            "Name: {name}\nsteamID64: {}\nTeam: Allies\nRole: Rifleman\n\
+                             Kills: 0 - Deaths: 0\nScore: C 0, O 0, D 0, S 0\nLevel: 1\n",
+                            player.id
+                        )
+                    }
+                    None => "SUCCESS".to_string(),
+                },
+            };
+
+            let mut response = response.into_bytes();
+            apply_xor(&mut response, Some(V1_XOR_KEY));
+            if tcp.write_all(&response).await.is_err() {
+                return;
+            }
+        }
+    }
+
+    fn players(count: usize) -> Vec<Player> {
+        (0..count)
+            .map(|i| Player::new(format!("player {i}"), PlayerId::Steam(i as u64)))
+            .collect()
+    }
+
+    #[test]
+    fn arguments_with_quotes_are_rejected() {
+        assert_eq!(quote("player one").unwrap(), "\"player one\"");
+        assert!(quote("\"quoted\" player").is_err());
+    }
+
+    #[test]
+    fn fail_responses_are_bad_requests() {
+        let response = text_response("kick", "FAIL".to_string());
+        assert_eq!(
+            response.into_result().unwrap_err().rejection_kind(),
+            Some(RejectionKind::BadRequest)
+        );
+
+        let response = text_response("get playerids", "1\tplayer : 1\t".to_string());
+        assert_eq!(response.status_code, 200);
+        assert_eq!(response.content_body, "1\tplayer : 1\t");
+    }
+
+    #[tokio::test]
+    async fn players_are_addressed_by_their_quoted_name() {
+        let mut online = players(1);
+        online.push(Player::new("\"quoted\"".to_string(), PlayerId::Steam(7)));
+        let (credentials, commands) = mock_v1_server(online).await;
+        let mut connection = RconV1Connection::new(&credentials).await.unwrap();
+
+        connection.individual_message("0", "hello").await.unwrap();
+        connection.kick_player("0", "bye").await.unwrap();
+        connection.temp_ban_player("0", 2, "rule 1").await.unwrap();
+        assert!(connection.kick_player("7", "bye").await.is_err());
+
+        let sent: Vec<_> = commands
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|c| !c.starts_with("get playerids"))
+            .cloned()
+            .collect();
+        assert_eq!(
+            sent,
+            [
+                "login password".to_string(),
+                "message \"player 0\" hello".to_string(),
+                "kick \"player 0\" bye".to_string(),
+                format!("tempban \"player 0\" 2 \"rule 1\" \"{BAN_ADMIN_NAME}\""),
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn fetching_players_refreshes_the_stalest() {
+        let (credentials, commands) = mock_v1_server(players(PLAYERINFO_PER_FETCH + 1)).await;
+        let mut connection = RconV1Connection::new(&credentials).await.unwrap();
+        let playerinfos = || {
+            commands
+                .lock()
+                .unwrap()
+                .drain(..)
+                .filter(|c| c.starts_with("playerinfo"))
+                .collect::<Vec<_>>()
+        };
+
+        // Every player is returned, the one not refreshed by name and id alone.
+        let fetched = connection.fetch_players().await.unwrap();
+        assert_eq!(fetched.len(), PLAYERINFO_PER_FETCH + 1);
+        let unrefreshed: Vec<_> = fetched.iter().filter(|p| p.team == -1).collect();
+        assert_eq!(unrefreshed.len(), 1);
+        let first = playerinfos();
+        assert!(!first.contains(&format!("playerinfo {}", unrefreshed[0].name)));
+        assert_eq!(first.len(), PLAYERINFO_PER_FETCH);
+
+        // The player not seen before is refreshed first, the others are returned as is.
+        let fetched = connection.fetch_players().await.unwrap();
+        assert_eq!(fetched.len(), PLAYERINFO_PER_FETCH + 1);
+        let refreshed = playerinfos();
+        assert_eq!(refreshed.len(), PLAYERINFO_PER_FETCH);
+        assert!(!first.contains(&refreshed[0]));
+    }
+
+    #[tokio::test]
+    async fn closed_connections_are_unhealthy() {
+        let (credentials, _) = mock_v1_server(vec![]).await;
+        let mut connection = RconV1Connection::new(&credentials).await.unwrap();
+        assert!(connection.is_healthy());
+
+        assert!(connection.execute_text("close").await.is_err());
+        assert!(!connection.is_healthy());
+    }
+}
@@ -1,22 +1,27 @@
-use std::{sync::Arc, time::Duration};
-
-use lazy_static::lazy_static;
-use tokio::sync::Mutex;
+use std::{
+    sync::atomic::{AtomicU64, Ordering},
+    time::Duration,
+};
 
 /// Amount of time before timing out a TCP connection.
 pub const TCP_TIMEOUT: Duration = Duration::from_secs(3);
 
+/// Amount of time to wait for the response to a request which may be queued behind others.
+pub const PIPELINE_TIMEOUT: Duration = Duration::from_secs(10);
+
 /// The default buffer length for reading responses.
 pub const BUFFER_LENGTH: usize = 32768;
 
-lazy_static! {
-    /// A globally unique ID for connections.
-    static ref RUNNING_ID: Arc<Mutex<u64>> = Arc::new(Mutex::new(0));
-}
+/// The largest response body accepted from the server.
+pub const MAX_FRAME_LENGTH: u32 = 32 * 1024 * 1024;
+
+/// The furthest back the server allows admin logs to be requested.
+pub const MAX_LOG_BACKTRACK: Duration = Duration::from_secs(180 * 60);
+
+/// A globally unique ID for connections, shared by the async and blocking connections.
+static RUNNING_ID: AtomicU64 = AtomicU64::new(0);
 
-/// Get the next globally unique id.
-pub async fn next_id() -> u64 {
-    let mut id = RUNNING_ID.lock().await;
-    *id += 1;
-    *id
+/// Get the next globally unique id, usable within and outside an async context.
+pub fn next_id() -> u64 {
+    RUNNING_ID.fetch_add(1, Ordering::Relaxed) + 1
 }
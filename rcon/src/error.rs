@@ -1,17 +1,20 @@
 use std::io::ErrorKind;
 
 use nom::error::Error;
+use std::io;
 use thiserror::Error;
-use tokio::io;
 
 #[derive(PartialEq, Error, Debug)]
 pub enum RconError {
     #[error("The data received is invalid. << {0}")]
     InvalidData(&'static str),
 
-    #[error("The JSOn received from the server is invalid.")]
+    #[error("The JSON received from the server is invalid.")]
     InvalidJson,
 
+    #[error("The server violated the protocol. << {0}")]
+    ProtocolViolation(String),
+
     #[error("Error occurred while parsing with nom.")]
     ParsingError(nom::Err<nom::error::Error<String>>),
 
@@ -23,13 +26,114 @@ pub enum RconError {
 
     #[error("A communication with the server has timed out.")]
     TimeOut,
+
+    #[error("The command '{0}' is not supported by this connection.")]
+    Unsupported(&'static str),
+
+    #[error("The server rejected '{command}' with status {status_code}: {status_message}")]
+    ServerRejected {
+        status_code: i32,
+        status_message: String,
+        command: String,
+    },
+}
+
+/// The reason why the server rejected a command.
+#[derive(PartialEq, Eq, Clone, Copy, Debug)]
+pub enum RejectionKind {
+    /// The command was malformed or its arguments invalid.
+    BadRequest,
+
+    /// The auth token of the connection expired, status `401`. The command was not executed
+    /// and succeeds once logged in again.
+    TokenExpired,
+
+    /// The connection is not authorized to execute the command, status `403`. Logging in
+    /// again does not change that.
+    Unauthorized,
+
+    /// The player the command targets could not be found.
+    PlayerNotFound,
+
+    /// Any other rejection.
+    Other,
+}
+
+impl RconError {
+    /// Whether the error leaves the connection unusable, such as a closed socket.
+    pub fn is_connection_broken(&self) -> bool {
+        matches!(
+            self,
+            RconError::IoError(_) | RconError::TimeOut | RconError::ProtocolViolation(_)
+        )
+    }
+
+    /// Whether the server refused the auth token as expired, without executing the command.
+    /// Other authorization failures, such as a forbidden command, are not resolved by
+    /// logging in again.
+    pub fn is_token_expired(&self) -> bool {
+        self.rejection_kind() == Some(RejectionKind::TokenExpired)
+    }
+
+    /// Should the server have rejected a command, get the reason why.
+    pub fn rejection_kind(&self) -> Option<RejectionKind> {
+        let RconError::ServerRejected {
+            status_code,
+            status_message,
+            ..
+        } = self
+        else {
+            return None;
+        };
+
+        let message = status_message.to_lowercase();
+        let kind = match status_code {
+            401 => RejectionKind::TokenExpired,
+            _ if message.contains("player") && message.contains("not found") => {
+                RejectionKind::PlayerNotFound
+            }
+            403 => RejectionKind::Unauthorized,
+            404 => RejectionKind::PlayerNotFound,
+            400 => RejectionKind::BadRequest,
+            _ => RejectionKind::Other,
+        };
+
+        Some(kind)
+    }
+}
+
+impl Clone for RconError {
+    fn clone(&self) -> Self {
+        match self {
+            Self::InvalidData(e) => Self::InvalidData(e),
+            Self::InvalidJson => Self::InvalidJson,
+            Self::ProtocolViolation(e) => Self::ProtocolViolation(e.clone()),
+            // Nom's error does not implement clone.
+            Self::ParsingError(e) => Self::ParsingError(match e {
+                nom::Err::Incomplete(n) => nom::Err::Incomplete(*n),
+                nom::Err::Error(e) => nom::Err::Error(Error::new(e.input.clone(), e.code)),
+                nom::Err::Failure(e) => nom::Err::Failure(Error::new(e.input.clone(), e.code)),
+            }),
+            Self::IoError(e) => Self::IoError(*e),
+            Self::InvalidPassword => Self::InvalidPassword,
+            Self::TimeOut => Self::TimeOut,
+            Self::Unsupported(e) => Self::Unsupported(e),
+            Self::ServerRejected {
+                status_code,
+                status_message,
+                command,
+            } => Self::ServerRejected {
+                status_code: *status_code,
+                status_message: status_message.clone(),
+                command: command.clone(),
+            },
+        }
+    }
 }
 
 impl From<io::Error> for RconError {
     fn from(value: io::Error) -> Self {
-        match value.kind() {
-            e => Self::IoError(e),
-        }
+        Self::IoError(value.kind())
     }
 }
 
@@ -39,3 +143,40 @@ impl From<nom::Err<Error<&str>>> for RconError {
         RconError::ParsingError(value)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rejected(status_code: i32, status_message: &str) -> RconError {
+        RconError::ServerRejected {
+            status_code,
+            status_message: status_message.to_string(),
+            command: "KickPlayer".to_string(),
+        }
+    }
+
+    #[test]
+    fn rejections_are_classified_by_status() {
+        let kind = |status_code| rejected(status_code, "").rejection_kind();
+        assert_eq!(kind(400), Some(RejectionKind::BadRequest));
+        assert_eq!(kind(401), Some(RejectionKind::TokenExpired));
+        assert_eq!(kind(403), Some(RejectionKind::Unauthorized));
+        assert_eq!(kind(404), Some(RejectionKind::PlayerNotFound));
+        assert_eq!(kind(500), Some(RejectionKind::Other));
+        assert_eq!(RconError::TimeOut.rejection_kind(), None);
+    }
+
+    #[test]
+    fn missing_players_are_recognized_by_the_message() {
+        let error = rejected(400, "Player Not Found");
+        assert_eq!(error.rejection_kind(), Some(RejectionKind::PlayerNotFound));
+    }
+
+    #[test]
+    fn only_401_is_an_expired_token() {
+        assert!(rejected(401, "").is_token_expired());
+        assert!(!rejected(403, "").is_token_expired());
+        assert!(!RconError::TimeOut.is_token_expired());
+    }
+}
@@ -0,0 +1,93 @@
+//! How requests and responses are encoded on the wire, shared by the async and blocking
+//! connections.
+use crate::{constants::MAX_FRAME_LENGTH, RconError};
+
+/// Apply the xor to the body of a request and prepend the header with the request id and
+/// body length. The server echoes the id in the header of its response.
+pub(crate) fn encode_request(
+    request_id: u32,
+    mut body: Vec<u8>,
+    xor_key: Option<&[u8]>,
+) -> Vec<u8> {
+    apply_xor(&mut body, xor_key);
+
+    let mut frame = Vec::with_capacity(body.len() + 8);
+    frame.extend_from_slice(&request_id.to_le_bytes());
+    frame.extend_from_slice(&(body.len() as u32).to_le_bytes());
+    frame.append(&mut body);
+    frame
+}
+
+/// Split the header of a response into the id of its request and the length of its body.
+pub(crate) fn decode_frame_header(header: [u8; 8]) -> Result<(u32, u32), RconError> {
+    let [i0, i1, i2, i3, l0, l1, l2, l3] = header;
+    let length = u32::from_le_bytes([l0, l1, l2, l3]);
+    check_frame_length(length)?;
+
+    Ok((u32::from_le_bytes([i0, i1, i2, i3]), length))
+}
+
+/// Reject a response which does not answer the request it was read for.
+pub(crate) fn check_response_id(request_id: u32, response_id: u32) -> Result<(), RconError> {
+    if response_id != request_id {
+        return Err(RconError::ProtocolViolation(format!(
+            "Response id {response_id} does not match request id {request_id}"
+        )));
+    }
+
+    Ok(())
+}
+
+/// Reject frames exceeding [`MAX_FRAME_LENGTH`].
+pub(crate) fn check_frame_length(length: u32) -> Result<(), RconError> {
+    if length > MAX_FRAME_LENGTH {
+        return Err(RconError::ProtocolViolation(format!(
+            "Frame of {length} bytes exceeds the maximum of {MAX_FRAME_LENGTH} bytes"
+        )));
+    }
+
+    Ok(())
+}
+
+/// Mutate the given buffer to apply the xor key.
+pub(crate) fn apply_xor(buffer: &mut [u8], xor_key: Option<&[u8]>) {
+    let Some(xor_key) = xor_key else {
+        return;
+    };
+
+    for (i, byte) in buffer.iter_mut().enumerate() {
+        *byte ^= xor_key[i % xor_key.len()];
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn frames_start_with_the_request_id_and_length() {
+        let frame = encode_request(7, b"body".to_vec(), None);
+        assert_eq!(&frame[..4], &7u32.to_le_bytes());
+        assert_eq!(&frame[4..8], &4u32.to_le_bytes());
+        assert_eq!(&frame[8..], b"body");
+
+        let frame = encode_request(7, vec![1, 2, 3], Some(&[1, 0]));
+        assert_eq!(&frame[8..], &[0, 2, 2]);
+    }
+
+    #[test]
+    fn headers_are_split_into_id_and_length() {
+        let frame = encode_request(7, b"body".to_vec(), None);
+        let header = frame[..8].try_into().unwrap();
+        assert_eq!(decode_frame_header(header).unwrap(), (7, 4));
+    }
+
+    #[test]
+    fn mismatched_response_ids_are_rejected() {
+        assert!(check_response_id(3, 3).is_ok());
+        assert!(matches!(
+            check_response_id(3, 4),
+            Err(RconError::ProtocolViolation(_))
+        ));
+    }
+}
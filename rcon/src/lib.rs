@@ -1,9 +1,34 @@
+#[cfg(feature = "blocking")]
+pub mod blocking;
+#[cfg(feature = "async")]
+pub mod capture;
+#[cfg(feature = "async")]
+pub mod client;
+// Without any connection only the parsing is of use.
+#[cfg_attr(not(any(feature = "async", feature = "blocking")), allow(dead_code))]
 pub mod commands;
+#[cfg(feature = "async")]
 pub mod connection;
+#[cfg(feature = "async")]
+pub mod connection_v1;
 pub mod constants;
 pub mod credentials;
 pub mod error;
+#[cfg_attr(not(any(feature = "async", feature = "blocking")), allow(dead_code))]
+mod framing;
 pub mod messages;
+#[cfg(feature = "async")]
+pub mod multiplexed;
+#[cfg(feature = "async")]
+pub mod offline;
 pub mod parsing;
+#[cfg(feature = "async")]
+pub mod ratelimit;
+#[cfg(feature = "async")]
+pub mod resilient;
 
-pub use error::RconError;
+// The mock server is only of use to the tests of the connections.
+#[cfg(all(test, any(feature = "async", feature = "blocking")))]
+mod testing;
+
+pub use error::{RconError, RejectionKind};
@@ -1,6 +1,8 @@
 use serde::{Deserialize, Serialize};
 use serde_json::Value;
 
+use crate::RconError;
+
 /// A request sent to the server.
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct RconRequest {
@@ -64,6 +66,26 @@ pub struct RconResponse {
 }
 
 impl RconResponse {
+    /// Decode a response from the already decrypted body of a frame.
+    pub fn decode(content: &[u8]) -> Result<Self, RconError> {
+        let string = String::from_utf8_lossy(content);
+        serde_json::from_str(&escape_control_characters(&string))
+            .map_err(|_| RconError::InvalidJson)
+    }
+
+    /// Turn a response with a status code other than 200 into [`RconError::ServerRejected`].
+    pub fn into_result(self) -> Result<Self, RconError> {
+        if self.status_code == 200 {
+            return Ok(self);
+        }
+
+        Err(RconError::ServerRejected {
+            status_code: self.status_code,
+            status_message: self.status_message,
+            command: self.name,
+        })
+    }
+
     /// Assert that the status code is 200 otherwise return [`T`].
     pub fn assert_ok<T>(&self, err: T) -> Result<(), T> {
         match self.status_code {
@@ -72,3 +94,109 @@ impl RconResponse {
         }
     }
 }
+
+/// The server sends control characters, for example newlines in chat messages, unescaped
+/// within JSON strings. Escape these so the JSON can be parsed without losing them.
+///
+/// ```
+/// use rcon::messages::escape_control_characters;
+///
+/// let escaped = escape_control_characters("{\"a\": \"b\tc\nd\"}\n");
+/// assert_eq!(escaped, "{\"a\": \"b\\tc\\nd\"}\n");
+/// ```
+pub fn escape_control_characters(input: &str) -> String {
+    let mut output = String::with_capacity(input.len());
+    let mut in_string = false;
+    let mut escaped = false;
+
+    for c in input.chars() {
+        if !in_string {
+            in_string = c == '"';
+            output.push(c);
+            continue;
+        }
+
+        match c {
+            _ if escaped => {
+                escaped = false;
+                output.push(c);
+            }
+            '\\' => {
+                escaped = true;
+                output.push(c);
+            }
+            '"' => {
+                in_string = false;
+                output.push(c);
+            }
+            '\n' => output.push_str("\\n"),
+            '\r' => output.push_str("\\r"),
+            '\t' => output.push_str("\\t"),
+            c if (c as u32) < 0x20 => output.push_str(&format!("\\u{:04x}", c as u32)),
+            c => output.push(c),
+        }
+    }
+
+    output
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn control_characters_are_escaped_within_strings() {
+        let escaped = escape_control_characters("{\"a\": \"b\r\u{1}c\"}");
+        assert_eq!(escaped, "{\"a\": \"b\\r\\u0001c\"}");
+    }
+
+    #[test]
+    fn whitespace_outside_of_strings_is_kept() {
+        let input = "{\n\t\"a\": 1\r\n}";
+        assert_eq!(escape_control_characters(input), input);
+    }
+
+    #[test]
+    fn escaped_quotes_do_not_end_strings() {
+        let escaped = escape_control_characters("{\"a\": \"say \\\"hi\\\"\nthere\"}");
+        assert_eq!(escaped, "{\"a\": \"say \\\"hi\\\"\\nthere\"}");
+    }
+
+    #[test]
+    fn chat_with_newlines_decodes() {
+        let content = "{\"statusCode\":200,\"statusMessage\":\"OK\",\"version\":2,\"name\":\"AdminLog\",\"contentBody\":\"a\nb\"}";
+        let response = RconResponse::decode(content.as_bytes()).unwrap();
+        assert_eq!(response.content_body, "a\nb");
+    }
+
+    #[test]
+    fn only_status_200_is_a_success() {
+        let mut response = RconResponse {
+            status_code: 200,
+            status_message: "OK".to_string(),
+            version: 2,
+            name: "Kick".to_string(),
+            content_body: String::new(),
+        };
+        assert!(response.clone().into_result().is_ok());
+
+        response.status_code = 400;
+        response.status_message = "Bad Request".to_string();
+        assert_eq!(
+            response.into_result().unwrap_err(),
+            RconError::ServerRejected {
+                status_code: 400,
+                status_message: "Bad Request".to_string(),
+                command: "Kick".to_string(),
+            }
+        );
+    }
+
+    #[test]
+    fn malformed_json_is_an_error() {
+        assert!(matches!(
+            RconResponse::decode(b"{\"statusCode\":"),
+            Err(RconError::InvalidJson)
+        ));
+    }
+}
@@ -0,0 +1,320 @@
+//! A connection with multiple requests in flight at once.
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex as StdMutex},
+};
+
+use tokio::{
+    io::AsyncWriteExt,
+    net::{
+        tcp::{OwnedReadHalf, OwnedWriteHalf},
+        TcpStream,
+    },
+    sync::{oneshot, Mutex},
+    task::JoinHandle,
+    time::timeout,
+};
+use tracing::{debug, trace};
+
+use crate::{
+    capture::CaptureRecorder,
+    connection::{read_raw_frame, wait_for_rate_limits},
+    constants::PIPELINE_TIMEOUT,
+    framing::encode_request,
+    messages::{RconRequest, RconResponse},
+    ratelimit::RateLimiter,
+    RconError,
+};
+
+type ResponseSender = oneshot::Sender<Result<Vec<u8>, RconError>>;
+
+/// A cloneable handle to a connection where a background task hands responses to the
+/// requests whose id they echo. As such many requests can be in flight over a single socket.
+///
+/// The rate limiters and recorder belong to the handle, clones start out with those of
+/// the handle they were cloned from.
+#[derive(Debug, Clone)]
+pub struct MultiplexedConnection {
+    inner: Arc<Inner>,
+
+    /// Limits the commands sent over this connection only.
+    rate_limiter: Option<RateLimiter>,
+
+    /// Limits the commands sent over this and other connections.
+    shared_rate_limiter: Option<RateLimiter>,
+
+    /// Records every request and response once set.
+    recorder: Option<CaptureRecorder>,
+}
+
+#[derive(Debug)]
+struct Inner {
+    /// A unique ID for this connection.
+    id: u64,
+
+    /// The half of the stream requests are written to.
+    writer: Mutex<OwnedWriteHalf>,
+
+    /// The xor key used for "encryption".
+    xor_key: Option<Arc<Vec<u8>>>,
+
+    /// The auth token passed with every request.
+    auth_token: Option<String>,
+
+    /// Requests waiting for their response.
+    pending: Arc<StdMutex<Pending>>,
+
+    /// The task reading responses.
+    reader: JoinHandle<()>,
+}
+
+#[derive(Debug, Default)]
+struct Pending {
+    /// The id of the next request, the server echoes it in its response.
+    next_request_id: u32,
+
+    /// Senders for the responses by the id of their request.
+    waiting: HashMap<u32, ResponseSender>,
+
+    /// Set once the reader failed, all further requests fail with this error.
+    closed: Option<RconError>,
+}
+
+impl Pending {
+    /// Fail all waiting and further requests with the error.
+    fn close(&mut self, error: RconError) {
+        for (_, tx) in self.waiting.drain() {
+            _ = tx.send(Err(error.clone()));
+        }
+        self.closed = Some(error);
+    }
+}
+
+impl Drop for Inner {
+    fn drop(&mut self) {
+        self.reader.abort();
+    }
+}
+
+impl MultiplexedConnection {
+    /// Take over an authenticated connection.
+    pub(crate) fn new(
+        id: u64,
+        tcp: TcpStream,
+        xor_key: Option<Vec<u8>>,
+        auth_token: Option<String>,
+        next_request_id: u32,
+    ) -> Self {
+        let (reader, writer) = tcp.into_split();
+        let xor_key = xor_key.map(Arc::new);
+        let pending = Arc::new(StdMutex::new(Pending {
+            next_request_id,
+            ..Default::default()
+        }));
+        let reader = tokio::spawn(read_responses(id, reader, xor_key.clone(), pending.clone()));
+
+        Self {
+            inner: Arc::new(Inner {
+                id,
+                writer: Mutex::new(writer),
+                xor_key,
+                auth_token,
+                pending,
+                reader,
+            }),
+            rate_limiter: None,
+            shared_rate_limiter: None,
+            recorder: None,
+        }
+    }
+
+    /// Send the command to the server and wait for its response, other requests may be
+    /// sent while waiting.
+    pub async fn execute(&self, mut request: RconRequest) -> Result<RconResponse, RconError> {
+        let inner = &self.inner;
+        wait_for_rate_limits(
+            [&self.rate_limiter, &self.shared_rate_limiter],
+            &request.name,
+            inner.id,
+        )
+        .await;
+        trace!("Executing '{}' on multiplexed #{}", request.name, inner.id);
+
+        if let Some(auth_token) = &inner.auth_token {
+            request.auth_token = auth_token.clone();
+        }
+
+        let body = request.serialize();
+        let (tx, rx) = oneshot::channel();
+        {
+            let mut writer = inner.writer.lock().await;
+            let frame = {
+                let mut pending = inner.pending.lock().unwrap();
+                if let Some(e) = &pending.closed {
+                    return Err(e.clone());
+                }
+
+                let request_id = pending.next_request_id;
+                pending.next_request_id = request_id.wrapping_add(1);
+                pending.waiting.insert(request_id, tx);
+                encode_request(
+                    request_id,
+                    body,
+                    inner.xor_key.as_deref().map(|k| k.as_slice()),
+                )
+            };
+
+            if let Err(e) = writer.write_all(&frame).await {
+                let error = RconError::from(e);
+                inner.pending.lock().unwrap().close(error.clone());
+                return Err(error);
+            }
+        }
+
+        let content = match timeout(PIPELINE_TIMEOUT, rx).await {
+            Ok(Ok(content)) => content?,
+            Ok(Err(_)) => return Err(self.closed_error()),
+            // The response may still arrive, it is discarded then.
+            Err(_) => return Err(RconError::TimeOut),
+        };
+
+        if let Some(recorder) = &self.recorder {
+            recorder.record(&request, &content);
+        }
+        RconResponse::decode(&content)
+    }
+
+    /// Limit the commands sent over this connection only.
+    pub fn set_rate_limiter(&mut self, limiter: Option<RateLimiter>) {
+        self.rate_limiter = limiter;
+    }
+
+    /// Limit the commands sent over this connection together with others sharing the limiter.
+    pub fn set_shared_rate_limiter(&mut self, limiter: Option<RateLimiter>) {
+        self.shared_rate_limiter = limiter;
+    }
+
+    /// Record every following request and its response to a capture file.
+    pub fn set_recorder(&mut self, recorder: Option<CaptureRecorder>) {
+        self.recorder = recorder;
+    }
+
+    /// Whether the connection has broken, all further requests will fail.
+    pub fn is_closed(&self) -> bool {
+        self.inner.pending.lock().unwrap().closed.is_some()
+    }
+
+    /// The id of this connection.
+    pub fn id(&self) -> u64 {
+        self.inner.id
+    }
+
+    fn closed_error(&self) -> RconError {
+        self.inner
+            .pending
+            .lock()
+            .unwrap()
+            .closed
+            .clone()
+            .unwrap_or(RconError::IoError(std::io::ErrorKind::NotConnected))
+    }
+}
+
+/// Read responses and hand them to the request waiting for them until the connection breaks.
+async fn read_responses(
+    id: u64,
+    mut reader: OwnedReadHalf,
+    xor_key: Option<Arc<Vec<u8>>>,
+    pending: Arc<StdMutex<Pending>>,
+) {
+    loop {
+        let error =
+            match read_raw_frame(&mut reader, xor_key.as_deref().map(|k| k.as_slice())).await {
+                Ok((response_id, content)) => {
+                    match pending.lock().unwrap().waiting.remove(&response_id) {
+                        Some(tx) => {
+                            _ = tx.send(Ok(content));
+                            continue;
+                        }
+                        None => RconError::ProtocolViolation(format!(
+                            "Received a response to {response_id} without a request"
+                        )),
+                    }
+                }
+                Err(e) => e,
+            };
+
+        debug!("Multiplexed connection #{id} closed: {error}");
+        pending.lock().unwrap().close(error);
+        return;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{connection::RconConnection, testing::mock_server};
+
+    use super::*;
+
+    #[tokio::test]
+    async fn many_requests_are_in_flight_at_once() {
+        let credentials = mock_server().await;
+        let multiplexed = RconConnection::new(&credentials)
+            .await
+            .unwrap()
+            .into_multiplexed();
+
+        let requests: Vec<_> = (0..20)
+            .map(|i| {
+                let multiplexed = multiplexed.clone();
+                tokio::spawn(async move {
+                    let response = multiplexed
+                        .execute(RconRequest::new("Echo", i.to_string()))
+                        .await;
+                    (i, response)
+                })
+            })
+            .collect();
+
+        for request in requests {
+            let (i, response) = request.await.unwrap();
+            assert_eq!(response.unwrap().content_body, i.to_string());
+        }
+    }
+
+    #[tokio::test]
+    async fn responses_are_matched_by_their_id() {
+        let credentials = mock_server().await;
+        let multiplexed = RconConnection::new(&credentials)
+            .await
+            .unwrap()
+            .into_multiplexed();
+
+        // The server answers the second request first.
+        let first = tokio::spawn({
+            let multiplexed = multiplexed.clone();
+            async move { multiplexed.execute(RconRequest::new("Swapped", "1")).await }
+        });
+        tokio::task::yield_now().await;
+        let second = multiplexed.execute(RconRequest::new("Swapped", "2")).await;
+
+        assert_eq!(second.unwrap().content_body, "2");
+        assert_eq!(first.await.unwrap().unwrap().content_body, "1");
+    }
+
+    #[tokio::test]
+    async fn responses_without_a_request_close_the_connection() {
+        let credentials = mock_server().await;
+        let multiplexed = RconConnection::new(&credentials)
+            .await
+            .unwrap()
+            .into_multiplexed();
+
+        let result = multiplexed.execute(RconRequest::new("WrongId", "")).await;
+        assert!(matches!(result, Err(RconError::ProtocolViolation(_))));
+        assert!(multiplexed.is_closed());
+
+        let result = multiplexed.execute(RconRequest::new("Echo", "")).await;
+        assert!(matches!(result, Err(RconError::ProtocolViolation(_))));
+    }
+}
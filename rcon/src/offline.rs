@@ -0,0 +1,26 @@
+//! A client for running without a server, such as when replaying or simulating a game.
+use crate::{
+    client::RconClient,
+    credentials::RconCredentials,
+    messages::{RconRequest, RconResponse},
+    RconError,
+};
+
+/// A client which is not connected to anything. Connecting always succeeds while every
+/// command fails with [`RconError::Unsupported`].
+#[derive(Debug, Default)]
+pub struct OfflineConnection;
+
+impl RconClient for OfflineConnection {
+    async fn connect(_credentials: &RconCredentials) -> Result<Self, RconError> {
+        Ok(Self)
+    }
+
+    fn id(&self) -> u64 {
+        0
+    }
+
+    async fn execute(&mut self, _request: RconRequest) -> Result<RconResponse, RconError> {
+        Err(RconError::Unsupported("execute"))
+    }
+}
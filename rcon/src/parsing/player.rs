@@ -15,8 +15,8 @@ impl Player {
     }
 }
 
-#[cfg_attr(not(feature = "simple_api"), derive(Serialize))]
-#[derive(Clone, Debug, PartialEq, Eq, Hash, Deserialize)]
+#[cfg_attr(not(feature = "simple_api"), derive(Serialize, Deserialize))]
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
 pub enum PlayerId {
     /// Steam conventiently uses a u64.
     Steam(u64),
@@ -44,6 +44,16 @@ impl Serialize for PlayerId {
     }
 }
 
+#[cfg(feature = "simple_api")]
+impl<'de> Deserialize<'de> for PlayerId {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: serde::Deserializer<'de>,
+    {
+        String::deserialize(deserializer).map(|id| Self::parse(&id))
+    }
+}
+
 impl PlayerId {
     pub fn parse(input: &str) -> Self {
         if let Ok(steam_id) = input.parse() {
@@ -57,3 +67,19 @@ impl PlayerId {
         Ok(("", Self::parse(input)))
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn ids_are_read_back_as_written() {
+        for id in [
+            PlayerId::Steam(76561198000000000),
+            PlayerId::Windows("0123456789abcdef0123456789abcdef".to_string()),
+        ] {
+            let json = serde_json::to_string(&id).unwrap();
+            assert_eq!(serde_json::from_str::<PlayerId>(&json).unwrap(), id);
+        }
+    }
+}
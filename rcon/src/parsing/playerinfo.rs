@@ -1,5 +1,9 @@
+use std::str::FromStr;
+
 use serde::{Deserialize, Serialize};
 
+use crate::RconError;
+
 /// Information about a player.
 #[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
 pub struct PlayerData {
@@ -73,3 +77,174 @@ pub struct WorldPosition {
 }
 
 impl Eq for WorldPosition {}
+
+impl WorldPosition {
+    /// The straight line distance to another position, in centimeters like the positions.
+    ///
+    /// ```
+    /// use rcon::parsing::playerinfo::WorldPosition;
+    ///
+    /// let a = WorldPosition { x: 0.0, y: 0.0, z: 0.0 };
+    /// let b = WorldPosition { x: 300.0, y: 400.0, z: 0.0 };
+    /// assert_eq!(a.distance(&b), 500.0);
+    /// ```
+    pub fn distance(&self, other: &WorldPosition) -> f64 {
+        ((self.x - other.x).powi(2) + (self.y - other.y).powi(2) + (self.z - other.z).powi(2))
+            .sqrt()
+    }
+
+    /// Whether the position is unknown, RCON v1 and players not yet spawned report the origin.
+    pub fn is_unknown(&self) -> bool {
+        self.x == 0.0 && self.y == 0.0 && self.z == 0.0
+    }
+}
+
+/// The roles in the order of their ids as used by RCON v2.
+const ROLES: [&str; 14] = [
+    "rifleman",
+    "assault",
+    "automaticrifleman",
+    "medic",
+    "spotter",
+    "support",
+    "heavymachinegunner",
+    "antitank",
+    "engineer",
+    "officer",
+    "sniper",
+    "crewman",
+    "tankcommander",
+    "armycommander",
+];
+
+impl PlayerData {
+    /// A player only known by their name and id, as listed by the RCON v1 `get playerids`
+    /// command. All other values are left at their defaults until the player is refreshed.
+    pub fn new_v1(name: String, id: String) -> Self {
+        PlayerData {
+            name,
+            clan_tag: String::new(),
+            id,
+            platform: String::new(),
+            level: 0,
+            team: -1,
+            eosid: String::new(),
+            role: -1,
+            platoon: String::new(),
+            kills: 0,
+            deaths: 0,
+            score: ScoreData {
+                combat: 0,
+                defense: 0,
+                support: 0,
+                offense: 0,
+            },
+            world_position: WorldPosition {
+                x: 0.0,
+                y: 0.0,
+                z: 0.0,
+            },
+            loadout: String::new(),
+        }
+    }
+
+    /// Parse the output of the RCON v1 `playerinfo` command. Values RCON v1 does not
+    /// provide, such as the world position, are left at their defaults.
+    ///
+    /// ```
+    /// use rcon::parsing::playerinfo::PlayerData;
+    ///
+    /// let player = PlayerData::parse_v1(
+    ///     "Name: Player\nsteamID64: 11111111111111111\nTeam: Axis\nRole: Anti-Tank\n\
+    ///      Unit: 1 - Baker\nLoadout: Standard Issue\nKills: 3 - Deaths: 1\n\
+    ///      Score: C 40, O 0, D 20, S 10\nLevel: 34\n",
+    /// )
+    /// .unwrap();
+    /// assert_eq!(player.role, 7);
+    /// assert_eq!(player.kills, 3);
+    /// assert_eq!(player.score.combat, 40);
+    /// ```
+    pub fn parse_v1(input: &str) -> Result<Self, RconError> {
+        let mut player = PlayerData::new_v1(String::new(), String::new());
+
+        for line in input.lines() {
+            let Some((key, value)) = line.split_once(": ") else {
+                continue;
+            };
+
+            match key {
+                "Name" => player.name = value.to_string(),
+                "steamID64" => player.id = value.to_string(),
+                "Team" => {
+                    player.team = match value {
+                        "Allies" => 0,
+                        "Axis" => 1,
+                        _ => -1,
+                    }
+                }
+                "Role" => {
+                    let role = value.to_lowercase().replace([' ', '-'], "");
+                    let role = if role == "commander" {
+                        ROLES[13]
+                    } else {
+                        &role
+                    };
+                    player.role = ROLES
+                        .iter()
+                        .position(|r| *r == role)
+                        .map_or(-1, |r| r as i32);
+                }
+                "Unit" => {
+                    let name = value.split_once(" - ").map_or(value, |(_, name)| name);
+                    player.platoon = name.to_uppercase();
+                }
+                "Loadout" => player.loadout = value.to_string(),
+                "Kills" => {
+                    let (kills, deaths) = value
+                        .split_once(" - Deaths: ")
+                        .ok_or(RconError::InvalidData("Malformed kills and deaths."))?;
+                    player.kills = parse_number(kills)?;
+                    player.deaths = parse_number(deaths)?;
+                }
+                "Score" => {
+                    for score in value.split(", ") {
+                        let (kind, points) = score
+                            .split_once(' ')
+                            .ok_or(RconError::InvalidData("Malformed score."))?;
+                        let points = parse_number(points)?;
+                        match kind {
+                            "C" => player.score.combat = points,
+                            "O" => player.score.offense = points,
+                            "D" => player.score.defense = points,
+                            "S" => player.score.support = points,
+                            _ => {}
+                        }
+                    }
+                }
+                "Level" => player.level = parse_number(value)?,
+                _ => {}
+            }
+        }
+
+        if player.id.is_empty() {
+            return Err(RconError::InvalidData("Player info is missing the id."));
+        }
+
+        Ok(player)
+    }
+
+    /// The lowercase name of the players role, such as `officer`, [`None`] if unknown.
+    pub fn role_name(&self) -> Option<&'static str> {
+        usize::try_from(self.role)
+            .ok()
+            .and_then(|role| ROLES.get(role))
+            .copied()
+    }
+}
+
+fn parse_number<T: FromStr>(input: &str) -> Result<T, RconError> {
+    input
+        .trim()
+        .parse()
+        .map_err(|_| RconError::InvalidData("Expected a number in player info."))
+}
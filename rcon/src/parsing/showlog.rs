@@ -1,8 +1,8 @@
 use nom::{
     branch::alt,
-    bytes::complete::{tag, take_until, take_while1},
+    bytes::complete::{tag, take_until, take_while, take_while1},
     character::complete::{char, multispace0},
-    combinator::recognize,
+    combinator::{opt, recognize},
     error::{Error, ErrorKind},
     sequence::{delimited, separated_pair, tuple},
     Err, IResult,
@@ -54,6 +54,31 @@ pub enum LogKind {
         reach: String,
         content: String,
     },
+    /// A log line which could not be parsed into any other kind.
+    Unknown {
+        raw: String,
+    },
+}
+
+impl LogKind {
+    /// Append a continuation line of a multi-line message to this log.
+    ///
+    /// Only chat messages and unknown logs can span multiple lines, for any other
+    /// kind the continuation is returned as a [`LogKind::Unknown`] instead.
+    pub fn append_continuation(&mut self, line: &str) -> Option<LogKind> {
+        let line = line.trim_end_matches('\n');
+        match self {
+            LogKind::Chat { content, .. } | LogKind::Unknown { raw: content } => {
+                content.truncate(content.trim_end_matches('\n').len());
+                content.push('\n');
+                content.push_str(line);
+                None
+            }
+            _ => Some(LogKind::Unknown {
+                raw: line.to_string(),
+            }),
+        }
+    }
 }
 
 /// Parse the prelude of every log message and extract the timestamp.
@@ -89,13 +114,13 @@ fn take_connect(input: &str) -> IResult<&str, LogKind> {
     let id = &id[2..id.len() - 1];
     let id = PlayerId::parse(id);
     let player = Player::new(name.to_string(), id);
-    return Ok((
+    Ok((
         input,
         LogKind::Connect {
             player,
             has_connected: connect,
         },
-    ));
+    ))
 }
 
 /// Parse a kill or team kill log line.
@@ -137,10 +162,10 @@ fn take_kill(input: &str) -> IResult<&str, LogKind> {
         victim,
         victim_faction,
         is_teamkill,
-        weapon: weapon.to_string(),
+        weapon: weapon.trim_end_matches('\n').to_string(),
     };
 
-    return Ok((input, kind));
+    Ok((input, kind))
 }
 
 /// Take a match start/end log line.
@@ -187,7 +212,7 @@ pub fn take_match(input: &str) -> IResult<&str, LogKind> {
 /// Takes a match ended value and parses it using
 fn take_match_ended(input: &str) -> Result<LogKind, Option<regex::Error>> {
     let pattern = r"`([^*]*)`.*\((\d+)\s*-\s*(\d+)\)";
-    let re = Regex::new(&pattern)?;
+    let re = Regex::new(pattern)?;
 
     let Some(caps) = re.captures(input) else {
         error!("Failed to capture match ended info for `{}`", input);
@@ -289,22 +314,210 @@ fn take_chat(input: &str) -> IResult<&str, LogKind> {
     ))
 }
 
-/// Parse an entire log line if possible otherwise skip until the next "\n".
-pub fn take_logline(input: &str) -> IResult<&str, Option<LogLine>> {
-    let res = take_prelude(input);
+/// Parse an unknown log line by taking everything until the next "\n".
+fn take_unknown(input: &str) -> IResult<&str, LogKind> {
+    let (input, raw) = take_while(|c| c != '\n')(input)?;
+    let (input, _) = opt(char('\n'))(input)?;
 
+    Ok((
+        input,
+        LogKind::Unknown {
+            raw: raw.to_string(),
+        },
+    ))
+}
+
+/// Parse an entire log line. Should the line not start with a prelude, such as is the
+/// case with continuations of multi-line messages, [`None`] is returned.
+///
+/// ```
+/// use rcon::parsing::showlog::*;
+///
+/// let (_, log) = take_logline("[1.2 sec (1718212472)] SOMETHING NEW\n").unwrap();
+/// assert_eq!(log.unwrap().kind, LogKind::Unknown { raw: "SOMETHING NEW".to_string() });
+///
+/// let (_, log) = take_logline("continuation of a chat message").unwrap();
+/// assert!(log.is_none());
+/// ```
+pub fn take_logline(input: &str) -> IResult<&str, Option<LogLine>> {
     // If parsing the prelude fails skip this line, such as the case with multi-line messages
-    if let Err(_) = res {
+    let Ok((input, timestamp)) = take_prelude(input) else {
         return Ok((input, None));
+    };
+
+    let (input, kind) =
+        alt((take_connect, take_kill, take_chat, take_match))(input).or_else(|_| {
+            trace!("Failed to parse, falling back to unknown: {}", input);
+            take_unknown(input)
+        })?;
+
+    Ok((input, Some(LogLine { timestamp, kind })))
+}
+
+/// Parse the messages of multiple log entries. Entries which are a continuation of a
+/// multi-line message are joined onto the log they belong to.
+///
+/// ```
+/// use rcon::parsing::showlog::*;
+///
+/// let logs = parse_loglines([
+///     "[1.2 sec (1718212472)] SOMETHING NEW\n",
+///     "on a second line",
+/// ]);
+/// assert_eq!(logs.len(), 1);
+/// assert_eq!(logs[0].kind, LogKind::Unknown { raw: "SOMETHING NEW\non a second line".to_string() });
+/// ```
+pub fn parse_loglines<'a>(messages: impl IntoIterator<Item = &'a str>) -> Vec<LogLine> {
+    let mut loglines: Vec<LogLine> = vec![];
+    let mut orphans = 0;
+
+    for message in messages {
+        if let Ok((_, Some(logline))) = take_logline(message) {
+            // The orphans took place before this log, their own is outside the fetched window.
+            for orphan in &mut loglines[..orphans] {
+                orphan.timestamp = logline.timestamp;
+            }
+            orphans = 0;
+            loglines.push(logline);
+            continue;
+        }
+
+        // Continuations without a parent are kept as unknown logs, timestamped once the
+        // next log is known.
+        let Some(parent) = loglines.last_mut().filter(|_| orphans == 0) else {
+            orphans += 1;
+            loglines.push(LogLine {
+                timestamp: 0,
+                kind: LogKind::Unknown {
+                    raw: message.trim_end_matches('\n').to_string(),
+                },
+            });
+            continue;
+        };
+
+        if let Some(kind) = parent.kind.append_continuation(message) {
+            let timestamp = parent.timestamp;
+            loglines.push(LogLine { timestamp, kind });
+        }
     }
-    let (input, timestamp) = res.unwrap();
 
-    // If we fail to parse the log line skip it
-    let Ok((input, kind)) = alt((take_connect, take_kill, take_chat, take_match))(input) else {
-        trace!("Failed to parse: {}", input);
+    // Continuations after a log take its timestamp, as such orphans remain only when no log
+    // was fetched at all. Without any timestamp to take they are dropped, rather than
+    // dated to 1970, their parent lies before the fetched window.
+    if orphans > 0 {
+        trace!("Dropping {orphans} continuations without a log to take the timestamp of");
+        loglines.clear();
+    }
 
-        return Ok((input, None));
-    };
+    loglines
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse_one(message: &str) -> LogLine {
+        let logs = parse_loglines([message]);
+        assert_eq!(logs.len(), 1);
+        logs.into_iter().next().unwrap()
+    }
+
+    #[test]
+    fn parses_connects_kills_and_chat() {
+        let log = parse_one("[1.2 sec (1718212472)] CONNECTED Player Name (11111111111111111)\n");
+        assert_eq!(log.timestamp, 1718212472);
+        assert!(matches!(
+            log.kind,
+            LogKind::Connect { player, has_connected: true } if player.name == "Player Name"
+        ));
+
+        let log = parse_one("[1.2 sec (1718212472)] TEAM KILL: Player Name(Axis/11111111111111111) -> PlayerName(Axis/22222222222222222) with MP40\n");
+        assert!(matches!(
+            log.kind,
+            LogKind::Kill { is_teamkill: true, weapon, .. } if weapon == "MP40"
+        ));
+
+        let log = parse_one(
+            "[1.2 sec (1718212472)] CHAT[Team][Player Name(Allies/11111111111111111)]: foo bar",
+        );
+        assert!(matches!(
+            log.kind,
+            LogKind::Chat { reach, team, content, .. }
+                if reach == "Team" && team == "Allies" && content == "foo bar"
+        ));
+    }
+
+    #[test]
+    fn joins_multi_line_chat() {
+        let logs = parse_loglines([
+            "[1.2 sec (1718212472)] CHAT[Team][Player Name(Allies/11111111111111111)]: foo\n",
+            "bar\n",
+            "[1.1 sec (1718212473)] SOMETHING NEW\n",
+        ]);
+
+        assert_eq!(logs.len(), 2);
+        assert!(matches!(&logs[0].kind, LogKind::Chat { content, .. } if content == "foo\nbar"));
+    }
+
+    #[test]
+    fn keeps_continuations_of_other_kinds_as_unknown() {
+        let logs = parse_loglines([
+            "[1.2 sec (1718212472)] MATCH START CARENTAN WARFARE\n",
+            "continued",
+        ]);
+
+        assert_eq!(logs.len(), 2);
+        assert_eq!(
+            logs[1],
+            LogLine {
+                timestamp: 1718212472,
+                kind: LogKind::Unknown {
+                    raw: "continued".to_string()
+                }
+            }
+        );
+    }
+
+    #[test]
+    fn orphaned_continuations_take_the_timestamp_of_the_next_log() {
+        let logs = parse_loglines([
+            "first orphan\n",
+            "second orphan",
+            "[1.2 sec (1718212472)] SOMETHING NEW\n",
+        ]);
+
+        assert_eq!(logs.len(), 3);
+        assert!(logs.iter().all(|l| l.timestamp == 1718212472));
+        assert_eq!(
+            logs[1].kind,
+            LogKind::Unknown {
+                raw: "second orphan".to_string()
+            }
+        );
+    }
 
-    return Ok((input, Some(LogLine { timestamp, kind })));
+    #[test]
+    fn continuations_after_the_last_log_take_its_timestamp() {
+        let logs = parse_loglines([
+            "[1.2 sec (1718212472)] MATCH START CARENTAN WARFARE\n",
+            "first continuation\n",
+            "second continuation",
+        ]);
+
+        assert_eq!(logs.len(), 2);
+        assert_eq!(
+            logs[1],
+            LogLine {
+                timestamp: 1718212472,
+                kind: LogKind::Unknown {
+                    raw: "first continuation\nsecond continuation".to_string()
+                }
+            }
+        );
+    }
+
+    #[test]
+    fn orphans_without_any_log_are_dropped() {
+        assert!(parse_loglines(["first orphan\n", "second orphan"]).is_empty());
+    }
 }
@@ -0,0 +1,153 @@
+//! Client side throttling of commands sent to the HLL server.
+use std::{
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use tokio::time::sleep;
+
+/// A token bucket limiting how many commands may be sent. Clones share the same bucket,
+/// as such a single limiter can be shared across many connections.
+#[derive(Debug, Clone)]
+pub struct RateLimiter {
+    bucket: Arc<Mutex<Bucket>>,
+}
+
+#[derive(Debug)]
+struct Bucket {
+    /// The maximum amount of tokens, allowing for short bursts.
+    capacity: f64,
+
+    /// The currently available tokens.
+    tokens: f64,
+
+    /// Tokens added per second.
+    refill_rate: f64,
+
+    /// The last time tokens were added.
+    refilled_at: Instant,
+}
+
+impl RateLimiter {
+    /// Create a limiter allowing `per_second` commands on average and up to `burst` at once.
+    ///
+    /// # Panics
+    ///
+    /// Panics should `per_second` not be a positive finite number.
+    pub fn new(per_second: f64, burst: u32) -> Self {
+        assert!(
+            per_second > 0.0 && per_second.is_finite(),
+            "The rate {per_second} is not a positive finite number"
+        );
+        let capacity = f64::from(burst.max(1));
+        Self {
+            bucket: Arc::new(Mutex::new(Bucket {
+                capacity,
+                tokens: capacity,
+                refill_rate: per_second,
+                refilled_at: Instant::now(),
+            })),
+        }
+    }
+
+    /// Wait until a command may be sent and return how long that took.
+    pub async fn acquire(&self) -> Duration {
+        let start = Instant::now();
+        loop {
+            let wait = self.bucket.lock().unwrap().try_take(Instant::now());
+            match wait {
+                None => return start.elapsed(),
+                Some(wait) => sleep(wait).await,
+            }
+        }
+    }
+}
+
+impl Bucket {
+    /// Take a token if available at the time, otherwise return how long until one is.
+    fn try_take(&mut self, now: Instant) -> Option<Duration> {
+        let elapsed = now.duration_since(self.refilled_at).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * self.refill_rate).min(self.capacity);
+        self.refilled_at = now;
+
+        if self.tokens >= 1.0 {
+            self.tokens -= 1.0;
+            return None;
+        }
+
+        Some(Duration::from_secs_f64(
+            (1.0 - self.tokens) / self.refill_rate,
+        ))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn bucket(per_second: f64, burst: u32) -> Bucket {
+        let limiter = RateLimiter::new(per_second, burst);
+        Arc::try_unwrap(limiter.bucket)
+            .unwrap()
+            .into_inner()
+            .unwrap()
+    }
+
+    #[test]
+    fn bursts_up_to_the_capacity() {
+        let mut bucket = bucket(1.0, 3);
+        let now = bucket.refilled_at;
+
+        for _ in 0..3 {
+            assert_eq!(bucket.try_take(now), None);
+        }
+        assert_eq!(bucket.try_take(now), Some(Duration::from_secs(1)));
+    }
+
+    #[test]
+    fn refills_over_time() {
+        let mut bucket = bucket(2.0, 1);
+        let now = bucket.refilled_at;
+        assert_eq!(bucket.try_take(now), None);
+
+        let wait = bucket.try_take(now).unwrap();
+        assert_eq!(wait, Duration::from_millis(500));
+        assert_eq!(
+            bucket.try_take(now + Duration::from_millis(250)),
+            Some(Duration::from_millis(250))
+        );
+        assert_eq!(bucket.try_take(now + wait), None);
+    }
+
+    #[test]
+    fn never_exceeds_the_capacity() {
+        let mut bucket = bucket(10.0, 2);
+        let later = bucket.refilled_at + Duration::from_secs(60);
+
+        assert_eq!(bucket.try_take(later), None);
+        assert_eq!(bucket.try_take(later), None);
+        assert!(bucket.try_take(later).is_some());
+    }
+
+    #[test]
+    fn a_burst_of_zero_still_allows_commands() {
+        let mut bucket = bucket(1.0, 0);
+        assert_eq!(bucket.try_take(bucket.refilled_at), None);
+    }
+
+    #[tokio::test]
+    async fn clones_share_the_bucket() {
+        let limiter = RateLimiter::new(1.0, 1);
+        let clone = limiter.clone();
+
+        assert!(limiter.acquire().await < Duration::from_millis(100));
+        let wait = clone.bucket.lock().unwrap().try_take(Instant::now());
+        assert!(wait.is_some());
+    }
+
+    #[test]
+    #[should_panic]
+    fn nan_rates_are_rejected() {
+        RateLimiter::new(f64::NAN, 1);
+    }
+}
@@ -0,0 +1,379 @@
+//! A connection which transparently reconnects to the HLL server.
+//!
+//! Requests are only sent again should they certainly not have been executed, that is when
+//! the server rejected an expired auth token. A connection breaking while waiting for the
+//! response may have executed the request, as such only idempotent requests, run with
+//! [`ResilientConnection::run_idempotent`], are retried then. Connections found to be broken
+//! before sending anything are reestablished first.
+use std::{future::Future, pin::Pin, time::Duration};
+
+use tokio::{sync::watch, time::sleep};
+use tracing::{debug, instrument, warn};
+
+use crate::{
+    client::RconClient,
+    connection::RconConnection,
+    credentials::RconCredentials,
+    messages::{RconRequest, RconResponse},
+    RconError,
+};
+
+/// The state of a [`ResilientConnection`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ConnectionState {
+    /// The connection is established and logged in.
+    Connected,
+
+    /// The connection broke and has not been reestablished yet.
+    Disconnected,
+
+    /// Attempting to reestablish the connection.
+    Reconnecting {
+        /// The current attempt, starting at 1.
+        attempt: u32,
+    },
+
+    /// Reconnecting failed too many times, no further attempts are made.
+    Failed,
+}
+
+/// How a [`ResilientConnection`] should reconnect.
+#[derive(Debug, Clone)]
+pub struct ReconnectPolicy {
+    /// The time to wait before the first reconnect attempt.
+    pub initial_backoff: Duration,
+
+    /// The backoff doubles with each attempt until it reaches this maximum.
+    pub max_backoff: Duration,
+
+    /// The amount of attempts before giving up, [`None`] retries forever and blocks the
+    /// caller until the server is reachable again.
+    pub max_attempts: Option<u32>,
+}
+
+impl Default for ReconnectPolicy {
+    fn default() -> Self {
+        Self {
+            initial_backoff: Duration::from_millis(500),
+            max_backoff: Duration::from_secs(30),
+            max_attempts: Some(10),
+        }
+    }
+}
+
+/// A connection which detects broken sockets and expired auth tokens and transparently
+/// reconnects and logs in again with the stored credentials.
+#[derive(Debug)]
+pub struct ResilientConnection<C = RconConnection> {
+    credentials: RconCredentials,
+    policy: ReconnectPolicy,
+    connection: Option<C>,
+    state: watch::Sender<ConnectionState>,
+}
+
+impl<C: RconClient> ResilientConnection<C> {
+    /// Create a new connection, failing should the first attempt not succeed.
+    pub async fn new(
+        credentials: RconCredentials,
+        policy: ReconnectPolicy,
+    ) -> Result<Self, RconError> {
+        let connection = C::connect(&credentials).await?;
+        let (state, _) = watch::channel(ConnectionState::Connected);
+
+        Ok(Self {
+            credentials,
+            policy,
+            connection: Some(connection),
+            state,
+        })
+    }
+
+    /// Subscribe to changes of the connection state.
+    pub fn subscribe(&self) -> watch::Receiver<ConnectionState> {
+        self.state.subscribe()
+    }
+
+    /// The current state of the connection.
+    pub fn state(&self) -> ConnectionState {
+        *self.state.borrow()
+    }
+
+    /// Send the request to the server, reconnecting should the connection be broken or
+    /// the auth token have expired. The request is only retried for an expired auth token,
+    /// as it may have been executed should the connection break.
+    pub async fn execute(&mut self, request: RconRequest) -> Result<RconResponse, RconError> {
+        self.run(|c| {
+            let request = request.clone();
+            Box::pin(async move {
+                let response = RconClient::execute(c, request).await?;
+                if response.status_code == 401 {
+                    return response.into_result();
+                }
+
+                Ok(response)
+            })
+        })
+        .await
+    }
+
+    /// Run a function, such as the typed commands, on the underlying connection. Should the
+    /// auth token expire the connection is reestablished and the function retried once. Should
+    /// the connection break it is reestablished on the next call, without retrying.
+    pub async fn run<F, R>(&mut self, f: F) -> Result<R, RconError>
+    where
+        F: for<'a> Fn(&'a mut C) -> Pin<Box<dyn Future<Output = Result<R, RconError>> + Send + 'a>>
+            + Send,
+    {
+        self.run_with_retry(f, false).await
+    }
+
+    /// Run a function which may safely run more than once, such as fetching players or logs.
+    /// Should the connection break or the auth token expire it is reestablished and the
+    /// function retried once.
+    pub async fn run_idempotent<F, R>(&mut self, f: F) -> Result<R, RconError>
+    where
+        F: for<'a> Fn(&'a mut C) -> Pin<Box<dyn Future<Output = Result<R, RconError>> + Send + 'a>>
+            + Send,
+    {
+        self.run_with_retry(f, true).await
+    }
+
+    async fn run_with_retry<F, R>(&mut self, f: F, idempotent: bool) -> Result<R, RconError>
+    where
+        F: for<'a> Fn(&'a mut C) -> Pin<Box<dyn Future<Output = Result<R, RconError>> + Send + 'a>>
+            + Send,
+    {
+        let mut retried = false;
+        loop {
+            let connection = self.connection().await?;
+            let error = match f(connection).await {
+                Ok(r) => return Ok(r),
+                Err(e) => e,
+            };
+
+            // The server refuses expired tokens without executing the request.
+            let expired = error.is_token_expired();
+            let broken = error.is_connection_broken();
+            if !expired && !broken {
+                return Err(error);
+            }
+
+            warn!("Connection lost, reconnecting: {error}");
+            self.connection = None;
+            self.state.send_replace(ConnectionState::Disconnected);
+
+            if retried || !(expired || idempotent) {
+                return Err(error);
+            }
+            retried = true;
+        }
+    }
+
+    /// Get the underlying connection, reconnecting should there be none or should it be
+    /// found broken without sending anything.
+    pub async fn connection(&mut self) -> Result<&mut C, RconError> {
+        if self.connection.as_ref().is_some_and(|c| !c.is_healthy()) {
+            warn!("Connection found broken, reconnecting");
+            self.connection = None;
+            self.state.send_replace(ConnectionState::Disconnected);
+        }
+
+        if self.connection.is_none() {
+            self.connection = Some(self.reconnect().await?);
+        }
+
+        Ok(self.connection.as_mut().unwrap())
+    }
+
+    /// Reconnect with exponential backoff until successful or the attempts are exhausted.
+    #[instrument(level = "debug", skip(self), fields(address = %self.credentials.address))]
+    async fn reconnect(&mut self) -> Result<C, RconError> {
+        let mut backoff = self.policy.initial_backoff;
+        let mut attempt = 0;
+
+        loop {
+            attempt += 1;
+            self.state
+                .send_replace(ConnectionState::Reconnecting { attempt });
+
+            match C::connect(&self.credentials).await {
+                Ok(connection) => {
+                    debug!("Reconnected after {attempt} attempt(s)");
+                    self.state.send_replace(ConnectionState::Connected);
+                    return Ok(connection);
+                }
+                // Retrying with the wrong password only risks getting banned.
+                Err(e @ RconError::InvalidPassword) => {
+                    self.state.send_replace(ConnectionState::Failed);
+                    return Err(e);
+                }
+                Err(e) if self.policy.max_attempts.is_some_and(|m| attempt >= m) => {
+                    self.state.send_replace(ConnectionState::Failed);
+                    return Err(e);
+                }
+                Err(e) => debug!("Reconnect attempt {attempt} failed: {e}"),
+            }
+
+            sleep(backoff).await;
+            backoff = (backoff * 2).min(self.policy.max_backoff);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        cell::{Cell, RefCell},
+        collections::VecDeque,
+    };
+
+    use crate::RejectionKind;
+
+    use super::*;
+
+    thread_local! {
+        /// The outcomes of the following executions, successful once empty.
+        static OUTCOMES: RefCell<VecDeque<Result<u16, RconError>>> = Default::default();
+        static CONNECTS: Cell<u32> = const { Cell::new(0) };
+        static EXECUTIONS: Cell<u32> = const { Cell::new(0) };
+        static HEALTHY: Cell<bool> = const { Cell::new(true) };
+    }
+
+    /// A client following the outcomes scripted for the current test.
+    struct MockClient;
+
+    impl RconClient for MockClient {
+        async fn connect(_credentials: &RconCredentials) -> Result<Self, RconError> {
+            CONNECTS.set(CONNECTS.get() + 1);
+            HEALTHY.set(true);
+            Ok(MockClient)
+        }
+
+        fn id(&self) -> u64 {
+            0
+        }
+
+        fn is_healthy(&self) -> bool {
+            HEALTHY.get()
+        }
+
+        async fn execute(&mut self, request: RconRequest) -> Result<RconResponse, RconError> {
+            EXECUTIONS.set(EXECUTIONS.get() + 1);
+            let status_code = OUTCOMES
+                .with_borrow_mut(|o| o.pop_front())
+                .unwrap_or(Ok(200))?;
+            Ok(RconResponse {
+                status_code: status_code.into(),
+                status_message: String::new(),
+                version: 2,
+                name: request.name,
+                content_body: String::new(),
+            })
+        }
+    }
+
+    async fn connect(
+        outcomes: impl IntoIterator<Item = Result<u16, RconError>>,
+    ) -> ResilientConnection<MockClient> {
+        OUTCOMES.set(outcomes.into_iter().collect());
+        CONNECTS.set(0);
+        EXECUTIONS.set(0);
+        let credentials = RconCredentials {
+            address: "127.0.0.1:0".parse().unwrap(),
+            password: String::new(),
+        };
+        let policy = ReconnectPolicy {
+            initial_backoff: Duration::ZERO,
+            ..Default::default()
+        };
+        ResilientConnection::new(credentials, policy).await.unwrap()
+    }
+
+    fn kick() -> RconRequest {
+        RconRequest::new("KickPlayer", "")
+    }
+
+    #[tokio::test]
+    async fn broken_connections_do_not_execute_twice() {
+        let mut connection = connect([Err(RconError::TimeOut)]).await;
+
+        assert!(matches!(
+            connection.execute(kick()).await,
+            Err(RconError::TimeOut)
+        ));
+        assert_eq!(EXECUTIONS.get(), 1);
+        assert_eq!(connection.state(), ConnectionState::Disconnected);
+
+        // The next request reconnects first.
+        assert!(connection.execute(kick()).await.is_ok());
+        assert_eq!(CONNECTS.get(), 2);
+        assert_eq!(connection.state(), ConnectionState::Connected);
+    }
+
+    #[tokio::test]
+    async fn idempotent_requests_are_retried_on_broken_connections() {
+        let mut connection = connect([Err(RconError::TimeOut)]).await;
+
+        let result = connection
+            .run_idempotent(|c| Box::pin(c.execute(RconRequest::new("ServerInformation", ""))))
+            .await;
+        assert!(result.is_ok());
+        assert_eq!(EXECUTIONS.get(), 2);
+        assert_eq!(CONNECTS.get(), 2);
+    }
+
+    #[tokio::test]
+    async fn expired_tokens_are_retried_once() {
+        let mut connection = connect([Ok(401)]).await;
+        assert!(connection.execute(kick()).await.is_ok());
+        assert_eq!(EXECUTIONS.get(), 2);
+        assert_eq!(CONNECTS.get(), 2);
+
+        let mut connection = connect([Ok(401), Ok(401)]).await;
+        let result = connection.execute(kick()).await;
+        assert_eq!(
+            result.unwrap_err().rejection_kind(),
+            Some(RejectionKind::TokenExpired)
+        );
+    }
+
+    #[tokio::test]
+    async fn forbidden_commands_do_not_reconnect() {
+        let mut connection = connect([Ok(403)]).await;
+        let result = connection
+            .run(|c| Box::pin(async move { c.execute(kick()).await?.into_result() }))
+            .await;
+
+        assert_eq!(
+            result.unwrap_err().rejection_kind(),
+            Some(RejectionKind::Unauthorized)
+        );
+        assert_eq!(EXECUTIONS.get(), 1);
+        assert_eq!(CONNECTS.get(), 1);
+        assert_eq!(connection.state(), ConnectionState::Connected);
+    }
+
+    #[tokio::test]
+    async fn unhealthy_connections_are_replaced_before_sending() {
+        let mut connection = connect([]).await;
+        HEALTHY.set(false);
+
+        assert!(connection.execute(kick()).await.is_ok());
+        assert_eq!(CONNECTS.get(), 2);
+        assert_eq!(EXECUTIONS.get(), 1);
+    }
+
+    #[tokio::test]
+    async fn other_errors_are_returned_as_is() {
+        let mut connection = connect([Ok(400)]).await;
+        let result = connection
+            .run_idempotent(|c| Box::pin(async move { c.execute(kick()).await?.into_result() }))
+            .await;
+
+        assert_eq!(
+            result.unwrap_err().rejection_kind(),
+            Some(RejectionKind::BadRequest)
+        );
+        assert_eq!(CONNECTS.get(), 1);
+    }
+}
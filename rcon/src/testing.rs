@@ -0,0 +1,107 @@
+//! A mock HLL server shared by the tests of the connections.
+use base64::{prelude::BASE64_STANDARD, Engine};
+use serde_json::json;
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{TcpListener, TcpStream},
+};
+
+use crate::{
+    credentials::RconCredentials,
+    framing::{apply_xor, decode_frame_header, encode_request},
+    messages::RconRequest,
+};
+
+const XOR_KEY: &[u8] = b"wise";
+
+/// A minimal RCON v2 server, answering every request with its name and content body.
+///
+/// Requests named `Silent` are never answered, those named `WrongId` are answered with the
+/// id of the next request and every two requests named `Swapped` are answered in reverse
+/// order. Every connection accepted is served.
+pub async fn mock_server() -> RconCredentials {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let address = listener.local_addr().unwrap();
+
+    tokio::spawn(async move {
+        while let Ok((tcp, _)) = listener.accept().await {
+            tokio::spawn(serve(tcp));
+        }
+    });
+
+    RconCredentials {
+        address,
+        password: "password".to_string(),
+    }
+}
+
+async fn serve(mut tcp: TcpStream) {
+    tcp.write_all(&[0; 4]).await.unwrap();
+
+    let mut xor_key = None;
+    let mut buffer = vec![];
+    let mut swapped = None;
+    loop {
+        let mut chunk = [0u8; 4096];
+        match tcp.read(&mut chunk).await {
+            Ok(0) | Err(_) => return,
+            Ok(read) => buffer.extend_from_slice(&chunk[..read]),
+        }
+
+        while let Some((mut request_id, request, length)) = decode_request(&buffer, xor_key) {
+            buffer.drain(..length);
+
+            let (name, content_body) = match request.name.as_str() {
+                "ServerConnect" => (request.name, BASE64_STANDARD.encode(XOR_KEY)),
+                "Login" => (request.name, "token".to_string()),
+                "Silent" => continue,
+                "WrongId" => {
+                    request_id += 1;
+                    (request.name, request.content_body)
+                }
+                _ => {
+                    assert_eq!(request.auth_token, "token");
+                    (request.name, request.content_body)
+                }
+            };
+
+            let response = json!({
+                "statusCode": 200,
+                "statusMessage": "OK",
+                "version": 2,
+                "name": &name,
+                "contentBody": content_body,
+            });
+            let body = serde_json::to_vec(&response).unwrap();
+            // Responses are framed just like requests.
+            let mut frame = encode_request(request_id, body, xor_key);
+            if name == "Swapped" {
+                match swapped.take() {
+                    None => {
+                        swapped = Some(frame);
+                        continue;
+                    }
+                    Some(mut first) => frame.append(&mut first),
+                }
+            }
+            if tcp.write_all(&frame).await.is_err() {
+                return;
+            }
+
+            if name == "ServerConnect" {
+                xor_key = Some(XOR_KEY);
+            }
+        }
+    }
+}
+
+/// Decode the first complete request in the buffer, its id and the amount of bytes it took up.
+fn decode_request(buffer: &[u8], xor_key: Option<&[u8]>) -> Option<(u32, RconRequest, usize)> {
+    let header = buffer.get(..8)?.try_into().unwrap();
+    let (request_id, length) = decode_frame_header(header).unwrap();
+    let mut body = buffer.get(8..8 + length as usize)?.to_vec();
+    apply_xor(&mut body, xor_key);
+
+    let request = serde_json::from_slice(&body).unwrap();
+    Some((request_id, request, 8 + length as usize))
+}
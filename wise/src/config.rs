@@ -5,12 +5,11 @@ use std::{
     time::Duration,
 };
 
-use clap::Parser;
+use clap::{Parser, Subcommand};
 use config::{Config, ConfigError, File};
 use notify::{EventKind, Watcher};
-use rcon::credentials::RconCredentials;
+use rcon::{credentials::RconCredentials, ratelimit::RateLimiter};
 use serde::{Deserialize, Serialize};
-use serde_with::serde_as;
 use tokio::sync::{
     mpsc::channel,
     watch::{self, Sender},
@@ -24,6 +23,51 @@ pub struct CliConfig {
     /// The configuration file to use.
     #[clap(default_value = "config.toml")]
     pub config_file: PathBuf,
+
+    #[command(subcommand)]
+    pub command: Option<CliCommand>,
+}
+
+/// Run wise differently than polling the HLL server.
+#[derive(Subcommand, Clone, Debug)]
+pub enum CliCommand {
+    /// Serve a recorded game over the websocket instead of polling the HLL server.
+    /// The replay starts once the first client receiving events connects.
+    Replay {
+        /// The recording to replay.
+        file: PathBuf,
+
+        /// How much faster than the game took place to replay it.
+        #[arg(long, default_value_t = 1.0)]
+        speed: f64,
+
+        /// Start paused, clients then step through the events one by one.
+        #[arg(long)]
+        step: bool,
+    },
+
+    /// Parse the players and admin logs of a capture file again, as recorded with
+    /// `capture_file`, to reproduce parser failures without the HLL server.
+    ParseCapture {
+        /// The capture to parse.
+        file: PathBuf,
+    },
+
+    /// Simulate plausible matches instead of polling the HLL server.
+    Simulate {
+        /// The seed of the simulation, the same seed always simulates the same matches.
+        /// A random seed is used if not provided.
+        #[arg(long)]
+        seed: Option<u64>,
+
+        /// The amount of players the server fills up to.
+        #[arg(long, default_value_t = 50)]
+        players: usize,
+
+        /// How much faster than real time to simulate.
+        #[arg(long, default_value_t = 1.0)]
+        speed: f64,
+    },
 }
 
 impl CliConfig {}
@@ -38,6 +82,97 @@ pub struct PollingConfig {
 
     #[serde_as(as = "serde_with::DurationMilliSeconds<u64>")]
     pub cooldown_ms: Duration,
+
+    /// Minutes of logs to fetch on startup to reconstruct the current match, emitted as
+    /// historical logs.
+    /// Capped at the maximum the server allows, `0` disables the backfill.
+    #[serde(default)]
+    pub backfill_mins: u64,
+}
+
+/// Configure the pool of connections to the HLL server.
+#[serde_with::serde_as]
+#[derive(Debug, Clone, Deserialize)]
+pub struct PoolConfig {
+    /// Connections kept open even when idle.
+    pub min_size: usize,
+
+    /// Maximum amount of connections open at the same time.
+    pub max_size: usize,
+
+    /// Connections of the maximum which only interactive commands, such as those
+    /// issued over the websocket, may use. Background polling can never take these.
+    #[serde(default = "default_reserved_interactive")]
+    pub reserved_interactive: usize,
+
+    /// How long to wait for a connection when the pool is exhausted.
+    #[serde_as(as = "serde_with::DurationMilliSeconds<u64>")]
+    pub acquire_timeout_ms: Duration,
+
+    /// How long a connection may be idle before it is closed.
+    #[serde_as(as = "serde_with::DurationMilliSeconds<u64>")]
+    pub idle_timeout_ms: Duration,
+
+    /// Send the commands of all slots of the pool over a single connection, requires the
+    /// v2 protocol. Only read on startup.
+    #[serde(default)]
+    pub multiplexed: bool,
+}
+
+impl Default for PoolConfig {
+    fn default() -> Self {
+        Self {
+            min_size: 1,
+            max_size: 8,
+            reserved_interactive: default_reserved_interactive(),
+            acquire_timeout_ms: Duration::from_secs(5),
+            idle_timeout_ms: Duration::from_secs(60),
+            multiplexed: false,
+        }
+    }
+}
+
+/// Configure how many commands may be sent to the HLL server.
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct RateLimitConfig {
+    /// Budget of every individual connection.
+    #[serde(default)]
+    pub per_connection: RateBudget,
+
+    /// Budget shared by all polling tasks.
+    #[serde(default)]
+    pub polling: RateBudget,
+
+    /// Budget shared by all commands sent by clients over the websocket.
+    #[serde(default)]
+    pub client: RateBudget,
+}
+
+/// A budget of commands for a token bucket.
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct RateBudget {
+    /// Commands per second on average, `0` disables the limit.
+    pub per_second: f64,
+
+    /// Commands which may be sent at once before being limited.
+    #[serde(default)]
+    pub burst: u32,
+}
+
+impl RateBudget {
+    /// Create the limiter for this budget, [`None`] if unlimited. Budgets which are not a
+    /// positive finite number, such as `NaN` or `inf`, are unlimited as well.
+    pub fn limiter(&self) -> Option<RateLimiter> {
+        if !self.per_second.is_finite() || self.per_second <= 0.0 {
+            return None;
+        }
+
+        Some(RateLimiter::new(self.per_second, self.burst))
+    }
+}
+
+fn default_reserved_interactive() -> usize {
+    1
 }
 
 #[derive(Debug, Clone, Deserialize)]
@@ -63,6 +198,43 @@ pub struct AuthPerms {
 #[derive(Debug, Clone, Deserialize)]
 pub struct ExportingConfig {
     pub websocket: WebSocketConfig,
+
+    /// Recording every match to its own file.
+    #[serde(default)]
+    pub recording: RecordingConfig,
+
+    /// Archiving every event to files.
+    #[serde(default)]
+    pub file: FileOutputConfig,
+
+    /// Persisting players, sessions, matches, kills and chat to SQLite.
+    #[serde(default)]
+    pub sqlite: SqliteConfig,
+}
+
+/// Configure the recording of matches, see [`crate::exporting::recording`] for the format.
+#[serde_with::serde_as]
+#[derive(Debug, Clone, Deserialize)]
+pub struct RecordingConfig {
+    /// Enable or disable recording, takes effect on the next match.
+    pub enabled: bool,
+
+    /// The directory the recordings are written to.
+    pub directory: PathBuf,
+
+    /// How often a full snapshot of the game is written. Only read on startup.
+    #[serde_as(as = "serde_with::DurationMilliSeconds<u64>")]
+    pub snapshot_interval_ms: Duration,
+}
+
+impl Default for RecordingConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            directory: PathBuf::from("recordings"),
+            snapshot_interval_ms: Duration::from_secs(60),
+        }
+    }
 }
 
 #[derive(Debug, Clone, Deserialize)]
@@ -86,9 +258,156 @@ pub struct WebSocketConfig {
     pub key_file: Option<String>,
 }
 
+/// Configure archiving every event as a line of JSON, see [`crate::exporting::file`].
+/// Changes require a restart.
+#[serde_with::serde_as]
 #[derive(Debug, Clone, Deserialize)]
 pub struct FileOutputConfig {
     pub enabled: bool,
+
+    /// The directory the files are written to.
+    pub directory: PathBuf,
+
+    /// Start a new file once the current one exceeds this size, `0` disables.
+    #[serde(default)]
+    pub max_size_bytes: u64,
+
+    /// Start a new file once the current one is older than this, `0` disables.
+    #[serde(default)]
+    #[serde_as(as = "serde_with::DurationMilliSeconds<u64>")]
+    pub max_age_ms: Duration,
+
+    /// Compress files with gzip once a new one is started.
+    #[serde(default)]
+    pub compress: bool,
+}
+
+impl Default for FileOutputConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            directory: PathBuf::from("events"),
+            max_size_bytes: 0,
+            max_age_ms: Duration::ZERO,
+            compress: false,
+        }
+    }
+}
+
+/// Configure persisting events to an SQLite database, see [`crate::exporting::sqlite`].
+/// Requires the `sqlite` feature, changes require a restart.
+#[derive(Debug, Clone, Deserialize)]
+pub struct SqliteConfig {
+    pub enabled: bool,
+
+    /// The database file, created if it does not exist.
+    pub path: PathBuf,
+}
+
+impl Default for SqliteConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            path: PathBuf::from("wise.sqlite3"),
+        }
+    }
+}
+
+/// Configure the rules players are moderated by, see [`crate::services::moderation`].
+/// Changes take effect right away, what rules already did is kept by their names.
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct ModerationConfig {
+    pub enabled: bool,
+
+    /// Only log what the rules would do, without executing anything on the server.
+    #[serde(default)]
+    pub dry_run: bool,
+
+    #[serde(default)]
+    pub rules: Vec<RuleConfig>,
+}
+
+/// A rule reacting to players once triggered.
+#[serde_with::serde_as]
+#[derive(Debug, Clone, Deserialize)]
+pub struct RuleConfig {
+    /// Uniquely identifies the rule, shown in logs and as `{rule}` in messages.
+    pub name: String,
+
+    pub trigger: TriggerConfig,
+
+    /// Conditions which must be met for the rule to apply at all.
+    #[serde(default)]
+    pub conditions: ConditionsConfig,
+
+    /// The actions, escalating each time the rule triggers for the same player.
+    /// Once all were taken the last is repeated.
+    pub actions: Vec<ActionConfig>,
+
+    /// Time after acting in which the rule does not act on the same player again.
+    #[serde(default)]
+    #[serde_as(as = "serde_with::DurationMilliSeconds<u64>")]
+    pub cooldown_ms: Duration,
+
+    /// Time without the rule triggering after which escalation starts over, `0` never.
+    #[serde(default)]
+    #[serde_as(as = "serde_with::DurationMilliSeconds<u64>")]
+    pub escalation_reset_ms: Duration,
+}
+
+/// What triggers a rule for a player.
+#[serde_with::serde_as]
+#[derive(Debug, Clone, Deserialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum TriggerConfig {
+    /// The player killed others `count` times within the time.
+    Kill {
+        /// Only kills with these weapons, any weapon if empty.
+        #[serde(default)]
+        weapons: Vec<String>,
+
+        /// Only teamkills if `true`, only kills of enemies if `false`, either if unset.
+        #[serde(default)]
+        teamkill: Option<bool>,
+
+        #[serde(default = "default_count")]
+        count: usize,
+
+        /// The time all kills have to take place in, `0` any time.
+        #[serde(default)]
+        #[serde_as(as = "serde_with::DurationMilliSeconds<u64>")]
+        within_ms: Duration,
+    },
+
+    /// The player wrote a chat message containing any of the words, case insensitive.
+    Chat { words: Vec<String> },
+}
+
+fn default_count() -> usize {
+    1
+}
+
+/// Conditions on the state of the server.
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct ConditionsConfig {
+    /// Only apply with at least this many players on the server.
+    #[serde(default)]
+    pub min_players: Option<u64>,
+
+    /// Only apply with at most this many players on the server, for example while seeding.
+    #[serde(default)]
+    pub max_players: Option<u64>,
+}
+
+/// What to do with a player once a rule triggered. Texts may contain `{name}`, the name of
+/// the player, and `{rule}`, the name of the rule.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum ActionConfig {
+    Message { text: String },
+    Punish { reason: String },
+    Kick { reason: String },
+    TempBan { hours: u32, reason: String },
 }
 
 /// Configure logggin of the application.
@@ -99,17 +418,54 @@ pub struct OperationalConfig {
     /// Whether to run a simple CLI which connects to the Hell Let Loose server.
     #[serde(default)]
     pub direct_cli: bool,
+
+    /// Record the raw RCON traffic of all connections to this file, for debugging parsers.
+    /// Only read on startup.
+    #[serde(default)]
+    pub capture_file: Option<PathBuf>,
+}
+
+/// The protocol used to talk to the HLL server.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum RconProtocol {
+    /// The legacy text based protocol.
+    V1,
+
+    /// The JSON based protocol.
+    #[default]
+    V2,
+}
+
+/// Configure how to access RCON.
+#[derive(Debug, Clone, Deserialize)]
+pub struct RconConfig {
+    /// Credentials for accessing RCON.
+    #[serde(flatten)]
+    pub credentials: RconCredentials,
+
+    /// The protocol spoken with the server. Changes require a restart.
+    #[serde(default)]
+    pub protocol: RconProtocol,
 }
 
 /// Overall configuration of the application.
 #[derive(Debug, Clone, Deserialize)]
 pub struct FileConfig {
-    /// Credentials for accessing RCON.
-    pub rcon: RconCredentials,
+    /// Configuration for accessing RCON.
+    pub rcon: RconConfig,
 
     /// Configuration for polling the HLL server.
     pub polling: PollingConfig,
 
+    /// Configuration for the pool of connections.
+    #[serde(default)]
+    pub pool: PoolConfig,
+
+    /// Configuration for limiting the commands sent to the HLL server.
+    #[serde(default)]
+    pub rate_limit: RateLimitConfig,
+
     /// Configuration for authentication and authorization.
     pub auth: AuthConfig,
 
@@ -118,6 +474,30 @@ pub struct FileConfig {
 
     /// Configuration for logging behaviour.
     pub operational: OperationalConfig,
+
+    /// Configuration for reacting to players automatically.
+    #[serde(default)]
+    pub moderation: ModerationConfig,
+}
+
+impl FileConfig {
+    /// Reject values which deserialize fine yet leave wise unable to work.
+    fn validate(&self) -> Result<(), ConfigError> {
+        if self.pool.max_size == 0 {
+            return Err(ConfigError::Message(
+                "pool.max_size must allow at least one connection".to_string(),
+            ));
+        }
+
+        if self.pool.reserved_interactive >= self.pool.max_size {
+            return Err(ConfigError::Message(
+                "pool.reserved_interactive must leave background tasks at least one connection"
+                    .to_string(),
+            ));
+        }
+
+        Ok(())
+    }
 }
 
 pub type AppConfig = watch::Receiver<FileConfig>;
@@ -138,7 +518,9 @@ fn load_config(path: &Path) -> Result<FileConfig, ConfigError> {
         .add_source(File::with_name(path.to_str().unwrap()))
         .build()?;
 
-    config.try_deserialize()
+    let file_config: FileConfig = config.try_deserialize()?;
+    file_config.validate()?;
+    Ok(file_config)
 }
 
 /// Continously watch the given file for updates and if detected update the given [`AppConfig`].
@@ -178,3 +560,49 @@ async fn watch_config(
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use crate::testing::file_config;
+
+    use super::*;
+
+    fn budget(per_second: f64) -> RateBudget {
+        RateBudget {
+            per_second,
+            burst: 1,
+        }
+    }
+
+    #[test]
+    fn only_positive_finite_budgets_limit() {
+        assert!(budget(2.0).limiter().is_some());
+
+        for per_second in [0.0, -1.0, f64::NAN, f64::INFINITY, f64::NEG_INFINITY] {
+            assert!(budget(per_second).limiter().is_none(), "{per_second}");
+        }
+    }
+
+    #[test]
+    fn example_config_is_valid() {
+        assert!(file_config().validate().is_ok());
+    }
+
+    #[test]
+    fn empty_pools_are_rejected() {
+        let mut file_config = file_config();
+        file_config.pool.max_size = 0;
+        assert!(file_config.validate().is_err());
+    }
+
+    #[test]
+    fn reservations_leave_background_connections() {
+        let mut file_config = file_config();
+        file_config.pool.max_size = 2;
+        file_config.pool.reserved_interactive = 2;
+        assert!(file_config.validate().is_err());
+
+        file_config.pool.reserved_interactive = 1;
+        assert!(file_config.validate().is_ok());
+    }
+}
@@ -24,14 +24,10 @@ impl AuthHandle {
     }
 }
 
+#[allow(clippy::result_unit_err)]
 pub fn authenticate_token(provided_token: &str, config: &AppConfig) -> Result<AuthHandle, ()> {
     let cnf = config.borrow();
-    let matched_token = cnf
-        .auth
-        .tokens
-        .iter()
-        .find(|t| t.value == provided_token)
-        .clone();
+    let matched_token = cnf.auth.tokens.iter().find(|t| t.value == provided_token);
 
     if matched_token.is_none() {
         error!(
@@ -0,0 +1,388 @@
+//! Archiving every event to files, as one line of JSON each.
+//!
+//! Files are named `events-<created at, YYYYMMDD-HHMMSS UTC>.jsonl` and hold one
+//! [`ServerWsMessage::Rcon`] per line, exactly as sent over the websocket.
+//! Once a file exceeds the configured size or age a new one is started and, if enabled,
+//! the previous one compressed to `<name>.jsonl.gz`.
+//! Writes take place on a dedicated thread, as such they never block the async runtime.
+//! Once wise stops the current file is completed like a rotated one.
+use std::{
+    fs::{self, File, OpenOptions},
+    io::{self, BufReader, BufWriter, Write},
+    path::{Path, PathBuf},
+    thread,
+    time::Instant,
+};
+
+use chrono::{DateTime, Utc};
+use flate2::{write::GzEncoder, Compression};
+use tokio::sync::{mpsc, oneshot};
+use tracing::{debug, error, info};
+use wise_api::messages::ServerWsMessage;
+
+use crate::{config::FileOutputConfig, exporting::queue::EventReceiver};
+
+/// Lines waiting to be written. Should the writer fall behind, the exporter waits for it
+/// and skips the events the event queue no longer holds once it catches up.
+const WRITE_QUEUE_CAPACITY: usize = 1000;
+
+/// The file events are currently appended to.
+struct EventFile {
+    path: PathBuf,
+    writer: BufWriter<File>,
+    created_at: Instant,
+    size: u64,
+}
+
+impl EventFile {
+    /// Create a new file in the directory, named after the time it was created at. Should a
+    /// file of the same name already exist, for example after rotating twice in a second,
+    /// a counter is appended to the name.
+    fn create(directory: &Path, created_at: DateTime<Utc>) -> io::Result<Self> {
+        fs::create_dir_all(directory)?;
+        let name = format!("events-{}", created_at.format("%Y%m%d-%H%M%S"));
+
+        let mut counter = 0;
+        let (path, file) = loop {
+            let path = match counter {
+                0 => directory.join(format!("{name}.jsonl")),
+                n => directory.join(format!("{name}-{n}.jsonl")),
+            };
+            counter += 1;
+
+            if with_gz_extension(&path).exists() {
+                continue;
+            }
+            match OpenOptions::new().write(true).create_new(true).open(&path) {
+                Ok(file) => break (path, file),
+                Err(e) if e.kind() == io::ErrorKind::AlreadyExists => continue,
+                Err(e) => return Err(e),
+            }
+        };
+        debug!("Writing events to {}", path.display());
+
+        Ok(Self {
+            path,
+            writer: BufWriter::new(file),
+            created_at: Instant::now(),
+            size: 0,
+        })
+    }
+
+    /// Append the line, it is only guaranteed to be in the file once flushed.
+    fn write_line(&mut self, line: &str) -> io::Result<()> {
+        self.writer.write_all(line.as_bytes())?;
+        self.writer.write_all(b"\n")?;
+        self.size += line.len() as u64 + 1;
+        Ok(())
+    }
+
+    /// Whether a new file should be started.
+    fn should_rotate(&self, config: &FileOutputConfig) -> bool {
+        let too_large = config.max_size_bytes > 0 && self.size >= config.max_size_bytes;
+        let too_old =
+            !config.max_age_ms.is_zero() && self.created_at.elapsed() >= config.max_age_ms;
+        too_large || too_old
+    }
+}
+
+/// Writes lines to the current file, starting new ones as configured.
+struct FileWriter {
+    config: FileOutputConfig,
+    current: Option<EventFile>,
+}
+
+impl FileWriter {
+    fn new(config: FileOutputConfig) -> Self {
+        Self {
+            config,
+            current: None,
+        }
+    }
+
+    /// Append the line to the current file, rotating it first if required.
+    fn write(&mut self, line: &str) {
+        if self
+            .current
+            .as_ref()
+            .is_some_and(|file| file.should_rotate(&self.config))
+        {
+            rotate(self.current.take().unwrap(), self.config.compress);
+        }
+
+        if self.current.is_none() {
+            match EventFile::create(&self.config.directory, Utc::now()) {
+                Ok(file) => self.current = Some(file),
+                Err(e) => {
+                    error!(
+                        "Failed to open a file in {}: {e}",
+                        self.config.directory.display()
+                    );
+                    return;
+                }
+            }
+        }
+
+        let file = self.current.as_mut().unwrap();
+        if let Err(e) = file.write_line(line) {
+            error!("Failed to write event to {}: {e}", file.path.display());
+            self.current = None;
+        }
+    }
+
+    /// Write the buffered lines to the current file.
+    fn flush(&mut self) {
+        let Some(file) = &mut self.current else {
+            return;
+        };
+
+        if let Err(e) = file.writer.flush() {
+            error!("Failed to write events to {}: {e}", file.path.display());
+            self.current = None;
+        }
+    }
+}
+
+/// Append every RCON event to files until told to stop, completing the current file.
+pub async fn run_file_exporter(
+    config: FileOutputConfig,
+    mut event_rx: EventReceiver,
+    mut stop: oneshot::Receiver<()>,
+) {
+    info!("Archiving events to {}", config.directory.display());
+    let (tx, rx) = mpsc::channel(WRITE_QUEUE_CAPACITY);
+    let writer = thread::Builder::new()
+        .name("file-writer".to_string())
+        .spawn(move || write_lines(FileWriter::new(config), rx));
+    let writer = match writer {
+        Ok(writer) => writer,
+        Err(e) => {
+            error!("Failed to start the file writer: {e}");
+            return;
+        }
+    };
+
+    loop {
+        // Events already queued are written before stopping.
+        let message = tokio::select! {
+            biased;
+            message = event_rx.receive() => message,
+            _ = &mut stop => break,
+        };
+        if !matches!(message, ServerWsMessage::Rcon(_)) {
+            continue;
+        }
+
+        let line = match serde_json::to_string(&message) {
+            Ok(line) => line,
+            Err(e) => {
+                error!("Failed to serialize event for the file exporter: {e}");
+                continue;
+            }
+        };
+
+        if tx.send(line).await.is_err() {
+            error!("The file writer stopped, no longer archiving events");
+            break;
+        }
+    }
+
+    // Closing the queue completes the current file once the queued lines are written.
+    drop(tx);
+    if let Ok(Err(_)) = tokio::task::spawn_blocking(move || writer.join()).await {
+        error!("The file writer panicked");
+    }
+}
+
+/// Write the received lines, blocking the thread until the sender is dropped. Files are
+/// flushed once no more lines are waiting rather than after every line. The current file
+/// is closed, and compressed if enabled, once the sender is dropped.
+fn write_lines(mut writer: FileWriter, mut rx: mpsc::Receiver<String>) {
+    while let Some(line) = rx.blocking_recv() {
+        writer.write(&line);
+        if rx.is_empty() {
+            writer.flush();
+        }
+    }
+
+    if let Some(file) = writer.current.take() {
+        rotate(file, writer.config.compress);
+    }
+}
+
+/// Close the file, compressing it if enabled.
+fn rotate(file: EventFile, compress: bool) {
+    let path = file.path;
+    if let Err(e) = file.writer.into_inner() {
+        error!(
+            "Failed to write events to {}: {}",
+            path.display(),
+            e.error()
+        );
+    }
+    debug!("Rotated {}", path.display());
+
+    if compress {
+        if let Err(e) = compress_file(&path) {
+            error!("Failed to compress {}: {e}", path.display());
+        }
+    }
+}
+
+/// Compress the file to `<path>.gz` and remove the original.
+fn compress_file(path: &Path) -> io::Result<()> {
+    let mut reader = BufReader::new(File::open(path)?);
+    let mut encoder = GzEncoder::new(
+        BufWriter::new(File::create(with_gz_extension(path))?),
+        Compression::default(),
+    );
+    io::copy(&mut reader, &mut encoder)?;
+    encoder.finish()?.flush()?;
+
+    fs::remove_file(path)
+}
+
+fn with_gz_extension(path: &Path) -> PathBuf {
+    let mut path = path.as_os_str().to_owned();
+    path.push(".gz");
+    path.into()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{io::Read, time::Duration};
+
+    use flate2::read::GzDecoder;
+    use wise_api::events::RconEvent;
+
+    use crate::{exporting::queue::EventSender, testing::connect};
+
+    use super::*;
+
+    /// A config writing to its own, empty directory.
+    fn config(name: &str) -> FileOutputConfig {
+        let directory = std::env::temp_dir().join(format!("wise-{}-{name}", std::process::id()));
+        _ = fs::remove_dir_all(&directory);
+        FileOutputConfig {
+            enabled: true,
+            directory,
+            ..Default::default()
+        }
+    }
+
+    /// The names of the files in the directory, sorted.
+    fn files(directory: &Path) -> Vec<String> {
+        let mut names: Vec<_> = fs::read_dir(directory)
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name().into_string().unwrap())
+            .collect();
+        names.sort();
+        names
+    }
+
+    /// Write the lines like the exporter would, returning once all are written.
+    fn write(config: &FileOutputConfig, lines: &[&str]) {
+        let (tx, rx) = mpsc::channel(lines.len());
+        for line in lines {
+            tx.try_send(line.to_string()).unwrap();
+        }
+        drop(tx);
+        write_lines(FileWriter::new(config.clone()), rx);
+    }
+
+    #[test]
+    fn files_of_the_same_second_are_numbered() {
+        let config = config("numbered");
+        let created_at = DateTime::from_timestamp(1718212472, 0).unwrap();
+        for _ in 0..3 {
+            EventFile::create(&config.directory, created_at).unwrap();
+        }
+
+        assert_eq!(
+            files(&config.directory),
+            [
+                "events-20240612-171432-1.jsonl",
+                "events-20240612-171432-2.jsonl",
+                "events-20240612-171432.jsonl",
+            ]
+        );
+    }
+
+    #[test]
+    fn files_are_rotated_by_size() {
+        let mut config = config("size");
+        config.max_size_bytes = 7;
+        write(&config, &["first", "second", "third"]);
+
+        // The first file is only rotated once it exceeds the size.
+        let files = files(&config.directory);
+        assert_eq!(files.len(), 2);
+        let contents: Vec<_> = files
+            .iter()
+            .map(|name| fs::read_to_string(config.directory.join(name)).unwrap())
+            .collect();
+        assert!(contents.contains(&"first\nsecond\n".to_string()));
+        assert!(contents.contains(&"third\n".to_string()));
+    }
+
+    #[test]
+    fn files_are_rotated_by_age() {
+        let mut config = config("age");
+        config.max_age_ms = Duration::from_millis(20);
+
+        let mut writer = FileWriter::new(config.clone());
+        writer.write("first");
+        writer.write("second");
+        thread::sleep(Duration::from_millis(30));
+        writer.write("third");
+        writer.flush();
+
+        assert_eq!(files(&config.directory).len(), 2);
+    }
+
+    #[test]
+    fn rotated_files_are_compressed() {
+        let mut config = config("compressed");
+        config.max_size_bytes = 1;
+        config.compress = true;
+        write(&config, &["first", "second"]);
+
+        // The last file is compressed as well once the writer stops.
+        let files = files(&config.directory);
+        assert_eq!(files.len(), 2);
+        assert!(files.iter().all(|f| f.ends_with(".jsonl.gz")));
+
+        let mut contents: Vec<_> = files
+            .iter()
+            .map(|name| {
+                let file = File::open(config.directory.join(name)).unwrap();
+                let mut content = String::new();
+                GzDecoder::new(file).read_to_string(&mut content).unwrap();
+                content
+            })
+            .collect();
+        contents.sort();
+        assert_eq!(contents, ["first\n", "second\n"]);
+    }
+
+    #[tokio::test]
+    async fn stopping_completes_the_current_file() {
+        let mut config = config("stopped");
+        config.compress = true;
+        let game_events = EventSender::new();
+        let (stop, stop_rx) = oneshot::channel();
+        let exporter = tokio::spawn(run_file_exporter(
+            config.clone(),
+            game_events.receiver(),
+            stop_rx,
+        ));
+
+        game_events.send_rcon(RconEvent::Log(connect(0, 1, true)));
+        drop(stop);
+        exporter.await.unwrap();
+
+        let files = files(&config.directory);
+        assert_eq!(files.len(), 1);
+        assert!(files[0].ends_with(".jsonl.gz"));
+    }
+}
@@ -1,10 +1,65 @@
+use std::future::Future;
+
+use rcon::client::RconClient;
+use tokio::{sync::oneshot, task::JoinHandle};
+
 use crate::services::DiContainer;
 
 pub mod auth;
+pub mod file;
 pub mod queue;
+pub mod recording;
+#[cfg(feature = "sqlite")]
+pub mod sqlite;
 pub mod websocket;
 
-pub async fn setup_exporting(di: &DiContainer) -> Result<(), Box<dyn std::error::Error>> {
+/// The running exporters which have to complete their writes before wise stops.
+#[derive(Default)]
+pub struct Exporters {
+    /// Each exporter stops once its sender is dropped.
+    running: Vec<(oneshot::Sender<()>, JoinHandle<()>)>,
+}
+
+impl Exporters {
+    /// Run an exporter until [`Exporters::shutdown`], which is signalled through the receiver.
+    fn spawn<F>(&mut self, exporter: impl FnOnce(oneshot::Receiver<()>) -> F)
+    where
+        F: Future<Output = ()> + Send + 'static,
+    {
+        let (stop, stop_rx) = oneshot::channel();
+        self.running.push((stop, tokio::spawn(exporter(stop_rx))));
+    }
+
+    /// Stop the exporters and wait for them to complete their writes.
+    pub async fn shutdown(self) {
+        let (stops, exporters): (Vec<_>, Vec<_>) = self.running.into_iter().unzip();
+        drop(stops);
+        for exporter in exporters {
+            _ = exporter.await;
+        }
+    }
+}
+
+pub async fn setup_exporting<C: RconClient>(
+    di: &mut DiContainer<C>,
+) -> Result<Exporters, Box<dyn std::error::Error>> {
+    let mut exporters = Exporters::default();
+
+    // Opened first, such that the websocket can query the store.
+    let sqlite_config = di.config.borrow().exporting.sqlite.clone();
+    if sqlite_config.enabled {
+        #[cfg(feature = "sqlite")]
+        {
+            let store = sqlite::SqliteStore::open(&sqlite_config.path)?;
+            di.store = Some(store.clone());
+            let event_rx = di.game_events.receiver();
+            exporters.spawn(|stop| sqlite::run_sqlite_exporter(store, event_rx, stop));
+        }
+
+        #[cfg(not(feature = "sqlite"))]
+        tracing::warn!("SQLite is enabled but wise was built without the `sqlite` feature");
+    }
+
     if di.config.borrow().exporting.websocket.enabled {
         let task = websocket::build_websocket_exporter(di.clone()).await?;
         _ = tokio::spawn(async move {
@@ -12,5 +67,14 @@ pub async fn setup_exporting(di: &DiContainer) -> Result<(), Box<dyn std::error:
         });
     }
 
-    Ok(())
+    let di_copy = di.clone();
+    exporters.spawn(|stop| recording::run_recording_exporter(di_copy, stop));
+
+    let file_config = di.config.borrow().exporting.file.clone();
+    if file_config.enabled {
+        let event_rx = di.game_events.receiver();
+        exporters.spawn(|stop| file::run_file_exporter(file_config, event_rx, stop));
+    }
+
+    Ok(exporters)
 }
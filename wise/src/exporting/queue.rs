@@ -1,16 +1,31 @@
-use tokio::sync::{broadcast::Receiver, broadcast::Sender};
-use wise_api::{
-    events::RconEvent,
-    messages::{ServerWsMessage, ServerWsResponse},
+use std::time::Duration;
+
+use tokio::{
+    sync::broadcast::{error::RecvError, Receiver, Sender},
+    time::{sleep, Instant},
 };
+use tracing::warn;
+use wise_api::{events::RconEvent, messages::ServerWsMessage};
 
 const EVENT_QUEUE_CAPACITY: usize = 1000;
 
+/// Messages the slowest receiver may be behind before paced sending waits for it.
+const PACED_QUEUE_LIMIT: usize = EVENT_QUEUE_CAPACITY / 2;
+
+/// How long paced sending waits for receivers which make no progress at all.
+const PACED_TIMEOUT: Duration = Duration::from_secs(5);
+
 #[derive(Debug, Clone)]
 pub struct EventSender {
     tx: Sender<ServerWsMessage>,
 }
 
+impl Default for EventSender {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl EventSender {
     pub fn new() -> Self {
         Self {
@@ -22,16 +37,34 @@ impl EventSender {
         EventReceiver::new(Sender::subscribe(&self.tx))
     }
 
-    /// Send a response to the client only if the id is [`Some`].
-    pub fn send_response(&self, id: Option<String>, value: ServerWsResponse) {
-        if let Some(id) = id {
-            _ = self.tx.send(ServerWsMessage::Response { id, value });
-        }
-    }
-
     pub fn send_rcon(&self, event: RconEvent) {
         _ = self.tx.send(ServerWsMessage::Rcon(event));
     }
+
+    /// Send the events no faster than every receiver takes them, for bursts which would
+    /// otherwise overrun the queue. Should receivers make no progress for [`PACED_TIMEOUT`]
+    /// the remaining events are sent right away, those receivers skip them.
+    pub async fn send_rcon_paced(&self, events: impl IntoIterator<Item = RconEvent>) {
+        let mut stalled = false;
+        for event in events {
+            let mut progressed_at = Instant::now();
+            let mut behind = self.tx.len();
+            while !stalled && behind >= PACED_QUEUE_LIMIT {
+                sleep(Duration::from_millis(5)).await;
+                if self.tx.len() < behind {
+                    progressed_at = Instant::now();
+                }
+                behind = self.tx.len();
+
+                if progressed_at.elapsed() >= PACED_TIMEOUT {
+                    warn!("Event receivers stopped taking paced events, sending regardless");
+                    stalled = true;
+                }
+            }
+
+            self.send_rcon(event);
+        }
+    }
 }
 
 #[derive(Debug)]
@@ -44,8 +77,61 @@ impl EventReceiver {
         Self { rx }
     }
 
+    /// Receive the next message. Messages missed because the receiver fell behind are skipped.
     pub async fn receive(&mut self) -> ServerWsMessage {
-        // TODO: make this redudant
-        self.rx.recv().await.unwrap()
+        loop {
+            match self.rx.recv().await {
+                Ok(message) => return message,
+                Err(RecvError::Lagged(skipped)) => {
+                    warn!("Event receiver fell behind, skipped {skipped} messages")
+                }
+                // TODO: make this redudant
+                Err(RecvError::Closed) => panic!("The event queue is never closed"),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rcon::parsing::showlog::{LogKind, LogLine};
+
+    use super::*;
+
+    fn historical_log(timestamp: u64) -> RconEvent {
+        RconEvent::HistoricalLog(LogLine {
+            timestamp,
+            kind: LogKind::Unknown {
+                raw: timestamp.to_string(),
+            },
+        })
+    }
+
+    #[tokio::test]
+    async fn paced_events_are_not_skipped_by_slow_receivers() {
+        let sender = EventSender::new();
+        let count = 3 * EVENT_QUEUE_CAPACITY as u64;
+
+        let mut rx = sender.receiver();
+        let receiving = tokio::spawn(async move {
+            let mut received = vec![];
+            loop {
+                let ServerWsMessage::Rcon(RconEvent::HistoricalLog(log)) = rx.receive().await
+                else {
+                    continue;
+                };
+                received.push(log.timestamp);
+                if log.timestamp == count - 1 {
+                    return received;
+                }
+                if received.len() % 100 == 0 {
+                    sleep(Duration::from_millis(1)).await;
+                }
+            }
+        });
+
+        sender.send_rcon_paced((0..count).map(historical_log)).await;
+        let received = receiving.await.unwrap();
+        assert_eq!(received, (0..count).collect::<Vec<_>>());
     }
 }
@@ -0,0 +1,517 @@
+//! Recording every match to its own file, to review games afterwards.
+//!
+//! # Format
+//! A recording is a gzip compressed file of newline delimited JSON, named
+//! `<started at, YYYYMMDD-HHMMSS UTC>_<map>.ndjson.gz`. Each line is a [`RecordingEntry`]:
+//!
+//! - The first line is always a [`RecordingEntry::Header`], carrying the
+//!   [`RECORDING_FORMAT_VERSION`] of the file.
+//! - [`RecordingEntry::Snapshot`] holds the full state of the game, written after the header
+//!   and periodically afterwards. Readers may seek to the closest snapshot before a timestamp.
+//! - [`RecordingEntry::Event`] holds a single [`RconEvent`] as sent over the websocket.
+//!
+//! A recording starts with the `MatchStart` log and ends with the `MatchEnded` log, both
+//! included as events. Should wise stop mid match the recording ends without it, and should
+//! wise not stop gracefully the file may be truncated. Readers keep the entries before the
+//! truncation.
+//! Timestamps are unix timestamps in milliseconds of when wise emitted the entry.
+//! Writes take place on a dedicated thread, as such they never block the async runtime.
+//!
+//! ```text
+//! {"Header":{"version":1,"started_at":1718194575000,"map":"CARENTAN WARFARE"}}
+//! {"Snapshot":{"timestamp":1718194575000,"state":{"players":[...]}}}
+//! {"Event":{"timestamp":1718194575000,"event":{"Log":{"timestamp":1718194575,"kind":{"MatchStart":{"map":"CARENTAN WARFARE"}}}}}}
+//! ```
+use std::{
+    fs::{self, File},
+    io::{self, BufRead, BufReader, BufWriter, Write},
+    path::{Path, PathBuf},
+    thread,
+};
+
+use chrono::{DateTime, Utc};
+use flate2::{read::MultiGzDecoder, write::GzEncoder, Compression};
+use rcon::{client::RconClient, parsing::showlog::LogKind};
+use serde::{Deserialize, Serialize};
+use tokio::{
+    sync::{
+        mpsc::{self, error::SendError},
+        oneshot,
+    },
+    time::{interval, MissedTickBehavior},
+};
+use tracing::{debug, error, info, warn};
+use wise_api::{events::RconEvent, messages::ServerWsMessage};
+
+use crate::services::{game_master::GameSnapshot, DiContainer};
+
+/// The version of the recording format written, incremented on breaking changes.
+pub const RECORDING_FORMAT_VERSION: u32 = 1;
+
+/// Entries waiting to be written. Should the writer fall behind, the exporter waits for it
+/// and skips the events the event queue no longer holds once it catches up.
+const WRITE_QUEUE_CAPACITY: usize = 1000;
+
+/// A single line of a recording.
+#[allow(clippy::large_enum_variant)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum RecordingEntry {
+    /// Describes the recording, always the first entry.
+    Header {
+        version: u32,
+        started_at: u64,
+        map: String,
+    },
+
+    /// The full state of the game.
+    Snapshot { timestamp: u64, state: GameSnapshot },
+
+    /// An event which took place.
+    Event { timestamp: u64, event: RconEvent },
+}
+
+/// The file of the match currently being recorded.
+struct Recording {
+    path: PathBuf,
+    writer: GzEncoder<BufWriter<File>>,
+}
+
+impl Recording {
+    /// Create the file for a match which started now on the given map.
+    fn create(directory: &Path, map: &str, started_at: DateTime<Utc>) -> io::Result<Self> {
+        fs::create_dir_all(directory)?;
+
+        let map_name: String = map
+            .chars()
+            .map(|c| if c.is_alphanumeric() { c } else { '_' })
+            .collect();
+        let path = directory.join(format!(
+            "{}_{}.ndjson.gz",
+            started_at.format("%Y%m%d-%H%M%S"),
+            map_name
+        ));
+
+        let file = File::create(&path)?;
+        Ok(Self {
+            path,
+            writer: GzEncoder::new(BufWriter::new(file), Compression::default()),
+        })
+    }
+
+    fn write(&mut self, entry: &RecordingEntry) -> io::Result<()> {
+        serde_json::to_writer(&mut self.writer, entry)?;
+        self.writer.write_all(b"\n")
+    }
+
+    /// Write everything buffered so far, such that the file is readable up to here.
+    fn flush(&mut self) -> io::Result<()> {
+        self.writer.flush()
+    }
+
+    /// Complete the file and return its path.
+    fn finish(self) -> io::Result<PathBuf> {
+        self.writer.finish()?.flush()?;
+        Ok(self.path)
+    }
+}
+
+/// Instructions for the thread writing the recordings.
+enum RecordingWrite {
+    /// Start recording a match, writing the header and the snapshot it started with.
+    Start {
+        directory: PathBuf,
+        map: String,
+        started_at: DateTime<Utc>,
+        snapshot: RecordingEntry,
+    },
+
+    /// Append an event to the current recording.
+    Event(RecordingEntry),
+
+    /// Append a snapshot to the current recording, making it readable up to here.
+    Snapshot(RecordingEntry),
+
+    /// Complete the current recording.
+    Finish,
+}
+
+/// Record every match to its own file until told to stop, completing the current one.
+pub async fn run_recording_exporter<C: RconClient>(
+    di: DiContainer<C>,
+    mut stop: oneshot::Receiver<()>,
+) {
+    let (tx, rx) = mpsc::channel(WRITE_QUEUE_CAPACITY);
+    let writer = thread::Builder::new()
+        .name("recording-writer".to_string())
+        .spawn(move || write_recordings(rx));
+    let writer = match writer {
+        Ok(writer) => writer,
+        Err(e) => {
+            error!("Failed to start the recording writer: {e}");
+            return;
+        }
+    };
+
+    let mut event_rx = di.game_events.receiver();
+    let snapshot_interval = di.config.borrow().exporting.recording.snapshot_interval_ms;
+    let mut snapshots = interval(snapshot_interval);
+    snapshots.set_missed_tick_behavior(MissedTickBehavior::Delay);
+
+    let mut recording = false;
+    loop {
+        // Events already queued are recorded before stopping.
+        let write = tokio::select! {
+            biased;
+            message = event_rx.receive() => {
+                let ServerWsMessage::Rcon(event) = message else {
+                    continue;
+                };
+                handle_event(&di, &mut recording, event, &tx).await
+            }
+
+            _ = snapshots.tick() => {
+                if !recording {
+                    continue;
+                }
+
+                let entry = RecordingEntry::Snapshot {
+                    timestamp: now(),
+                    state: di.game_master.current_state().await,
+                };
+                tx.send(RecordingWrite::Snapshot(entry)).await
+            }
+
+            _ = &mut stop => break,
+        };
+
+        if write.is_err() {
+            error!("The recording writer stopped, no longer recording matches");
+            break;
+        }
+    }
+
+    // Closing the queue completes the current recording once the queued entries are written.
+    drop(tx);
+    if let Ok(Err(_)) = tokio::task::spawn_blocking(move || writer.join()).await {
+        error!("The recording writer panicked");
+    }
+}
+
+/// Queue the event for the current recording, starting or finishing it on the match
+/// boundaries.
+async fn handle_event<C: RconClient>(
+    di: &DiContainer<C>,
+    recording: &mut bool,
+    event: RconEvent,
+    tx: &mpsc::Sender<RecordingWrite>,
+) -> Result<(), SendError<RecordingWrite>> {
+    let match_boundary = match &event {
+        RconEvent::Log(log) => match &log.kind {
+            LogKind::MatchStart { map } => Some(Some(map.clone())),
+            LogKind::MatchEnded { .. } => Some(None),
+            _ => None,
+        },
+        // Took place before wise started, as such these are not part of a live recording.
+        RconEvent::HistoricalLog(_) => return Ok(()),
+        _ => None,
+    };
+
+    if let Some(Some(map)) = match_boundary.clone() {
+        if *recording {
+            warn!("Match started before the previous one ended, finishing its recording");
+        }
+        *recording = start(di, map, tx).await?;
+    }
+
+    if !*recording {
+        return Ok(());
+    }
+
+    let entry = RecordingEntry::Event {
+        timestamp: now(),
+        event,
+    };
+    tx.send(RecordingWrite::Event(entry)).await?;
+
+    if let Some(None) = match_boundary {
+        *recording = false;
+        tx.send(RecordingWrite::Finish).await?;
+    }
+    Ok(())
+}
+
+/// Start recording the match if enabled, returning whether it is recorded.
+async fn start<C: RconClient>(
+    di: &DiContainer<C>,
+    map: String,
+    tx: &mpsc::Sender<RecordingWrite>,
+) -> Result<bool, SendError<RecordingWrite>> {
+    let config = di.config.borrow().exporting.recording.clone();
+    if !config.enabled {
+        debug!("Recording is disabled, not recording the match on {map}");
+        tx.send(RecordingWrite::Finish).await?;
+        return Ok(false);
+    }
+
+    let snapshot = RecordingEntry::Snapshot {
+        timestamp: now(),
+        state: di.game_master.current_state().await,
+    };
+    tx.send(RecordingWrite::Start {
+        directory: config.directory,
+        map,
+        started_at: Utc::now(),
+        snapshot,
+    })
+    .await?;
+    Ok(true)
+}
+
+/// Write the received instructions, blocking the thread until the sender is dropped.
+/// The current recording is completed then.
+fn write_recordings(mut rx: mpsc::Receiver<RecordingWrite>) {
+    let mut recording: Option<Recording> = None;
+    while let Some(write) = rx.blocking_recv() {
+        let (result, current) = match write {
+            RecordingWrite::Start {
+                directory,
+                map,
+                started_at,
+                snapshot,
+            } => {
+                if let Some(previous) = recording.take() {
+                    finish(previous);
+                }
+                recording = create(&directory, &map, started_at, &snapshot);
+                continue;
+            }
+            RecordingWrite::Event(entry) => match &mut recording {
+                Some(current) => (current.write(&entry), current),
+                None => continue,
+            },
+            RecordingWrite::Snapshot(entry) => match &mut recording {
+                Some(current) => (current.write(&entry).and_then(|_| current.flush()), current),
+                None => continue,
+            },
+            RecordingWrite::Finish => {
+                if let Some(current) = recording.take() {
+                    finish(current);
+                }
+                continue;
+            }
+        };
+
+        if let Err(e) = result {
+            error!(
+                "Failed to write to recording {}: {e}",
+                current.path.display()
+            );
+            recording = None;
+        }
+    }
+
+    if let Some(current) = recording {
+        finish(current);
+    }
+}
+
+/// Create the recording of a match, starting with its header and snapshot.
+fn create(
+    directory: &Path,
+    map: &str,
+    started_at: DateTime<Utc>,
+    snapshot: &RecordingEntry,
+) -> Option<Recording> {
+    let mut recording = match Recording::create(directory, map, started_at) {
+        Ok(recording) => recording,
+        Err(e) => {
+            error!("Failed to create a recording for the match on {map}: {e}");
+            return None;
+        }
+    };
+
+    let header = RecordingEntry::Header {
+        version: RECORDING_FORMAT_VERSION,
+        started_at: started_at.timestamp_millis() as u64,
+        map: map.to_string(),
+    };
+    if let Err(e) = recording
+        .write(&header)
+        .and_then(|_| recording.write(snapshot))
+    {
+        error!(
+            "Failed to write to recording {}: {e}",
+            recording.path.display()
+        );
+        return None;
+    }
+
+    info!("Recording the match to {}", recording.path.display());
+    Some(recording)
+}
+
+fn finish(recording: Recording) {
+    match recording.finish() {
+        Ok(path) => info!("Finished recording the match to {}", path.display()),
+        Err(e) => error!("Failed to finish a recording: {e}"),
+    }
+}
+
+/// The current unix timestamp in milliseconds.
+fn now() -> u64 {
+    Utc::now().timestamp_millis() as u64
+}
+
+/// Read all the entries of a recording, ensuring its format version is supported.
+/// Should the file be truncated the entries before the truncation are returned.
+pub fn read_recording(path: &Path) -> io::Result<Vec<RecordingEntry>> {
+    let reader = BufReader::new(MultiGzDecoder::new(File::open(path)?));
+    let mut entries = Vec::new();
+    for line in reader.lines() {
+        let entry = line.and_then(|line| {
+            if line.trim().is_empty() {
+                return Ok(None);
+            }
+            Ok(Some(serde_json::from_str::<RecordingEntry>(&line)?))
+        });
+
+        match entry {
+            Ok(Some(entry)) => entries.push(entry),
+            Ok(None) => {}
+            Err(e) => {
+                warn!(
+                    "Recording {} is truncated, keeping the first {} entries: {e}",
+                    path.display(),
+                    entries.len()
+                );
+                break;
+            }
+        }
+    }
+
+    match entries.first() {
+        Some(RecordingEntry::Header { version, .. }) if *version == RECORDING_FORMAT_VERSION => {
+            Ok(entries)
+        }
+        Some(RecordingEntry::Header { version, .. }) => Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("Unsupported recording format version {version}"),
+        )),
+        _ => Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "The recording does not start with a header",
+        )),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::testing::connect;
+
+    use super::*;
+
+    fn snapshot(timestamp: u64) -> RecordingEntry {
+        RecordingEntry::Snapshot {
+            timestamp,
+            state: GameSnapshot {
+                players: vec![],
+                game_state: None,
+                squads: vec![],
+            },
+        }
+    }
+
+    /// Create a recording in its own directory with the given entries.
+    fn recording(name: &str, entries: &[RecordingEntry]) -> Recording {
+        let directory = std::env::temp_dir().join(format!("wise-{}-{name}", std::process::id()));
+        let mut recording = Recording::create(&directory, "map", Utc::now()).unwrap();
+        for entry in entries {
+            recording.write(entry).unwrap();
+        }
+        recording
+    }
+
+    fn header() -> RecordingEntry {
+        RecordingEntry::Header {
+            version: RECORDING_FORMAT_VERSION,
+            started_at: 0,
+            map: "map".to_string(),
+        }
+    }
+
+    #[test]
+    fn reads_finished_recordings() {
+        let path = recording("finished", &[header(), snapshot(0), snapshot(1)])
+            .finish()
+            .unwrap();
+        assert_eq!(read_recording(&path).unwrap().len(), 3);
+        _ = fs::remove_dir_all(path.parent().unwrap());
+    }
+
+    #[test]
+    fn reads_back_events_with_players() {
+        let event = RecordingEntry::Event {
+            timestamp: 0,
+            event: RconEvent::Log(connect(0, 1, true)),
+        };
+        let path = recording("players", &[header(), event, snapshot(1)])
+            .finish()
+            .unwrap();
+
+        let entries = read_recording(&path).unwrap();
+        assert_eq!(entries.len(), 3);
+        assert!(matches!(
+            &entries[1],
+            RecordingEntry::Event { event: RconEvent::Log(log), .. } if log == &connect(0, 1, true)
+        ));
+        _ = fs::remove_dir_all(path.parent().unwrap());
+    }
+
+    #[test]
+    fn keeps_the_entries_of_truncated_recordings() {
+        let mut recording = recording("truncated", &[header(), snapshot(0), snapshot(1)]);
+        recording.flush().unwrap();
+        let path = recording.path.clone();
+
+        // Never finished, as if wise was killed.
+        std::mem::forget(recording);
+        assert_eq!(read_recording(&path).unwrap().len(), 3);
+        _ = fs::remove_dir_all(path.parent().unwrap());
+    }
+
+    #[test]
+    fn the_writer_completes_recordings_once_stopped() {
+        let directory = std::env::temp_dir().join(format!("wise-{}-writer", std::process::id()));
+        _ = fs::remove_dir_all(&directory);
+        let (tx, rx) = mpsc::channel(4);
+        let writes = [
+            RecordingWrite::Start {
+                directory: directory.clone(),
+                map: "map".to_string(),
+                started_at: Utc::now(),
+                snapshot: snapshot(0),
+            },
+            RecordingWrite::Event(RecordingEntry::Event {
+                timestamp: 1,
+                event: RconEvent::Log(connect(1, 1, true)),
+            }),
+            RecordingWrite::Snapshot(snapshot(2)),
+        ];
+        for write in writes {
+            tx.try_send(write).unwrap();
+        }
+        drop(tx);
+        write_recordings(rx);
+
+        let file = fs::read_dir(&directory).unwrap().next().unwrap().unwrap();
+        assert_eq!(read_recording(&file.path()).unwrap().len(), 4);
+        _ = fs::remove_dir_all(&directory);
+    }
+
+    #[test]
+    fn rejects_recordings_without_a_header() {
+        let path = recording("headerless", &[snapshot(0)]).finish().unwrap();
+        assert!(read_recording(&path).is_err());
+        _ = fs::remove_dir_all(path.parent().unwrap());
+    }
+}
@@ -0,0 +1,965 @@
+//! Persisting players, sessions, matches, kills and chat to an embedded SQLite database.
+//!
+//! # Schema
+//! All timestamps are unix timestamps in seconds, player ids are the steam or windows ids.
+//!
+//! - `players`: every player seen, with `first_seen` and `last_seen`.
+//! - `player_names` and `player_clan_tags`: every name and clan tag a player used.
+//! - `sessions`: when a player joined and, once known, left the server.
+//! - `matches`: the map, start and, once known, end and final score of every match.
+//! - `kills`: killer, victim, their factions, weapon and whether it was a teamkill.
+//!   Positions are the last known positions of both players, `NULL` if unknown.
+//! - `chat`: every chat message with its team and reach.
+//!
+//! - `events`: every event as sent over the websocket, with the players involved in
+//!   `event_players`. Served to clients by [`SqliteStore::query_events`]. Players changing
+//!   only their position are not persisted, their latest position is kept in memory for kills.
+//!
+//! Kills, chat and events reference the match they took place in, `NULL` if it is unknown.
+//! Historical logs emitted on startup are persisted as well, duplicates are ignored.
+//! Writes take place on a dedicated thread, as such they never block the async runtime.
+//! Once wise stops the events still queued are written before it exits.
+//!
+//! ```sql
+//! -- Who teamkilled most this week?
+//! SELECT killer_id, COUNT(*) AS teamkills FROM kills
+//! WHERE is_teamkill AND timestamp >= unixepoch() - 7 * 24 * 60 * 60
+//! GROUP BY killer_id ORDER BY teamkills DESC LIMIT 10;
+//! ```
+use std::{
+    collections::HashMap,
+    path::Path,
+    sync::{Arc, Mutex},
+    thread,
+};
+
+use chrono::Utc;
+use rcon::parsing::{
+    playerinfo::{PlayerData, WorldPosition},
+    showlog::{LogKind, LogLine},
+};
+use rusqlite::{params, params_from_iter, types::Value, Connection, OptionalExtension};
+use tokio::sync::{mpsc, oneshot};
+use tracing::{debug, error, info, warn};
+use wise_api::{
+    events::{EventKind, PlayerChanges, RconEvent, SquadEvent},
+    messages::{EventPage, EventQuery, ServerWsMessage, StoredEvent},
+};
+
+use crate::exporting::queue::EventReceiver;
+
+/// The schema, each entry migrates the database from the previous version to the next.
+/// The version of a database is kept in its `user_version`.
+const MIGRATIONS: &[&str] = &[
+    r#"
+CREATE TABLE players (
+    id TEXT PRIMARY KEY,
+    first_seen INTEGER NOT NULL,
+    last_seen INTEGER NOT NULL
+);
+
+CREATE TABLE player_names (
+    player_id TEXT NOT NULL REFERENCES players (id),
+    name TEXT NOT NULL,
+    first_seen INTEGER NOT NULL,
+    last_seen INTEGER NOT NULL,
+    PRIMARY KEY (player_id, name)
+);
+
+CREATE TABLE player_clan_tags (
+    player_id TEXT NOT NULL REFERENCES players (id),
+    clan_tag TEXT NOT NULL,
+    first_seen INTEGER NOT NULL,
+    last_seen INTEGER NOT NULL,
+    PRIMARY KEY (player_id, clan_tag)
+);
+
+CREATE TABLE sessions (
+    id INTEGER PRIMARY KEY,
+    player_id TEXT NOT NULL REFERENCES players (id),
+    joined_at INTEGER NOT NULL,
+    left_at INTEGER,
+    UNIQUE (player_id, joined_at)
+);
+
+CREATE TABLE matches (
+    id INTEGER PRIMARY KEY,
+    map TEXT NOT NULL,
+    started_at INTEGER NOT NULL,
+    ended_at INTEGER,
+    allied_score INTEGER,
+    axis_score INTEGER,
+    UNIQUE (map, started_at)
+);
+
+CREATE TABLE kills (
+    id INTEGER PRIMARY KEY,
+    match_id INTEGER REFERENCES matches (id),
+    timestamp INTEGER NOT NULL,
+    killer_id TEXT NOT NULL REFERENCES players (id),
+    killer_faction TEXT NOT NULL,
+    victim_id TEXT NOT NULL REFERENCES players (id),
+    victim_faction TEXT NOT NULL,
+    weapon TEXT NOT NULL,
+    is_teamkill INTEGER NOT NULL,
+    killer_x REAL,
+    killer_y REAL,
+    killer_z REAL,
+    victim_x REAL,
+    victim_y REAL,
+    victim_z REAL,
+    UNIQUE (timestamp, killer_id, victim_id, weapon)
+);
+
+CREATE TABLE chat (
+    id INTEGER PRIMARY KEY,
+    match_id INTEGER REFERENCES matches (id),
+    timestamp INTEGER NOT NULL,
+    sender_id TEXT NOT NULL REFERENCES players (id),
+    team TEXT NOT NULL,
+    reach TEXT NOT NULL,
+    content TEXT NOT NULL,
+    UNIQUE (timestamp, sender_id, content)
+);
+
+CREATE INDEX sessions_player ON sessions (player_id, joined_at);
+CREATE INDEX kills_timestamp ON kills (timestamp);
+CREATE INDEX kills_killer ON kills (killer_id, timestamp);
+CREATE INDEX kills_victim ON kills (victim_id, timestamp);
+CREATE INDEX chat_timestamp ON chat (timestamp);
+CREATE INDEX chat_sender ON chat (sender_id, timestamp);
+"#,
+    r#"
+CREATE TABLE events (
+    id INTEGER PRIMARY KEY,
+    match_id INTEGER REFERENCES matches (id),
+    timestamp INTEGER NOT NULL,
+    kind TEXT NOT NULL,
+    text TEXT,
+    event TEXT NOT NULL
+);
+
+CREATE TABLE event_players (
+    event_id INTEGER NOT NULL REFERENCES events (id),
+    player_id TEXT NOT NULL,
+    PRIMARY KEY (player_id, event_id)
+) WITHOUT ROWID;
+
+CREATE INDEX events_timestamp ON events (timestamp);
+CREATE INDEX events_match ON events (match_id);
+"#,
+];
+
+/// The number of events returned for a query should the client not provide a limit.
+const DEFAULT_QUERY_LIMIT: u32 = 100;
+
+/// The maximum number of events returned for a query.
+const MAX_QUERY_LIMIT: u32 = 1000;
+
+/// Events waiting to be written. Should the writer fall behind, the exporter waits for it
+/// and skips the events the event queue no longer holds once it catches up.
+const WRITE_QUEUE_CAPACITY: usize = 1000;
+
+/// A handle to the database, cheap to clone.
+#[derive(Clone)]
+pub struct SqliteStore {
+    connection: Arc<Mutex<Connection>>,
+}
+
+impl SqliteStore {
+    /// Open the database, creating it and migrating it to the latest schema if required.
+    pub fn open(path: &Path) -> rusqlite::Result<Self> {
+        let connection = Connection::open(path)?;
+        connection.pragma_update(None, "journal_mode", "WAL")?;
+        connection.pragma_update(None, "synchronous", "NORMAL")?;
+        Self::new(connection)
+    }
+
+    /// Take over the connection, migrating its database to the latest schema if required.
+    fn new(mut connection: Connection) -> rusqlite::Result<Self> {
+        connection.pragma_update(None, "foreign_keys", true)?;
+        migrate(&mut connection)?;
+
+        Ok(Self {
+            connection: Arc::new(Mutex::new(connection)),
+        })
+    }
+
+    /// Get a page of the persisted events matching the query. Blocks until done.
+    pub fn query_events(&self, query: &EventQuery) -> rusqlite::Result<EventPage> {
+        let mut conditions = vec![];
+        let mut values = vec![];
+        let mut bind = |value: Value| {
+            values.push(value);
+            format!("?{}", values.len())
+        };
+
+        if let Some(after) = query.after {
+            conditions.push(format!("id > {}", bind(Value::Integer(after as i64))));
+        }
+        if let Some(from) = query.from {
+            conditions.push(format!(
+                "timestamp >= {}",
+                bind(Value::Integer(from as i64))
+            ));
+        }
+        if let Some(to) = query.to {
+            conditions.push(format!("timestamp < {}", bind(Value::Integer(to as i64))));
+        }
+        if let Some(match_id) = query.match_id {
+            conditions.push(format!("match_id = {}", bind(Value::Integer(match_id))));
+        }
+        if let Some(player_id) = &query.player_id {
+            conditions.push(format!(
+                "id IN (SELECT event_id FROM event_players WHERE player_id = {})",
+                bind(Value::Text(player_id.clone()))
+            ));
+        }
+        if let Some(text) = &query.text {
+            let escaped = text
+                .replace('\\', "\\\\")
+                .replace('%', "\\%")
+                .replace('_', "\\_");
+            conditions.push(format!(
+                "text LIKE {} ESCAPE '\\'",
+                bind(Value::Text(format!("%{escaped}%")))
+            ));
+        }
+        if !query.kinds.is_empty() {
+            let kinds: Vec<_> = query
+                .kinds
+                .iter()
+                .map(|kind| bind(Value::Text(kind_name(*kind))))
+                .collect();
+            conditions.push(format!("kind IN ({})", kinds.join(", ")));
+        }
+
+        let limit = query
+            .limit
+            .unwrap_or(DEFAULT_QUERY_LIMIT)
+            .clamp(1, MAX_QUERY_LIMIT);
+        let filter = match conditions.is_empty() {
+            true => String::new(),
+            false => format!("WHERE {}", conditions.join(" AND ")),
+        };
+        // Fetch one more than the limit to know whether there is another page.
+        let sql = format!(
+            "SELECT id, timestamp, match_id, event FROM events {filter} ORDER BY id LIMIT {}",
+            limit + 1
+        );
+
+        let connection = self.connection.lock().unwrap();
+        let mut statement = connection.prepare(&sql)?;
+        let mut rows = statement
+            .query_map(params_from_iter(values), |row| {
+                Ok((row.get(0)?, row.get(1)?, row.get(2)?, row.get(3)?))
+            })?
+            .collect::<rusqlite::Result<Vec<(u64, u64, Option<i64>, String)>>>()?;
+
+        // Whether there is another page is decided by the rows, as such a row skipped below
+        // does not end paging early.
+        let next = match rows.len() > limit as usize {
+            true => {
+                rows.truncate(limit as usize);
+                rows.last().map(|(id, ..)| *id)
+            }
+            false => None,
+        };
+        let events = rows
+            .into_iter()
+            .filter_map(
+                |(id, timestamp, match_id, json)| match serde_json::from_str(&json) {
+                    Ok(event) => Some(StoredEvent {
+                        id,
+                        timestamp,
+                        match_id,
+                        event,
+                    }),
+                    Err(e) => {
+                        warn!("Skipping stored event {id}, it cannot be read: {e}");
+                        None
+                    }
+                },
+            )
+            .collect();
+        Ok(EventPage { events, next })
+    }
+}
+
+/// Apply the migrations the database is missing.
+fn migrate(connection: &mut Connection) -> rusqlite::Result<()> {
+    let version: usize = connection.pragma_query_value(None, "user_version", |r| r.get(0))?;
+    for (i, migration) in MIGRATIONS.iter().enumerate().skip(version) {
+        debug!("Migrating the database to schema version {}", i + 1);
+        let transaction = connection.transaction()?;
+        transaction.execute_batch(migration)?;
+        transaction.pragma_update(None, "user_version", i + 1)?;
+        transaction.commit()?;
+    }
+
+    Ok(())
+}
+
+/// Knowledge required to persist events which is not part of the events themselves.
+#[derive(Default)]
+struct Context {
+    /// The id of the match currently taking place.
+    current_match: Option<i64>,
+
+    /// The last known position of every player.
+    positions: HashMap<String, WorldPosition>,
+}
+
+/// Persist every RCON event to the database until told to stop, waiting for the queued
+/// events to be written.
+pub async fn run_sqlite_exporter(
+    store: SqliteStore,
+    mut event_rx: EventReceiver,
+    mut stop: oneshot::Receiver<()>,
+) {
+    info!("Persisting events to SQLite");
+    let (tx, rx) = mpsc::channel(WRITE_QUEUE_CAPACITY);
+    let writer = thread::Builder::new()
+        .name("sqlite-writer".to_string())
+        .spawn(move || write_events(store, rx));
+    let writer = match writer {
+        Ok(writer) => writer,
+        Err(e) => {
+            error!("Failed to start the SQLite writer: {e}");
+            return;
+        }
+    };
+
+    loop {
+        // Events already queued are written before stopping.
+        let message = tokio::select! {
+            biased;
+            message = event_rx.receive() => message,
+            _ = &mut stop => break,
+        };
+        let ServerWsMessage::Rcon(event) = message else {
+            continue;
+        };
+
+        if tx.send(event).await.is_err() {
+            error!("The SQLite writer stopped, no longer persisting events");
+            break;
+        }
+    }
+
+    drop(tx);
+    if let Ok(Err(_)) = tokio::task::spawn_blocking(move || writer.join()).await {
+        error!("The SQLite writer panicked");
+    }
+}
+
+/// Write the received events to the database, blocking the thread until the sender is dropped.
+fn write_events(store: SqliteStore, mut rx: mpsc::Receiver<RconEvent>) {
+    let mut context = Context::default();
+    while let Some(event) = rx.blocking_recv() {
+        if let RconEvent::Player { new, changes, .. } = &event {
+            if only_moved(changes) {
+                context
+                    .positions
+                    .insert(new.id.clone(), new.world_position.clone());
+                continue;
+            }
+        }
+
+        let mut connection = store.connection.lock().unwrap();
+        let result = connection.transaction().and_then(|transaction| {
+            let previous_match = context.current_match;
+            persist_event(&transaction, &mut context, &event)?;
+            // Match ends belong to the match which ended, starts to the match which started.
+            let match_id = context.current_match.or(previous_match);
+            persist_raw_event(&transaction, match_id, &event)?;
+            transaction.commit()
+        });
+
+        if let Err(e) = result {
+            error!("Failed to persist event {event:?}: {e}");
+        }
+    }
+}
+
+/// Whether the player did nothing but move, which happens too often to be worth persisting.
+fn only_moved(changes: &[PlayerChanges]) -> bool {
+    changes
+        .iter()
+        .all(|change| matches!(change, PlayerChanges::WorldPosition { .. }))
+}
+
+fn persist_event(
+    connection: &Connection,
+    context: &mut Context,
+    event: &RconEvent,
+) -> rusqlite::Result<()> {
+    match event {
+        RconEvent::Player { new, .. } => persist_player(connection, context, new),
+        RconEvent::Log(log) => persist_log(connection, context, log, false),
+        RconEvent::HistoricalLog(log) => persist_log(connection, context, log, true),
+        RconEvent::Game { .. }
+        | RconEvent::MatchSummary(_)
+        | RconEvent::KillDetails(_)
+        | RconEvent::Squad(_) => Ok(()),
+    }
+}
+
+/// Persist the event as it was sent over the websocket, for clients to query.
+fn persist_raw_event(
+    connection: &Connection,
+    match_id: Option<i64>,
+    event: &RconEvent,
+) -> rusqlite::Result<()> {
+    let (timestamp, log, historical) = match event {
+        RconEvent::Log(log) => (log.timestamp as i64, Some(log), false),
+        RconEvent::HistoricalLog(log) => (log.timestamp as i64, Some(log), true),
+        RconEvent::MatchSummary(summary) => (summary.ended_at as i64, None, false),
+        RconEvent::KillDetails(details) => (details.timestamp as i64, None, false),
+        RconEvent::Player { .. } | RconEvent::Game { .. } | RconEvent::Squad(_) => {
+            (Utc::now().timestamp(), None, false)
+        }
+    };
+
+    // Historical logs are stored as live ones, they may have been persisted while live.
+    let json = match log {
+        Some(log) => serde_json::to_string(&RconEvent::Log(log.clone())),
+        None => serde_json::to_string(event),
+    }
+    .map_err(|e| rusqlite::Error::ToSqlConversionFailure(Box::new(e)))?;
+
+    if historical {
+        let exists = connection
+            .query_row(
+                "SELECT 1 FROM events WHERE timestamp = ?1 AND event = ?2",
+                params![timestamp, json],
+                |_| Ok(()),
+            )
+            .optional()?;
+        if exists.is_some() {
+            return Ok(());
+        }
+    }
+
+    let (text, players) = match event {
+        RconEvent::Player { new, .. } => (None, vec![new.id.clone()]),
+        RconEvent::Game { .. } => (None, vec![]),
+        RconEvent::KillDetails(details) => (
+            None,
+            vec![details.killer.id.clone(), details.victim.id.clone()],
+        ),
+        RconEvent::MatchSummary(summary) => {
+            (None, summary.players.iter().map(|p| p.id.clone()).collect())
+        }
+        RconEvent::Squad(event) => (None, squad_event_players(event)),
+        RconEvent::Log(log) | RconEvent::HistoricalLog(log) => match &log.kind {
+            LogKind::Connect { player, .. } | LogKind::TeamSwitch { player, .. } => {
+                (None, vec![player.id.to_string()])
+            }
+            LogKind::Kill { killer, victim, .. } => {
+                (None, vec![killer.id.to_string(), victim.id.to_string()])
+            }
+            LogKind::Chat {
+                sender, content, ..
+            } => (Some(content), vec![sender.id.to_string()]),
+            LogKind::MatchStart { .. } | LogKind::MatchEnded { .. } | LogKind::Unknown { .. } => {
+                (None, vec![])
+            }
+        },
+    };
+
+    connection.execute(
+        "INSERT INTO events (match_id, timestamp, kind, text, event) VALUES (?1, ?2, ?3, ?4, ?5)",
+        params![match_id, timestamp, kind_name(event.kind()), text, json],
+    )?;
+    let event_id = connection.last_insert_rowid();
+    for player_id in players {
+        connection.execute(
+            "INSERT OR IGNORE INTO event_players (event_id, player_id) VALUES (?1, ?2)",
+            params![event_id, player_id],
+        )?;
+    }
+
+    Ok(())
+}
+
+/// The name a kind of event is stored under, that of its serialization.
+fn kind_name(kind: EventKind) -> String {
+    let name = serde_json::to_value(kind).expect("Event kinds always serialize");
+    name.as_str()
+        .expect("Event kinds serialize to their name")
+        .to_string()
+}
+
+/// The ids of the players a squad event is about.
+fn squad_event_players(event: &SquadEvent) -> Vec<String> {
+    match event {
+        SquadEvent::Created { squad } | SquadEvent::Changed { squad } => squad.members.clone(),
+        SquadEvent::Disbanded { .. } => vec![],
+        SquadEvent::LeaderChanged { old, new, .. }
+        | SquadEvent::CommanderChanged { old, new, .. } => old.iter().chain(new).cloned().collect(),
+    }
+}
+
+fn persist_player(
+    connection: &Connection,
+    context: &mut Context,
+    player: &PlayerData,
+) -> rusqlite::Result<()> {
+    let now = Utc::now().timestamp();
+    upsert_player(connection, &player.id, &player.name, now)?;
+
+    if !player.clan_tag.is_empty() {
+        connection.execute(
+            "INSERT INTO player_clan_tags (player_id, clan_tag, first_seen, last_seen)
+             VALUES (?1, ?2, ?3, ?3)
+             ON CONFLICT DO UPDATE SET last_seen = max(last_seen, excluded.last_seen)",
+            params![player.id, player.clan_tag, now],
+        )?;
+    }
+
+    context
+        .positions
+        .insert(player.id.clone(), player.world_position.clone());
+    Ok(())
+}
+
+fn persist_log(
+    connection: &Connection,
+    context: &mut Context,
+    log: &LogLine,
+    historical: bool,
+) -> rusqlite::Result<()> {
+    let timestamp = log.timestamp as i64;
+    match &log.kind {
+        LogKind::Connect {
+            player,
+            has_connected: true,
+        } => {
+            let id = player.id.to_string();
+            upsert_player(connection, &id, &player.name, timestamp)?;
+            connection.execute(
+                "INSERT OR IGNORE INTO sessions (player_id, joined_at) VALUES (?1, ?2)",
+                params![id, timestamp],
+            )?;
+        }
+        LogKind::Connect {
+            player,
+            has_connected: false,
+        } => {
+            let id = player.id.to_string();
+            upsert_player(connection, &id, &player.name, timestamp)?;
+            connection.execute(
+                "UPDATE sessions SET left_at = ?2
+                 WHERE player_id = ?1 AND left_at IS NULL AND joined_at <= ?2",
+                params![id, timestamp],
+            )?;
+            context.positions.remove(&id);
+        }
+        LogKind::Kill {
+            killer,
+            killer_faction,
+            victim,
+            victim_faction,
+            is_teamkill,
+            weapon,
+        } => {
+            let killer_id = killer.id.to_string();
+            let victim_id = victim.id.to_string();
+            upsert_player(connection, &killer_id, &killer.name, timestamp)?;
+            upsert_player(connection, &victim_id, &victim.name, timestamp)?;
+
+            // Positions known now say nothing about where historical kills took place.
+            let position = |id: &str| match historical {
+                true => None,
+                false => context.positions.get(id),
+            };
+            let killer_position = position(&killer_id);
+            let victim_position = position(&victim_id);
+
+            connection.execute(
+                "INSERT OR IGNORE INTO kills (
+                    match_id, timestamp, killer_id, killer_faction, victim_id, victim_faction,
+                    weapon, is_teamkill, killer_x, killer_y, killer_z, victim_x, victim_y, victim_z
+                 ) VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11, ?12, ?13, ?14)",
+                params![
+                    context.current_match,
+                    timestamp,
+                    killer_id,
+                    killer_faction,
+                    victim_id,
+                    victim_faction,
+                    weapon,
+                    is_teamkill,
+                    killer_position.map(|p| p.x),
+                    killer_position.map(|p| p.y),
+                    killer_position.map(|p| p.z),
+                    victim_position.map(|p| p.x),
+                    victim_position.map(|p| p.y),
+                    victim_position.map(|p| p.z),
+                ],
+            )?;
+        }
+        LogKind::MatchStart { map } => {
+            connection.execute(
+                "INSERT OR IGNORE INTO matches (map, started_at) VALUES (?1, ?2)",
+                params![map, timestamp],
+            )?;
+            context.current_match = connection
+                .query_row(
+                    "SELECT id FROM matches WHERE map = ?1 AND started_at = ?2",
+                    params![map, timestamp],
+                    |r| r.get(0),
+                )
+                .optional()?;
+        }
+        LogKind::MatchEnded {
+            map,
+            allied_score,
+            axis_score,
+        } => {
+            connection.execute(
+                "UPDATE matches SET ended_at = ?2, allied_score = ?3, axis_score = ?4
+                 WHERE id = (
+                    SELECT id FROM matches
+                    WHERE map = ?1 AND started_at <= ?2 AND ended_at IS NULL
+                    ORDER BY started_at DESC LIMIT 1
+                 )",
+                params![map, timestamp, allied_score, axis_score],
+            )?;
+            context.current_match = None;
+        }
+        LogKind::Chat {
+            sender,
+            team,
+            reach,
+            content,
+        } => {
+            let id = sender.id.to_string();
+            upsert_player(connection, &id, &sender.name, timestamp)?;
+            connection.execute(
+                "INSERT OR IGNORE INTO chat (match_id, timestamp, sender_id, team, reach, content)
+                 VALUES (?1, ?2, ?3, ?4, ?5, ?6)",
+                params![context.current_match, timestamp, id, team, reach, content],
+            )?;
+        }
+        LogKind::TeamSwitch { .. } | LogKind::Unknown { .. } => {}
+    }
+
+    Ok(())
+}
+
+/// Record that the player was seen with the name at the time.
+fn upsert_player(
+    connection: &Connection,
+    id: &str,
+    name: &str,
+    timestamp: i64,
+) -> rusqlite::Result<()> {
+    connection.execute(
+        "INSERT INTO players (id, first_seen, last_seen) VALUES (?1, ?2, ?2)
+         ON CONFLICT DO UPDATE SET
+            first_seen = min(first_seen, excluded.first_seen),
+            last_seen = max(last_seen, excluded.last_seen)",
+        params![id, timestamp],
+    )?;
+    connection.execute(
+        "INSERT INTO player_names (player_id, name, first_seen, last_seen) VALUES (?1, ?2, ?3, ?3)
+         ON CONFLICT DO UPDATE SET
+            first_seen = min(first_seen, excluded.first_seen),
+            last_seen = max(last_seen, excluded.last_seen)",
+        params![id, name, timestamp],
+    )?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use rcon::parsing::showlog::LogKind;
+
+    use crate::{
+        exporting::queue::EventSender,
+        testing::{connect, log, player},
+    };
+
+    use super::*;
+
+    fn store() -> SqliteStore {
+        SqliteStore::new(Connection::open_in_memory().unwrap()).unwrap()
+    }
+
+    /// Write the events like the exporter would, returning once all are written.
+    fn write(store: &SqliteStore, events: Vec<RconEvent>) {
+        let (tx, rx) = mpsc::channel(events.len().max(1));
+        for event in events {
+            tx.try_send(event).unwrap();
+        }
+        drop(tx);
+        write_events(store.clone(), rx);
+    }
+
+    fn count(store: &SqliteStore, sql: &str) -> i64 {
+        let connection = store.connection.lock().unwrap();
+        connection.query_row(sql, [], |r| r.get(0)).unwrap()
+    }
+
+    fn kill(timestamp: u64, killer: u64, victim: u64) -> LogLine {
+        log(
+            timestamp,
+            LogKind::Kill {
+                killer: player(killer),
+                killer_faction: "Allies".to_string(),
+                victim: player(victim),
+                victim_faction: "Axis".to_string(),
+                is_teamkill: false,
+                weapon: "M1 GARAND".to_string(),
+            },
+        )
+    }
+
+    #[test]
+    fn migrations_are_applied_once() {
+        let store = store();
+        let mut connection = store.connection.lock().unwrap();
+        let version: usize = connection
+            .pragma_query_value(None, "user_version", |r| r.get(0))
+            .unwrap();
+        assert_eq!(version, MIGRATIONS.len());
+
+        // Migrating again would fail on the existing tables.
+        migrate(&mut connection).unwrap();
+    }
+
+    #[test]
+    fn tracks_sessions_and_matches() {
+        let store = store();
+        let start = log(
+            100,
+            LogKind::MatchStart {
+                map: "CARENTAN".to_string(),
+            },
+        );
+        let end = log(
+            200,
+            LogKind::MatchEnded {
+                map: "CARENTAN".to_string(),
+                allied_score: 3,
+                axis_score: 2,
+            },
+        );
+        let logs = [
+            start,
+            connect(110, 1, true),
+            kill(120, 1, 2),
+            connect(130, 1, false),
+            end,
+            kill(210, 2, 1),
+        ];
+        write(&store, logs.into_iter().map(RconEvent::Log).collect());
+
+        let connection = store.connection.lock().unwrap();
+        let session: (i64, Option<i64>) = connection
+            .query_row(
+                "SELECT joined_at, left_at FROM sessions WHERE player_id = '1'",
+                [],
+                |r| Ok((r.get(0)?, r.get(1)?)),
+            )
+            .unwrap();
+        assert_eq!(session, (110, Some(130)));
+
+        let (match_id, ended_at, allied_score): (i64, i64, i64) = connection
+            .query_row(
+                "SELECT id, ended_at, allied_score FROM matches WHERE started_at = 100",
+                [],
+                |r| Ok((r.get(0)?, r.get(1)?, r.get(2)?)),
+            )
+            .unwrap();
+        assert_eq!((ended_at, allied_score), (200, 3));
+
+        // Kills after the match ended belong to no match.
+        let kills: Vec<(i64, Option<i64>)> = connection
+            .prepare("SELECT timestamp, match_id FROM kills ORDER BY timestamp")
+            .unwrap()
+            .query_map([], |r| Ok((r.get(0)?, r.get(1)?)))
+            .unwrap()
+            .collect::<Result<_, _>>()
+            .unwrap();
+        assert_eq!(kills, vec![(120, Some(match_id)), (210, None)]);
+
+        // The end belongs to the match which ended.
+        let ended_in: Option<i64> = connection
+            .query_row(
+                "SELECT match_id FROM events WHERE timestamp = 200",
+                [],
+                |r| r.get(0),
+            )
+            .unwrap();
+        assert_eq!(ended_in, Some(match_id));
+    }
+
+    #[test]
+    fn historical_logs_are_persisted_once() {
+        let store = store();
+        write(
+            &store,
+            vec![
+                RconEvent::Log(kill(120, 1, 2)),
+                RconEvent::HistoricalLog(kill(120, 1, 2)),
+                RconEvent::HistoricalLog(kill(130, 1, 2)),
+            ],
+        );
+
+        assert_eq!(count(&store, "SELECT COUNT(*) FROM events"), 2);
+        assert_eq!(count(&store, "SELECT COUNT(*) FROM kills"), 2);
+        assert_eq!(count(&store, "SELECT COUNT(*) FROM players"), 2);
+        assert_eq!(count(&store, "SELECT COUNT(*) FROM event_players"), 4);
+    }
+
+    fn chat(timestamp: u64, sender: u64, content: &str) -> LogLine {
+        log(
+            timestamp,
+            LogKind::Chat {
+                sender: player(sender),
+                team: "Allies".to_string(),
+                reach: "Team".to_string(),
+                content: content.to_string(),
+            },
+        )
+    }
+
+    /// The timestamps of the events matching the query.
+    fn query(store: &SqliteStore, query: EventQuery) -> Vec<u64> {
+        let page = store.query_events(&query).unwrap();
+        page.events.into_iter().map(|e| e.timestamp).collect()
+    }
+
+    #[test]
+    fn queries_filter_events() {
+        let store = store();
+        let logs = [
+            connect(100, 1, true),
+            chat(110, 1, "100% sure"),
+            chat(120, 2, "100 sure"),
+            chat(130, 1, "a_b"),
+            chat(140, 2, "axb"),
+            kill(150, 2, 1),
+        ];
+        write(&store, logs.into_iter().map(RconEvent::Log).collect());
+
+        let kinds = |kinds: Vec<EventKind>| EventQuery {
+            kinds,
+            ..Default::default()
+        };
+        assert_eq!(query(&store, kinds(vec![EventKind::Kill])), vec![150]);
+        assert_eq!(
+            query(&store, kinds(vec![EventKind::Connect, EventKind::Kill])),
+            vec![100, 150]
+        );
+
+        let player = EventQuery {
+            player_id: Some("1".to_string()),
+            ..Default::default()
+        };
+        assert_eq!(query(&store, player), vec![100, 110, 130, 150]);
+
+        let window = EventQuery {
+            from: Some(110),
+            to: Some(130),
+            ..Default::default()
+        };
+        assert_eq!(query(&store, window), vec![110, 120]);
+
+        // Wildcards within the text are matched literally.
+        let text = |text: &str| EventQuery {
+            text: Some(text.to_string()),
+            ..Default::default()
+        };
+        assert_eq!(query(&store, text("100%")), vec![110]);
+        assert_eq!(query(&store, text("A_B")), vec![130]);
+        assert_eq!(query(&store, text("sure")), vec![110, 120]);
+    }
+
+    #[test]
+    fn queries_are_paged() {
+        let store = store();
+        let logs = (0..5).map(|t| chat(t, 1, "gg"));
+        write(&store, logs.map(RconEvent::Log).collect());
+
+        let mut query = EventQuery {
+            limit: Some(2),
+            ..Default::default()
+        };
+        let mut pages = vec![];
+        loop {
+            let page = store.query_events(&query).unwrap();
+            pages.push(page.events.iter().map(|e| e.timestamp).collect::<Vec<_>>());
+            if page.next.is_none() {
+                break;
+            }
+            assert_eq!(page.next, page.events.last().map(|e| e.id));
+            query.after = page.next;
+        }
+        assert_eq!(pages, vec![vec![0, 1], vec![2, 3], vec![4]]);
+
+        // A page exactly filling the limit is the last one.
+        let query = EventQuery {
+            limit: Some(5),
+            ..Default::default()
+        };
+        assert_eq!(store.query_events(&query).unwrap().next, None);
+    }
+
+    #[test]
+    fn unreadable_events_are_skipped() {
+        let store = store();
+        let logs = (0..3).map(|t| chat(t, 1, "gg"));
+        write(&store, logs.map(RconEvent::Log).collect());
+        store
+            .connection
+            .lock()
+            .unwrap()
+            .execute("UPDATE events SET event = '{}' WHERE timestamp = 1", [])
+            .unwrap();
+
+        let query = EventQuery {
+            limit: Some(2),
+            ..Default::default()
+        };
+        let page = store.query_events(&query).unwrap();
+        let timestamps: Vec<_> = page.events.iter().map(|e| e.timestamp).collect();
+        assert_eq!(timestamps, vec![0]);
+        // The skipped event still counts towards the page.
+        assert!(page.next.is_some());
+    }
+
+    #[tokio::test]
+    async fn stopping_writes_the_queued_events() {
+        let store = store();
+        let game_events = EventSender::new();
+        let (stop, stop_rx) = oneshot::channel();
+        let exporter = tokio::spawn(run_sqlite_exporter(
+            store.clone(),
+            game_events.receiver(),
+            stop_rx,
+        ));
+
+        for t in 0..3 {
+            game_events.send_rcon(RconEvent::Log(chat(t, 1, "gg")));
+        }
+        drop(stop);
+        exporter.await.unwrap();
+        assert_eq!(count(&store, "SELECT COUNT(*) FROM events"), 3);
+    }
+
+    #[test]
+    fn kinds_are_stored_by_their_serialized_name() {
+        let store = store();
+        write(&store, vec![RconEvent::Log(connect(100, 1, false))]);
+
+        let connection = store.connection.lock().unwrap();
+        let kind: String = connection
+            .query_row("SELECT kind FROM events", [], |r| r.get(0))
+            .unwrap();
+        assert_eq!(kind, "Disconnect");
+    }
+}
@@ -1,6 +1,7 @@
 use std::{error::Error, fs::File, io::BufReader, path::Path, sync::Arc};
 
 use futures::Future;
+use rcon::client::RconClient;
 use tokio::net::TcpListener;
 use tokio_rustls::{rustls::ServerConfig, TlsAcceptor};
 use tracing::debug;
@@ -11,14 +12,14 @@ use crate::{
 };
 
 /// Build the task that listens for incoming websocket connections.
-pub async fn build_websocket_exporter(
-    di: DiContainer,
+pub async fn build_websocket_exporter<C: RconClient>(
+    di: DiContainer<C>,
 ) -> Result<impl Future<Output = Result<(), Box<dyn Error>>>, Box<dyn Error>> {
     debug!("Initializing exporting over WebSockets");
     let ws_config = &di.config.borrow().exporting.websocket.clone();
 
     let acceptor = if ws_config.tls {
-        Some(build_tls_ws(&ws_config)?)
+        Some(build_tls_ws(ws_config)?)
     } else {
         None
     };
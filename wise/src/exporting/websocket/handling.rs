@@ -1,10 +1,11 @@
 use std::{error::Error, net::SocketAddr, time::Duration};
 
 use futures::{SinkExt, StreamExt};
-use rcon::{messages::RconRequest, RconError};
+use rcon::{client::RconClient, messages::RconRequest, RconError};
 use tokio::{
     io::{AsyncRead, AsyncWrite},
     net::{TcpListener, TcpStream},
+    sync::mpsc,
     time::timeout,
 };
 use tokio_rustls::TlsAcceptor;
@@ -13,23 +14,48 @@ use tracing::{debug, error, info, info_span, instrument, trace, warn};
 
 use crate::{
     exporting::auth::{authenticate_token, AuthHandle},
-    services::DiContainer,
+    services::{
+        connection_pool::{PoolError, Priority},
+        DiContainer,
+    },
 };
 
-use wise_api::messages::*;
+use wise_api::{events::RconEvent, messages::*};
 
-#[derive(Clone)]
-struct WsContext {
+struct WsContext<C> {
     peer: SocketAddr,
     auth: AuthHandle,
-    di: DiContainer,
+    di: DiContainer<C>,
+
+    /// Responses to the requests of this client, sent only over its own connection.
+    responses: mpsc::UnboundedSender<ServerWsMessage>,
+}
+
+impl<C> Clone for WsContext<C> {
+    fn clone(&self) -> Self {
+        Self {
+            peer: self.peer,
+            auth: self.auth.clone(),
+            di: self.di.clone(),
+            responses: self.responses.clone(),
+        }
+    }
+}
+
+impl<C> WsContext<C> {
+    /// Respond to this client only if the id is [`Some`].
+    fn respond(&self, id: Option<String>, value: ServerWsResponse) {
+        if let Some(id) = id {
+            _ = self.responses.send(ServerWsMessage::Response { id, value });
+        }
+    }
 }
 
 /// Runs the websocket server as a background task.
-pub async fn run_websocket_server(
+pub async fn run_websocket_server<C: RconClient>(
     listener: TcpListener,
     acceptor: Option<TlsAcceptor>,
-    di: DiContainer,
+    di: DiContainer<C>,
 ) -> Result<(), Box<dyn Error>> {
     if acceptor.is_some() {
         info!(
@@ -44,13 +70,20 @@ pub async fn run_websocket_server(
     }
 
     while let Ok((stream, peer)) = listener.accept().await {
+        let (responses, response_rx) = mpsc::unbounded_channel();
         let ctx = WsContext {
             peer,
             auth: AuthHandle::default_no_perms(),
             di: di.clone(),
+            responses,
         };
 
-        _ = tokio::spawn(accept_connection(stream, acceptor.clone(), ctx));
+        _ = tokio::spawn(accept_connection(
+            stream,
+            acceptor.clone(),
+            ctx,
+            response_rx,
+        ));
     }
 
     info!("WebSocket server stopped");
@@ -59,30 +92,38 @@ pub async fn run_websocket_server(
 
 /// Accept a connection
 #[instrument(skip_all, fields(peer = ?ctx.peer))]
-async fn accept_connection(stream: TcpStream, acceptor: Option<TlsAcceptor>, ctx: WsContext) {
-    if acceptor.is_some() {
-        let tls_stream = acceptor.unwrap().accept(stream).await.unwrap();
+async fn accept_connection<C: RconClient>(
+    stream: TcpStream,
+    acceptor: Option<TlsAcceptor>,
+    ctx: WsContext<C>,
+    response_rx: mpsc::UnboundedReceiver<ServerWsMessage>,
+) {
+    if let Some(acceptor) = acceptor {
+        let tls_stream = acceptor.accept(stream).await.unwrap();
         let ws_stream = tokio_tungstenite::accept_async(tls_stream)
             .await
             .expect("WebSocket handshake failed");
 
         debug!("Accepted TLS websocket connection");
-        handle_connection(ws_stream, ctx).await;
+        handle_connection(ws_stream, ctx, response_rx).await;
     } else {
         let ws_stream = tokio_tungstenite::accept_async(stream)
             .await
             .expect("WebSocket handshake failed");
 
         debug!("Accepted websocket connection");
-        handle_connection(ws_stream, ctx).await;
+        handle_connection(ws_stream, ctx, response_rx).await;
     };
 
     debug!("WebSocket connection closed");
 }
 
 /// Handle a single websocket connection.
-async fn handle_connection<T>(mut ws_stream: WebSocketStream<T>, mut ctx: WsContext)
-where
+async fn handle_connection<T, C: RconClient>(
+    mut ws_stream: WebSocketStream<T>,
+    mut ctx: WsContext<C>,
+    mut response_rx: mpsc::UnboundedReceiver<ServerWsMessage>,
+) where
     T: AsyncRead + AsyncWrite + Unpin,
 {
     let auth_handle = handle_token(&mut ws_stream, &mut ctx).await;
@@ -97,8 +138,28 @@ where
     _ = ws_stream.send(Message::text(json)).await;
 
     info!("WebSocket connection fully ready");
-
+    // Subscribed before getting the history and starting a replay, such that neither a
+    // historical log nor the state the replay starts with is missed.
     let mut event_rx = ctx.di.game_events.receiver();
+    if let Some(replay) = ctx
+        .di
+        .replay
+        .as_ref()
+        .filter(|_| ctx.auth.perms.read_rcon_events)
+    {
+        replay.client_connected();
+    }
+
+    let mut given_history = false;
+    if ctx.auth.perms.read_rcon_events {
+        let history = ctx.di.game_master.history().await;
+        given_history = !history.is_empty();
+        for log in history {
+            let event = ServerWsMessage::Rcon(RconEvent::HistoricalLog(log));
+            send_message(&mut ws_stream, &event).await;
+        }
+    }
+
     // Main loop for the WS connection
     loop {
         tokio::select! {
@@ -114,16 +175,35 @@ where
                     continue;
                 }
 
-                match serde_json::to_string(&event) {
-                    Ok(json) => _ = ws_stream.send(Message::text(json)).await,
-                    Err(e) => warn!("Failed to serialize server websocket message {}", e),
-                };
+                // The history is set before its logs are sent, those still being sent are
+                // already part of the history given to the client.
+                if given_history && matches!(event, ServerWsMessage::Rcon(RconEvent::HistoricalLog(_))) {
+                    continue;
+                }
+
+                send_message(&mut ws_stream, &event).await;
             }
+
+            // Never closed, the context holds a sender.
+            Some(response) = response_rx.recv() => send_message(&mut ws_stream, &response).await,
         }
     }
 }
 
-async fn handle_token<T>(stream: &mut WebSocketStream<T>, ctx: &mut WsContext) -> Result<(), ()>
+async fn send_message<T>(ws_stream: &mut WebSocketStream<T>, message: &ServerWsMessage)
+where
+    T: AsyncRead + AsyncWrite + Unpin,
+{
+    match serde_json::to_string(message) {
+        Ok(json) => _ = ws_stream.send(Message::text(json)).await,
+        Err(e) => warn!("Failed to serialize server websocket message {}", e),
+    };
+}
+
+async fn handle_token<T, C>(
+    stream: &mut WebSocketStream<T>,
+    ctx: &mut WsContext<C>,
+) -> Result<(), ()>
 where
     T: AsyncRead + AsyncWrite + Unpin,
 {
@@ -150,7 +230,7 @@ where
     Ok(())
 }
 
-fn accept_client_message(message: Message, ctx: &WsContext) {
+fn accept_client_message<C: RconClient>(message: Message, ctx: &WsContext<C>) {
     trace!("Received message from client {}", message);
     if !message.is_text() {
         return;
@@ -167,8 +247,14 @@ fn accept_client_message(message: Message, ctx: &WsContext) {
         }
     };
 
-    if !ctx.auth.perms.write_rcon {
-        warn!("Client is not allowed to execute commands");
+    let ClientWsMessage::Request { value, .. } = &client_message;
+    let allowed = match value {
+        ClientWsRequest::Execute(_) => ctx.auth.perms.write_rcon,
+        ClientWsRequest::Replay(_) => ctx.auth.perms.write_rcon,
+        ClientWsRequest::QueryEvents(_) => ctx.auth.perms.read_rcon_events,
+    };
+    if !allowed {
+        warn!("Client is not allowed to send {value:?}");
         // TODO: emit an error here on the websocket
         return;
     }
@@ -176,9 +262,32 @@ fn accept_client_message(message: Message, ctx: &WsContext) {
     _ = tokio::spawn(handle_client_message(client_message, ctx.clone()));
 }
 
-async fn handle_client_message(message: ClientWsMessage, mut ctx: WsContext) {
+async fn handle_client_message<C: RconClient>(message: ClientWsMessage, mut ctx: WsContext<C>) {
     let ClientWsMessage::Request { id, value } = message;
-    let ClientWsRequest::Execute(request) = value;
+    let request = match value {
+        ClientWsRequest::Execute(request) => request,
+        ClientWsRequest::Replay(control) => {
+            let status = match &ctx.di.replay {
+                Some(replay) => replay.control(control).await,
+                None => None,
+            };
+            let ws_response = ServerWsResponse::Replay {
+                failure: status.is_none(),
+                status,
+            };
+            ctx.respond(id, ws_response);
+            return;
+        }
+        ClientWsRequest::QueryEvents(query) => {
+            let page = query_events(&ctx.di, query).await;
+            let ws_response = ServerWsResponse::QueryEvents {
+                failure: page.is_none(),
+                page,
+            };
+            ctx.respond(id, ws_response);
+            return;
+        }
+    };
     let response_kind = execute_client_command(&mut ctx, request).await;
 
     let ws_response = match response_kind {
@@ -186,35 +295,68 @@ async fn handle_client_message(message: ClientWsMessage, mut ctx: WsContext) {
             failure: false,
             response: Some(o),
         },
+        // The server refusing the command is not an internal failure, the client is told why.
+        Err(e @ RconError::ServerRejected { .. }) => ServerWsResponse::Execute {
+            failure: false,
+            response: Some(CommandResponseKind::Error(e.to_string())),
+        },
         Err(_) => ServerWsResponse::Execute {
             failure: true,
             response: None,
         },
     };
 
-    ctx.di.game_events.send_response(id, ws_response);
+    ctx.respond(id, ws_response);
+}
+
+/// Query the persisted events, [`None`] if they are not persisted or the query failed.
+#[cfg(feature = "sqlite")]
+async fn query_events<C>(di: &DiContainer<C>, query: EventQuery) -> Option<EventPage> {
+    let store = di.store.clone()?;
+    match tokio::task::spawn_blocking(move || store.query_events(&query)).await {
+        Ok(Ok(page)) => Some(page),
+        Ok(Err(e)) => {
+            error!("Failed to query events: {e}");
+            None
+        }
+        Err(e) => {
+            error!("Failed to query events: {e}");
+            None
+        }
+    }
+}
+
+/// Query the persisted events, always [`None`] as wise was built without SQLite.
+#[cfg(not(feature = "sqlite"))]
+async fn query_events<C>(_di: &DiContainer<C>, _query: EventQuery) -> Option<EventPage> {
+    None
 }
 
 /// Execute a client command on the connection pool.
-async fn execute_client_command(
-    ctx: &mut WsContext,
+async fn execute_client_command<C: RconClient>(
+    ctx: &mut WsContext<C>,
     kind: CommandRequestKind,
 ) -> Result<CommandResponseKind, RconError> {
-    let mut conn = ctx.di.connection_pool.get_connection().await.unwrap();
+    let mut conn = ctx
+        .di
+        .connection_pool
+        .get_connection(Priority::Interactive)
+        .await
+        .map_err(PoolError::into_inner)?;
 
     let response = match kind {
         CommandRequestKind::Raw { name, content_body } => conn
             .execute(RconRequest::new(name, content_body))
             .await
-            .map(|v| CommandResponseKind::Raw(v)),
+            .map(CommandResponseKind::Raw),
         CommandRequestKind::GetGameState => conn
             .fetch_gamestate()
             .await
-            .map(|o| CommandResponseKind::GetGameState(o)),
+            .map(CommandResponseKind::GetGameState),
         CommandRequestKind::GetPlayers => conn
             .fetch_players()
             .await
-            .map(|o| CommandResponseKind::GetPlayers(o)),
+            .map(CommandResponseKind::GetPlayers),
         CommandRequestKind::GetPlayer(id) => conn
             .fetch_player(id)
             .await
@@ -235,11 +377,17 @@ async fn execute_client_command(
             .kick_player(&id, &reason)
             .await
             .map(|_| CommandResponseKind::Success),
-        CommandRequestKind::TemporaryBan() => todo!(),
-        CommandRequestKind::RemoveTemporaryBan() => todo!(),
+        CommandRequestKind::TemporaryBan(id, hours, reason) => conn
+            .temp_ban_player(&id, hours, &reason)
+            .await
+            .map(|_| CommandResponseKind::Success),
+        CommandRequestKind::RemoveTemporaryBan(id) => conn
+            .remove_temp_ban_player(&id)
+            .await
+            .map(|_| CommandResponseKind::Success),
     };
 
-    ctx.di.connection_pool.return_connection(conn).await;
+    ctx.di.connection_pool.release(conn, &response).await;
 
     response
 }
@@ -5,68 +5,126 @@ pub mod services;
 
 pub mod utils;
 
-use std::{error::Error, time::Duration};
+#[cfg(test)]
+mod testing;
+
+use std::{
+    error::Error,
+    path::{Path, PathBuf},
+};
 
 use clap::Parser;
-use config::{setup_config, AppConfig, CliConfig};
+use config::{setup_config, AppConfig, CliCommand, CliConfig, RconProtocol};
 
-use exporting::setup_exporting;
-use services::{polling_manager::start_polling, *};
+use exporting::{recording::read_recording, setup_exporting, websocket::build_websocket_exporter};
+use services::{
+    moderation::run_moderation, polling_manager::start_polling, replay::start_replay,
+    simulator::run_simulation, *,
+};
 use tokio::{
     io::{stdin, AsyncBufReadExt, BufReader},
-    time::sleep,
+    signal,
 };
-use tracing::{error, info, level_filters::LevelFilter};
+use tracing::{error, info, level_filters::LevelFilter, warn};
 use tracing_subscriber::{fmt, layer::SubscriberExt, reload, util::SubscriberInitExt, Layer};
 
-use rcon::{connection::RconConnection, credentials::RconCredentials, messages::RconRequest};
+use rcon::{
+    capture::{read_capture, ReplayConnection},
+    client::RconClient,
+    connection::RconConnection,
+    connection_v1::RconV1Connection,
+    constants::MAX_LOG_BACKTRACK,
+    credentials::RconCredentials,
+    messages::RconRequest,
+    multiplexed::MultiplexedConnection,
+    offline::OfflineConnection,
+    resilient::{ReconnectPolicy, ResilientConnection},
+};
 use utils::get_levelfilter;
 
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn Error>> {
-    let config = load_config()?;
+    let cli_config = CliConfig::parse();
+    let config = load_config(cli_config.config_file)?;
     info!("File config intialized");
 
+    match cli_config.command {
+        Some(CliCommand::Replay { file, speed, step }) => {
+            return run_replay(config, &file, speed, step).await
+        }
+        Some(CliCommand::ParseCapture { file }) => return run_parse_capture(&file).await,
+        Some(CliCommand::Simulate {
+            seed,
+            players,
+            speed,
+        }) => return run_simulator(config, seed, players, speed).await,
+        None => {}
+    }
+
+    let protocol = config.borrow().rcon.protocol;
+    let multiplexed = config.borrow().pool.multiplexed;
+    info!("Using RCON protocol {protocol:?}");
+    match protocol {
+        RconProtocol::V1 => {
+            if multiplexed {
+                warn!("The v1 protocol can not multiplex connections, ignoring the option");
+            }
+            run::<RconV1Connection>(config).await
+        }
+        RconProtocol::V2 if multiplexed => run::<MultiplexedConnection>(config).await,
+        RconProtocol::V2 => run::<RconConnection>(config).await,
+    }
+}
+
+/// Run the application with the given kind of connection to the server.
+async fn run<C: RconClient>(config: AppConfig) -> Result<(), Box<dyn Error>> {
     if config.borrow().operational.direct_cli {
-        run_direct_cli(&config).await?;
+        run_direct_cli::<C>(&config).await?;
         return Ok(());
     }
 
-    test_connectivity(&config.borrow().rcon).await?;
+    let credentials = config.borrow().rcon.credentials.clone();
+    test_connectivity::<C>(&credentials).await?;
     info!("Connection to server successfully tested");
 
-    let di = DiContainer::create(config);
+    let mut di = DiContainer::<C>::create(config);
+    if let Err(e) = di.connection_pool.warm_up().await {
+        error!("Failed to open the minimum amount of connections: {e}");
+    }
+    info!(
+        "Connection pool ready {:?}",
+        di.connection_pool.stats().await
+    );
 
-    setup_exporting(&di).await?;
+    let exporters = setup_exporting(&mut di).await?;
+    _ = tokio::spawn(run_moderation(di.clone()));
     start_polling(&di);
 
-    loop {
-        sleep(Duration::from_secs(36000)).await;
-    }
+    signal::ctrl_c().await?;
+    info!("Shutting down");
+    exporters.shutdown().await;
+    Ok(())
 }
 
 /// Loads the config from the file and setups logging.
-fn load_config() -> Result<AppConfig, Box<dyn Error>> {
-    let cli_config = CliConfig::parse();
-
+fn load_config(config_file: PathBuf) -> Result<AppConfig, Box<dyn Error>> {
     let filtered_layer = fmt::Layer::default().with_filter(LevelFilter::INFO);
     let (filtered_layer, reload_handle) = reload::Layer::new(filtered_layer);
     tracing_subscriber::registry().with(filtered_layer).init();
 
     info!("Logging & CLI config initialized... Loading file config");
 
-    let rx = setup_config(cli_config.config_file)?;
+    let rx = setup_config(config_file)?;
     let mut rx_clone = rx.clone();
     _ = tokio::spawn(async move {
         loop {
-            _ = rx_clone
-                .wait_for(|obj| {
-                    _ = reload_handle.modify(|layer| {
-                        *layer.filter_mut() = get_levelfilter(obj.operational.log_level)
-                    });
-                    return true;
-                })
-                .await;
+            let log_level = rx_clone.borrow_and_update().operational.log_level;
+            _ = reload_handle.modify(|layer| *layer.filter_mut() = get_levelfilter(log_level));
+
+            // Wait for the config to change instead of spinning on the current one.
+            if rx_clone.changed().await.is_err() {
+                return;
+            }
         }
     });
 
@@ -74,10 +132,10 @@ fn load_config() -> Result<AppConfig, Box<dyn Error>> {
 }
 
 /// Test if connectivity to the server exists.
-async fn test_connectivity(
+async fn test_connectivity<C: RconClient>(
     credentials: &RconCredentials,
 ) -> Result<(), Box<dyn std::error::Error>> {
-    let connection = RconConnection::new(credentials).await;
+    let connection = C::connect(credentials).await;
     if let Err(e) = connection {
         error!("The test connection to the server failed: {e}");
         return Err(e.into());
@@ -86,13 +144,119 @@ async fn test_connectivity(
     Ok(())
 }
 
-async fn run_direct_cli(config: &AppConfig) -> Result<(), Box<dyn Error>> {
+/// Serve a recorded game over the websocket. Commands fail as no server is connected.
+async fn run_replay(
+    config: AppConfig,
+    file: &Path,
+    speed: f64,
+    step: bool,
+) -> Result<(), Box<dyn Error>> {
+    if !speed.is_finite() || speed <= 0.0 {
+        return Err("The replay speed must be greater than zero".into());
+    }
+
+    let entries = read_recording(file)?;
+    info!("Loaded recording {} to replay", file.display());
+
+    let mut di = DiContainer::<OfflineConnection>::create(config);
+    di.replay = Some(start_replay(entries, speed, step, di.game_events.clone()));
+
+    let task = build_websocket_exporter(di).await?;
+    _ = task.await;
+    Ok(())
+}
+
+/// Parse every player and admin log response of a capture again, each through a replay
+/// serving only that response, and print what the parsers made of it.
+async fn run_parse_capture(file: &Path) -> Result<(), Box<dyn Error>> {
+    let entries = read_capture(file)?;
+    info!(
+        "Loaded {} entries of capture {}",
+        entries.len(),
+        file.display()
+    );
+
+    for entry in entries {
+        let request = entry.request.clone();
+        let timestamp = entry.timestamp;
+        let mut connection = ReplayConnection::new([entry]);
+
+        let body = serde_json::from_str::<serde_json::Value>(&request.content_body);
+        let body = body.unwrap_or_default();
+        let parsed = match (request.name.as_str(), body["Name"].as_str()) {
+            ("AdminLog", _) => connection
+                .fetch_showlog(MAX_LOG_BACKTRACK, &[])
+                .await
+                .map(|logs| format!("{logs:#?}")),
+            ("ServerInformation", Some("players")) => connection
+                .fetch_players()
+                .await
+                .map(|players| format!("{players:#?}")),
+            ("ServerInformation", Some("player")) => {
+                let id = body["Value"].as_str().unwrap_or_default().to_string();
+                connection
+                    .fetch_player(id)
+                    .await
+                    .map(|player| format!("{player:#?}"))
+            }
+            _ => continue,
+        };
+
+        match parsed {
+            Ok(parsed) => println!("{parsed}"),
+            Err(e) => error!(
+                "Failed to parse the response to '{}' captured at {timestamp}. << {e}",
+                request.name
+            ),
+        }
+    }
+
+    Ok(())
+}
+
+/// Simulate matches and export them like those of a real server. Commands, such as those
+/// of the moderation, fail as no server is connected.
+async fn run_simulator(
+    config: AppConfig,
+    seed: Option<u64>,
+    players: usize,
+    speed: f64,
+) -> Result<(), Box<dyn Error>> {
+    if !speed.is_finite() || speed <= 0.0 {
+        return Err("The simulation speed must be greater than zero".into());
+    }
+
+    let mut di = DiContainer::<OfflineConnection>::create(config);
+    let exporters = setup_exporting(&mut di).await?;
+    _ = tokio::spawn(run_moderation(di.clone()));
+
+    let seed = seed.unwrap_or_else(rand::random);
+    tokio::select! {
+        _ = run_simulation(di, seed, players, speed) => {}
+        res = signal::ctrl_c() => res?,
+    }
+
+    info!("Shutting down");
+    exporters.shutdown().await;
+    Ok(())
+}
+
+async fn run_direct_cli<C: RconClient>(config: &AppConfig) -> Result<(), Box<dyn Error>> {
     let reader = BufReader::new(stdin());
     let mut lines = reader.lines();
 
-    let mut connection = RconConnection::new(&config.borrow().rcon).await?;
+    let credentials = config.borrow().rcon.credentials.clone();
+    let mut connection =
+        ResilientConnection::<C>::new(credentials, ReconnectPolicy::default()).await?;
     info!("Running direct CLI to Hell Let Loose server");
 
+    let mut state_rx = connection.subscribe();
+    _ = tokio::spawn(async move {
+        while state_rx.changed().await.is_ok() {
+            info!("Connection state changed to {:?}", *state_rx.borrow());
+        }
+    });
+
     loop {
         let Some(command) = lines.next_line().await? else {
             continue;
@@ -101,9 +265,13 @@ async fn run_direct_cli(config: &AppConfig) -> Result<(), Box<dyn Error>> {
         let Some((name, body)) = command.split_once(" ") else {
             continue;
         };
-        let response = connection.execute(RconRequest::new(name, body)).await?;
-        dbg!(&response);
-
-        print!("{}", response.content_body);
+        // A failed command, even a broken connection, should not end the CLI.
+        match connection.execute(RconRequest::new(name, body)).await {
+            Ok(response) => {
+                dbg!(&response);
+                print!("{}", response.content_body);
+            }
+            Err(e) => error!("Failed to execute '{name}'. << {e}"),
+        }
     }
 }
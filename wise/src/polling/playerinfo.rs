@@ -1,13 +1,17 @@
 use std::time::Duration;
 
-use crate::services::{game_master::IncomingState, DiContainer};
+use crate::services::{connection_pool::Priority, game_master::IncomingState, DiContainer};
 
+use chrono::Utc;
+use rcon::client::RconClient;
 use tokio::time::sleep;
 use tracing::{debug, error, instrument};
 
 /// Consistently polls the current state of a player and records the changes.
 #[instrument(level = "debug", skip_all)]
-pub async fn poll_players(mut di: DiContainer) -> Result<(), Box<dyn std::error::Error>> {
+pub async fn poll_players<C: RconClient>(
+    mut di: DiContainer<C>,
+) -> Result<(), Box<dyn std::error::Error>> {
     debug!("Starting player poller");
 
     // TODO: stop the loop if we go into hibernation
@@ -16,10 +20,15 @@ pub async fn poll_players(mut di: DiContainer) -> Result<(), Box<dyn std::error:
 
         // execute method is super janky generally. Maybe it will be looked at
         // TODO: do not exit the loop on connection failure
-        let Ok(mut conn) = di.connection_pool.get_connection().await else {
+        let Ok(mut conn) = di
+            .connection_pool
+            .get_connection(Priority::Background)
+            .await
+        else {
             continue;
         };
 
+        let polled_at = Utc::now().timestamp_millis() as u64;
         let players = match conn.fetch_players().await {
             Ok(v) => v,
             Err(e) => {
@@ -31,7 +40,7 @@ pub async fn poll_players(mut di: DiContainer) -> Result<(), Box<dyn std::error:
         di.connection_pool.return_connection(conn).await;
         let di_copy = di.clone();
         di.game_master
-            .update_state(IncomingState::Players(players), &di_copy)
+            .update_state(IncomingState::Players { players, polled_at }, &di_copy)
             .await;
     }
 }
@@ -1,31 +1,50 @@
-use std::time::Duration;
+use std::{collections::HashSet, time::Duration};
 
 use chrono::Utc;
-use rcon::parsing::showlog::LogLine;
+use rcon::{
+    client::RconClient,
+    constants::MAX_LOG_BACKTRACK,
+    parsing::showlog::{LogKind, LogLine},
+};
 use tokio::time::sleep;
-use tracing::{error, instrument};
+use tracing::{debug, error, instrument, trace, warn};
 
-use crate::services::{game_master::IncomingState, DiContainer};
+use crate::services::{connection_pool::Priority, game_master::IncomingState, DiContainer};
+
+/// Extra time requested before the last seen log to make up for clock drift
+/// between the server and us and logs arriving late.
+const LOG_MARGIN: Duration = Duration::from_secs(30);
+
+/// How long logs are remembered after the last seen log. The server rounds the window up to
+/// whole minutes, logs that far back have to be told apart from those arriving too late.
+const LOG_MEMORY: Duration = Duration::from_secs(LOG_MARGIN.as_secs() + 60);
 
 /// Repeatedly poll the admin logs.
 #[instrument(level = "debug", skip_all)]
-pub async fn poll_showlog(mut di: DiContainer) -> Result<(), Box<dyn std::error::Error>> {
-    // TODO: use the rx
-    let mut known_logs = vec![];
+pub async fn poll_showlog<C: RconClient>(
+    mut di: DiContainer<C>,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let mut cursor = LogCursor::default();
+    backfill_logs(&mut di, &mut cursor).await;
 
     loop {
         sleep(Duration::from_secs(1)).await;
 
         // execute method is super janky generally. Maybe it will be looked at
         // TODO: do not exit the loop on connection failure
-        let Ok(mut conn) = di.connection_pool.get_connection().await else {
+        let Ok(mut conn) = di
+            .connection_pool
+            .get_connection(Priority::Background)
+            .await
+        else {
             continue;
         };
 
-        let new_logs = match conn.fetch_showlog().await {
+        let backtrack = cursor.backtrack();
+        let new_logs = match conn.fetch_showlog(backtrack, &[]).await {
             Ok(v) => v,
             Err(e) => {
-                error!("An error ocurred while fetching players. << {e}");
+                error!("An error ocurred while fetching logs. << {e}");
                 continue;
             }
         };
@@ -33,7 +52,12 @@ pub async fn poll_showlog(mut di: DiContainer) -> Result<(), Box<dyn std::error:
         di.connection_pool.return_connection(conn).await;
         let di_copy = di.clone();
 
-        let untracked_logs = merge_logs(&mut known_logs, new_logs);
+        let untracked_logs = cursor.merge(new_logs);
+        trace!(
+            "Fetched logs with a backtrack of {:?}, {} are new",
+            backtrack,
+            untracked_logs.len()
+        );
 
         di.game_master
             .update_state(IncomingState::Logs(untracked_logs), &di_copy)
@@ -41,19 +65,119 @@ pub async fn poll_showlog(mut di: DiContainer) -> Result<(), Box<dyn std::error:
     }
 }
 
-/// Merge and combine the logs to update the old and get the currently untracked logs.
-fn merge_logs(old_logs: &mut Vec<LogLine>, mut new_logs: Vec<LogLine>) -> Vec<LogLine> {
-    let untracked_logs = new_logs
+/// Fetch the logs from before polling started and feed those of the current match into
+/// the game master as historical logs. All fetched logs are merged into the cursor.
+async fn backfill_logs<C: RconClient>(di: &mut DiContainer<C>, cursor: &mut LogCursor) {
+    let backfill_mins = di.config.borrow().polling.backfill_mins;
+    if backfill_mins == 0 {
+        return;
+    }
+
+    let backtrack = Duration::from_secs(backfill_mins * 60).min(MAX_LOG_BACKTRACK);
+    let Ok(mut conn) = di
+        .connection_pool
+        .get_connection(Priority::Background)
+        .await
+    else {
+        error!("Failed to get a connection to backfill logs");
+        return;
+    };
+
+    let logs = match conn.fetch_showlog(backtrack, &[]).await {
+        Ok(v) => v,
+        Err(e) => {
+            error!("An error ocurred while backfilling logs. << {e}");
+            return;
+        }
+    };
+    di.connection_pool.return_connection(conn).await;
+
+    let mut logs = cursor.merge(logs);
+    logs.sort_by_key(|l| l.timestamp);
+
+    // Only the current match is of interest, anything before is discarded.
+    if let Some(start) = logs
         .iter()
-        .filter(|new_log| !old_logs.contains(new_log))
-        .map(|l| l.clone())
-        .collect::<Vec<LogLine>>();
+        .rposition(|l| matches!(l.kind, LogKind::MatchStart { .. }))
+    {
+        logs.drain(..start);
+    }
+
+    debug!(
+        "Backfilling {} logs with a backtrack of {:?}",
+        logs.len(),
+        backtrack
+    );
+    let di_copy = di.clone();
+    di.game_master
+        .update_state(IncomingState::HistoricalLogs(logs), &di_copy)
+        .await;
+}
+
+/// Tracks which logs have already been seen to only request and emit new ones.
+#[derive(Debug, Default)]
+struct LogCursor {
+    /// Timestamp of the most recent log seen so far.
+    last_seen: Option<u64>,
+
+    /// All logs seen within [`LOG_MEMORY`] of `last_seen`.
+    known_logs: HashSet<LogLine>,
+}
+
+impl LogCursor {
+    /// The minimal window that has to be requested to receive all logs since the last poll.
+    fn backtrack(&self) -> Duration {
+        self.backtrack_at(Utc::now().timestamp() as u64)
+    }
+
+    /// The window to request at the unix timestamp in seconds, capped at the maximum the
+    /// server allows such that quiet servers do not request ever more history.
+    fn backtrack_at(&self, now: u64) -> Duration {
+        let Some(last_seen) = self.last_seen else {
+            return LOG_MARGIN;
+        };
+
+        let since = Duration::from_secs(now.saturating_sub(last_seen)) + LOG_MARGIN;
+        since.min(MAX_LOG_BACKTRACK)
+    }
+
+    /// Merge the newly fetched logs and return those which are untracked.
+    fn merge(&mut self, new_logs: Vec<LogLine>) -> Vec<LogLine> {
+        // Anything older than the cutoff has been seen by a previous poll already, unless
+        // it arrived too late to be requested again.
+        let cutoff = self.cutoff();
+        let mut untracked_logs = vec![];
+        let mut skipped = 0;
+        for log in new_logs {
+            if !self.known_logs.insert(log.clone()) {
+                continue;
+            }
+
+            match cutoff.is_some_and(|c| log.timestamp < c) {
+                true => skipped += 1,
+                false => untracked_logs.push(log),
+            }
+        }
+        if skipped > 0 {
+            warn!("Skipped {skipped} logs which arrived more than {LOG_MARGIN:?} late");
+        }
+
+        let newest = untracked_logs.iter().map(|l| l.timestamp).max();
+        self.last_seen = self.last_seen.max(newest);
 
-    old_logs.append(&mut new_logs);
-    let cutoff = (Utc::now() - Duration::from_secs(60 * 2)).timestamp() as u64;
-    old_logs.retain(|l| l.timestamp > cutoff);
+        if let Some(last_seen) = self.last_seen {
+            let forget = last_seen.saturating_sub(LOG_MEMORY.as_secs());
+            self.known_logs.retain(|l| l.timestamp >= forget);
+        }
+
+        untracked_logs
+    }
 
-    untracked_logs
+    /// The timestamp before which logs are no longer tracked.
+    fn cutoff(&self) -> Option<u64> {
+        self.last_seen
+            .map(|l| l.saturating_sub(LOG_MARGIN.as_secs()))
+    }
 }
 
 /*
@@ -115,3 +239,82 @@ async fn handle_untracked_log(
 }
 
  */
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn log(timestamp: u64, raw: &str) -> LogLine {
+        LogLine {
+            timestamp,
+            kind: LogKind::Unknown {
+                raw: raw.to_string(),
+            },
+        }
+    }
+
+    #[test]
+    fn backtrack_covers_the_time_since_the_last_log() {
+        let mut cursor = LogCursor::default();
+        assert_eq!(cursor.backtrack_at(1000), LOG_MARGIN);
+
+        cursor.merge(vec![log(1000, "a")]);
+        assert_eq!(cursor.backtrack_at(1000), LOG_MARGIN);
+        assert_eq!(
+            cursor.backtrack_at(1060),
+            Duration::from_secs(60) + LOG_MARGIN
+        );
+    }
+
+    #[test]
+    fn backtrack_is_capped_on_quiet_servers() {
+        let mut cursor = LogCursor::default();
+        cursor.merge(vec![log(1000, "a")]);
+
+        let now = 1000 + 2 * MAX_LOG_BACKTRACK.as_secs();
+        assert_eq!(cursor.backtrack_at(now), MAX_LOG_BACKTRACK);
+    }
+
+    #[test]
+    fn merge_only_returns_untracked_logs() {
+        let mut cursor = LogCursor::default();
+        assert_eq!(cursor.merge(vec![log(1000, "a"), log(1001, "b")]).len(), 2);
+
+        let untracked = cursor.merge(vec![log(1000, "a"), log(1001, "b"), log(1002, "c")]);
+        assert_eq!(untracked, vec![log(1002, "c")]);
+    }
+
+    #[test]
+    fn merge_skips_logs_older_than_the_margin() {
+        let mut cursor = LogCursor::default();
+        cursor.merge(vec![log(1000, "a")]);
+
+        let old = 1000 - LOG_MARGIN.as_secs() - 1;
+        assert!(cursor.merge(vec![log(old, "old")]).is_empty());
+
+        // Logs within the margin may arrive late and are still emitted.
+        let late = 1000 - LOG_MARGIN.as_secs();
+        assert_eq!(cursor.merge(vec![log(late, "late")]).len(), 1);
+    }
+
+    #[test]
+    fn merge_remembers_logs_the_server_still_returns() {
+        let mut cursor = LogCursor::default();
+        cursor.merge(vec![log(1000, "a")]);
+
+        // Outside the margin, yet within the whole minute the server rounds up to.
+        let now = 1000 + LOG_MARGIN.as_secs() + 1;
+        assert_eq!(cursor.merge(vec![log(1000, "a"), log(now, "b")]).len(), 1);
+        assert_eq!(cursor.known_logs.len(), 2);
+    }
+
+    #[test]
+    fn merge_forgets_logs_outside_the_memory() {
+        let mut cursor = LogCursor::default();
+        cursor.merge(vec![log(1000, "a")]);
+        cursor.merge(vec![log(1000 + LOG_MEMORY.as_secs() + 1, "b")]);
+
+        assert_eq!(cursor.known_logs.len(), 1);
+        assert_eq!(cursor.last_seen, Some(1000 + LOG_MEMORY.as_secs() + 1));
+    }
+}
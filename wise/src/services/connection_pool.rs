@@ -1,18 +1,123 @@
-use std::{collections::VecDeque, fmt::Debug, io::ErrorKind, pin::Pin, sync::Arc};
+use std::{
+    collections::VecDeque,
+    fmt::Debug,
+    io::ErrorKind,
+    ops::{Deref, DerefMut},
+    pin::Pin,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+    time::Instant,
+};
 
 use futures::Future;
-use rcon::{connection::RconConnection, RconError};
+use rcon::{
+    capture::CaptureRecorder, client::RconClient, connection::RconConnection,
+    ratelimit::RateLimiter, RconError,
+};
+use serde::Serialize;
 use thiserror::Error;
-use tokio::sync::Mutex;
-use tracing::{debug, error, trace};
+use tokio::{
+    sync::{Mutex, OwnedSemaphorePermit, Semaphore},
+    time::timeout,
+};
+use tracing::{debug, error, info, trace};
 
-use crate::config::AppConfig;
+use crate::config::{AppConfig, PoolConfig};
 
 /// A lightweight struct referencing a protected list of connections and config.
-#[derive(Debug, Clone)]
-pub struct ConnectionPool {
-    pub connections: Arc<Mutex<VecDeque<RconConnection>>>,
+#[derive(Debug)]
+pub struct ConnectionPool<C = RconConnection> {
+    /// Connections currently not in use, the most recently returned at the back.
+    pub connections: Arc<Mutex<VecDeque<IdleConnection<C>>>>,
     pub config: Arc<AppConfig>,
+
+    /// Limits the amount of connections open at the same time.
+    permits: Arc<Semaphore>,
+
+    /// The maximum size the pool was created with, the amount of `permits` in total.
+    capacity: usize,
+
+    /// Limits the amount of connections background tasks may take, keeping the rest
+    /// reserved for interactive commands.
+    background_permits: Arc<Semaphore>,
+
+    /// Limits the commands sent by background tasks over all connections.
+    background_limiter: Option<RateLimiter>,
+
+    /// Limits the commands sent by interactive commands over all connections.
+    interactive_limiter: Option<RateLimiter>,
+
+    /// Records the traffic of all connections, if enabled.
+    recorder: Option<CaptureRecorder>,
+
+    /// The most recently created connection of clients able to share theirs, see
+    /// [`RconClient::share`]. New slots of the pool use it instead of opening another one.
+    shared: Arc<Mutex<Option<C>>>,
+    counters: Arc<PoolCounters>,
+}
+
+/// A connection waiting in the pool to be reused.
+#[derive(Debug)]
+pub struct IdleConnection<C = RconConnection> {
+    connection: C,
+    returned_at: Instant,
+}
+
+/// A connection taken from the pool. Dropping it instead of returning it
+/// discards the connection and frees its slot in the pool.
+#[derive(Debug)]
+pub struct PooledConnection<C = RconConnection> {
+    connection: C,
+    _permit: OwnedSemaphorePermit,
+    _background_permit: Option<OwnedSemaphorePermit>,
+}
+
+/// The priority with which a connection is requested from the pool.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Priority {
+    /// Commands issued by a user waiting on the result, these may use the reserved connections.
+    Interactive,
+
+    /// Periodic tasks such as polling which yield to interactive commands.
+    Background,
+}
+
+impl<C> Deref for PooledConnection<C> {
+    type Target = C;
+
+    fn deref(&self) -> &Self::Target {
+        &self.connection
+    }
+}
+
+impl<C> DerefMut for PooledConnection<C> {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        &mut self.connection
+    }
+}
+
+#[derive(Debug, Default)]
+struct PoolCounters {
+    created: AtomicUsize,
+    failed: AtomicUsize,
+}
+
+/// A snapshot of the pools usage.
+#[derive(Debug, Clone, Serialize)]
+pub struct PoolStats {
+    /// Connections currently taken from the pool.
+    pub in_use: usize,
+
+    /// Connections waiting in the pool to be reused.
+    pub idle: usize,
+
+    /// Connections created over the lifetime of the pool.
+    pub created: usize,
+
+    /// Connections which failed to be created or failed their health check.
+    pub failed: usize,
 }
 
 // TODO: remove the pool error enum its useless
@@ -27,6 +132,15 @@ pub enum PoolError {
     Unrecoverable(RconError),
 }
 
+impl PoolError {
+    /// Get the underlying rcon error.
+    pub fn into_inner(self) -> RconError {
+        match self {
+            PoolError::Recoverable(e) | PoolError::Unrecoverable(e) => e,
+        }
+    }
+}
+
 impl From<RconError> for PoolError {
     fn from(value: RconError) -> Self {
         match &value {
@@ -42,41 +156,113 @@ impl From<RconError> for PoolError {
 
 const MAX_RETRIES: usize = 5;
 
-impl ConnectionPool {
+impl<C> Clone for ConnectionPool<C> {
+    fn clone(&self) -> Self {
+        Self {
+            connections: self.connections.clone(),
+            config: self.config.clone(),
+            permits: self.permits.clone(),
+            capacity: self.capacity,
+            background_permits: self.background_permits.clone(),
+            background_limiter: self.background_limiter.clone(),
+            interactive_limiter: self.interactive_limiter.clone(),
+            recorder: self.recorder.clone(),
+            shared: self.shared.clone(),
+            counters: self.counters.clone(),
+        }
+    }
+}
+
+impl<C: RconClient> ConnectionPool<C> {
+    /// Create a new pool. The maximum size and rate limits are fixed upon creation and not updated with the config.
     pub fn new(config: AppConfig) -> Self {
+        let pool_config = config.borrow().pool.clone();
+        let rate_limit = config.borrow().rate_limit.clone();
+        let capture_file = config.borrow().operational.capture_file.clone();
+        let recorder = capture_file.and_then(|path| match CaptureRecorder::create(&path) {
+            Ok(recorder) => {
+                info!("Recording RCON traffic to {}", path.display());
+                Some(recorder)
+            }
+            Err(e) => {
+                error!("Failed to create the capture file {}: {e}", path.display());
+                None
+            }
+        });
+        // The config is validated to leave background tasks at least one connection.
+        let background_size = pool_config
+            .max_size
+            .saturating_sub(pool_config.reserved_interactive);
+
         Self {
             connections: Arc::default(),
             config: Arc::new(config),
+            permits: Arc::new(Semaphore::new(pool_config.max_size)),
+            capacity: pool_config.max_size,
+            background_permits: Arc::new(Semaphore::new(background_size)),
+            background_limiter: rate_limit.polling.limiter(),
+            interactive_limiter: rate_limit.client.limiter(),
+            recorder,
+            shared: Arc::default(),
+            counters: Arc::default(),
         }
     }
 
-    /// Execute a given function on the pool. A returned [`Err`] guarantees that the
+    /// Open connections until the configured minimum of idle connections is reached.
+    pub async fn warm_up(&mut self) -> Result<(), PoolError> {
+        let pool_config = self.pool_config();
+        let min_size = pool_config.min_size.min(self.capacity);
+        let idle = self.connections.lock().await.len();
+
+        for _ in idle..min_size {
+            let connection = self.allocate_connection().await?;
+            self.connections.lock().await.push_back(IdleConnection {
+                connection,
+                returned_at: Instant::now(),
+            });
+        }
+
+        Ok(())
+    }
+
+    /// Execute a given function on the pool with [`Priority::Background`]. A returned [`Err`] guarantees that the
     /// fault is so critical that the caller should stop themselves.
     ///
-    /// Any function call has [`MAX_RETRIES`] attempts to execute its function.
+    /// Any function call has [`MAX_RETRIES`] attempts to get a connection and execute its function.
     /// Should this limit be exceeded an [`Err`] is returned.
+    ///
+    /// Commands rejected by the server are not retried and returned as [`PoolError::Recoverable`].
     pub async fn execute<F, R>(&mut self, f: F) -> Result<R, PoolError>
     where
         R: Debug,
-        F: for<'a> Fn(
-                &'a mut RconConnection,
-            )
-                -> Pin<Box<dyn Future<Output = Result<R, RconError>> + Send + 'a>>
+        F: for<'a> Fn(&'a mut C) -> Pin<Box<dyn Future<Output = Result<R, RconError>> + Send + 'a>>
             + Send,
     {
         let mut retries = 0;
         loop {
-            let mut connection = self.get_connection().await?;
-            let res = f(&mut connection).await;
-
-            if res.is_ok() {
-                self.return_connection(connection).await;
-                return Ok(res.unwrap());
-            }
-
-            // Should a connection fail for any reason it is discarded.
-            // This prevents stuck data in a TcpStream from messing up future parsers.
-            let error = res.unwrap_err();
+            let error = match self.get_connection(Priority::Background).await {
+                Ok(mut connection) => match f(&mut connection).await {
+                    Ok(res) => {
+                        self.return_connection(connection).await;
+                        return Ok(res);
+                    }
+                    // The command was not executed, a new connection logs in again.
+                    Err(e) if e.is_token_expired() => {
+                        self.discard(connection, &e).await;
+                        e
+                    }
+                    // A rejected command leaves the connection intact and retrying won't help.
+                    Err(e @ RconError::ServerRejected { .. }) => {
+                        self.return_connection(connection).await;
+                        return Err(PoolError::Recoverable(e));
+                    }
+                    // Should a connection fail for any reason it is discarded.
+                    // This prevents stuck data in a TcpStream from messing up future parsers.
+                    Err(e) => e,
+                },
+                Err(PoolError::Unrecoverable(e)) => return Err(PoolError::Unrecoverable(e)),
+                Err(PoolError::Recoverable(e)) => e,
+            };
 
             retries += 1;
             if retries >= MAX_RETRIES {
@@ -91,28 +277,351 @@ impl ConnectionPool {
     }
 
     /// Return a connection to the pool.
-    pub async fn return_connection(&mut self, connection: RconConnection) {
+    pub async fn return_connection(&mut self, connection: PooledConnection<C>) {
         let mut lock = self.connections.lock().await;
-        lock.push_back(connection);
+        lock.push_back(IdleConnection {
+            connection: connection.connection,
+            returned_at: Instant::now(),
+        });
         let size = lock.len();
-        trace!("Returned connection current size {}", size + 1);
+        trace!("Returned connection current size {}", size);
+    }
+
+    /// Return a connection to the pool after executing a command on it, unless the result shows
+    /// the connection broke or its token expired. Such connections are discarded to free their
+    /// slot instead.
+    pub async fn release<R>(
+        &mut self,
+        connection: PooledConnection<C>,
+        result: &Result<R, RconError>,
+    ) {
+        match result {
+            Err(e) if e.is_connection_broken() || e.is_token_expired() => {
+                self.discard(connection, e).await
+            }
+            _ => self.return_connection(connection).await,
+        }
+    }
+
+    /// Discard a connection which failed. Should its token have expired, the idle handles
+    /// sharing it are discarded as well and no longer shared with new slots.
+    async fn discard(&mut self, connection: PooledConnection<C>, error: &RconError) {
+        let id = connection.id();
+        debug!("Discarding connection #{id}: {error}");
+        self.counters.failed.fetch_add(1, Ordering::Relaxed);
+        if !error.is_token_expired() {
+            return;
+        }
+
+        let mut shared = self.shared.lock().await;
+        if shared.as_ref().is_some_and(|c| c.id() == id) {
+            *shared = None;
+        }
+        self.connections
+            .lock()
+            .await
+            .retain(|idle| idle.connection.id() != id);
     }
 
     /// Get a connection from the pool or try to allocate one if the pool is empty.
-    pub async fn get_connection(&mut self) -> Result<RconConnection, PoolError> {
-        let connection = self.connections.lock().await.pop_front();
-        if connection.is_some() {
-            return Ok(connection.unwrap());
+    /// Should the pool be exhausted wait until a connection is returned or time out.
+    pub async fn get_connection(
+        &mut self,
+        priority: Priority,
+    ) -> Result<PooledConnection<C>, PoolError> {
+        let pool_config = self.pool_config();
+        let (background_permit, permit) = timeout(pool_config.acquire_timeout_ms, async {
+            let background_permit = match priority {
+                Priority::Interactive => None,
+                Priority::Background => Some(self.background_permits.clone().acquire_owned().await),
+            };
+            let permit = self.permits.clone().acquire_owned().await;
+            (background_permit, permit)
+        })
+        .await
+        .map_err(|_| {
+            debug!("Timed out waiting for a {priority:?} connection from the pool");
+            PoolError::Recoverable(RconError::TimeOut)
+        })?;
+        let background_permit =
+            background_permit.map(|p| p.expect("The pools semaphore is never closed"));
+        let permit = permit.expect("The pools semaphore is never closed");
+
+        self.evict_idle(&pool_config).await;
+        let mut connection = loop {
+            let Some(idle) = self.connections.lock().await.pop_back() else {
+                break self.allocate_connection().await?;
+            };
+
+            if idle.connection.is_healthy() {
+                break idle.connection;
+            }
+
+            debug!("Discarding unhealthy connection #{}", idle.connection.id());
+            self.counters.failed.fetch_add(1, Ordering::Relaxed);
+        };
+
+        connection.set_shared_rate_limiter(match priority {
+            Priority::Interactive => self.interactive_limiter.clone(),
+            Priority::Background => self.background_limiter.clone(),
+        });
+
+        Ok(PooledConnection {
+            connection,
+            _permit: permit,
+            _background_permit: background_permit,
+        })
+    }
+
+    /// Get the current usage of the pool.
+    pub async fn stats(&self) -> PoolStats {
+        PoolStats {
+            in_use: self.capacity - self.permits.available_permits(),
+            idle: self.connections.lock().await.len(),
+            created: self.counters.created.load(Ordering::Relaxed),
+            failed: self.counters.failed.load(Ordering::Relaxed),
         }
+    }
+
+    /// Close the connections which have been idle for too long while keeping the minimum.
+    async fn evict_idle(&self, pool_config: &PoolConfig) {
+        let mut connections = self.connections.lock().await;
+        while connections.len() > pool_config.min_size {
+            let Some(oldest) = connections.front() else {
+                break;
+            };
 
-        self.allocate_connection().await
+            if oldest.returned_at.elapsed() < pool_config.idle_timeout_ms {
+                break;
+            }
+
+            let evicted = connections.pop_front().unwrap();
+            trace!("Evicted idle connection #{}", evicted.connection.id());
+        }
     }
 
-    /// Attempt to allocate a connection.
-    async fn allocate_connection(&mut self) -> Result<RconConnection, PoolError> {
-        let config = self.config.borrow().rcon.clone();
+    /// Attempt to allocate a connection, sharing an existing one if the client supports it.
+    async fn allocate_connection(&mut self) -> Result<C, PoolError> {
+        let mut shared = self.shared.lock().await;
+        if let Some(conn) = shared
+            .as_ref()
+            .filter(|c| c.is_healthy())
+            .and_then(C::share)
+        {
+            trace!("Sharing connection #{}", conn.id());
+            return Ok(conn);
+        }
+
+        let credentials = self.config.borrow().rcon.credentials.clone();
         trace!("Allocating new connection");
-        let conn = RconConnection::new(&config).await?;
-        Ok(conn)
+        match C::connect(&credentials).await {
+            Ok(mut conn) => {
+                self.counters.created.fetch_add(1, Ordering::Relaxed);
+                conn.set_rate_limiter(self.config.borrow().rate_limit.per_connection.limiter());
+                conn.set_recorder(self.recorder.clone());
+                *shared = conn.share();
+                Ok(conn)
+            }
+            Err(e) => {
+                self.counters.failed.fetch_add(1, Ordering::Relaxed);
+                Err(e.into())
+            }
+        }
+    }
+
+    fn pool_config(&self) -> PoolConfig {
+        self.config.borrow().pool.clone()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use rcon::{
+        credentials::RconCredentials,
+        messages::{RconRequest, RconResponse},
+    };
+
+    use crate::{
+        config::FileConfig,
+        testing::{config, file_config},
+    };
+
+    use super::*;
+
+    /// Ids of the connections of [`MockClient`].
+    static NEXT_ID: AtomicUsize = AtomicUsize::new(0);
+
+    /// A client which connects instantly and breaks or expires when asked to.
+    #[derive(Debug)]
+    struct MockClient(u64);
+
+    impl RconClient for MockClient {
+        async fn connect(_credentials: &RconCredentials) -> Result<Self, RconError> {
+            Ok(MockClient(NEXT_ID.fetch_add(1, Ordering::Relaxed) as u64))
+        }
+
+        fn id(&self) -> u64 {
+            self.0
+        }
+
+        async fn execute(&mut self, request: RconRequest) -> Result<RconResponse, RconError> {
+            match request.name.as_str() {
+                "Break" => Err(RconError::TimeOut),
+                "Expire" => Err(RconError::ServerRejected {
+                    status_code: 401,
+                    status_message: "Unauthorized".to_string(),
+                    command: request.name,
+                }),
+                _ => Ok(RconResponse {
+                    status_code: 200,
+                    status_message: String::new(),
+                    version: 2,
+                    name: request.name,
+                    content_body: String::new(),
+                }),
+            }
+        }
+    }
+
+    /// A [`MockClient`] sharing its connection with every slot of the pool.
+    #[derive(Debug)]
+    struct SharingClient(MockClient);
+
+    impl RconClient for SharingClient {
+        async fn connect(credentials: &RconCredentials) -> Result<Self, RconError> {
+            MockClient::connect(credentials).await.map(SharingClient)
+        }
+
+        fn id(&self) -> u64 {
+            self.0.id()
+        }
+
+        fn share(&self) -> Option<Self> {
+            Some(SharingClient(MockClient(self.0.id())))
+        }
+
+        async fn execute(&mut self, request: RconRequest) -> Result<RconResponse, RconError> {
+            self.0.execute(request).await
+        }
+    }
+
+    fn create_pool<C: RconClient>(configure: impl FnOnce(&mut FileConfig)) -> ConnectionPool<C> {
+        let mut file_config = file_config();
+        file_config.pool.min_size = 0;
+        file_config.pool.acquire_timeout_ms = Duration::from_millis(50);
+        file_config.rate_limit = Default::default();
+        file_config.operational.capture_file = None;
+        configure(&mut file_config);
+        ConnectionPool::new(config(file_config))
+    }
+
+    #[tokio::test]
+    async fn pool_never_exceeds_its_size() {
+        let mut pool = create_pool::<MockClient>(|c| {
+            c.pool.max_size = 2;
+            c.pool.reserved_interactive = 0;
+        });
+
+        let first = pool.get_connection(Priority::Interactive).await.unwrap();
+        let _second = pool.get_connection(Priority::Interactive).await.unwrap();
+        assert_eq!(pool.stats().await.in_use, 2);
+        assert!(pool.get_connection(Priority::Interactive).await.is_err());
+
+        // Dropping a connection frees its slot.
+        drop(first);
+        assert!(pool.get_connection(Priority::Interactive).await.is_ok());
+        assert_eq!(pool.stats().await.created, 3);
+    }
+
+    #[tokio::test]
+    async fn exhausted_pools_time_out() {
+        let mut pool = create_pool::<MockClient>(|c| {
+            c.pool.max_size = 1;
+            c.pool.reserved_interactive = 0;
+            c.pool.acquire_timeout_ms = Duration::from_millis(100);
+        });
+
+        let _taken = pool.get_connection(Priority::Interactive).await.unwrap();
+        let start = Instant::now();
+        let result = pool.get_connection(Priority::Interactive).await;
+
+        assert!(matches!(
+            result,
+            Err(PoolError::Recoverable(RconError::TimeOut))
+        ));
+        assert!(start.elapsed() >= Duration::from_millis(100));
+    }
+
+    #[tokio::test]
+    async fn interactive_commands_use_the_reserved_connections() {
+        let mut pool = create_pool::<MockClient>(|c| {
+            c.pool.max_size = 2;
+            c.pool.reserved_interactive = 1;
+        });
+
+        let _background = pool.get_connection(Priority::Background).await.unwrap();
+        assert!(pool.get_connection(Priority::Background).await.is_err());
+        assert!(pool.get_connection(Priority::Interactive).await.is_ok());
+    }
+
+    #[tokio::test]
+    async fn idle_connections_are_reused_until_evicted() {
+        let mut pool =
+            create_pool::<MockClient>(|c| c.pool.idle_timeout_ms = Duration::from_secs(60));
+        let conn = pool.get_connection(Priority::Background).await.unwrap();
+        pool.return_connection(conn).await;
+        let conn = pool.get_connection(Priority::Background).await.unwrap();
+        pool.return_connection(conn).await;
+        assert_eq!(pool.stats().await.created, 1);
+
+        let mut pool = create_pool::<MockClient>(|c| c.pool.idle_timeout_ms = Duration::ZERO);
+        let conn = pool.get_connection(Priority::Background).await.unwrap();
+        pool.return_connection(conn).await;
+        let conn = pool.get_connection(Priority::Background).await.unwrap();
+        pool.return_connection(conn).await;
+        assert_eq!(pool.stats().await.created, 2);
+        assert_eq!(pool.stats().await.idle, 1);
+    }
+
+    #[tokio::test]
+    async fn broken_connections_are_not_returned() {
+        let mut pool = create_pool::<MockClient>(|_| ());
+
+        let mut conn = pool.get_connection(Priority::Background).await.unwrap();
+        let result = conn.execute(RconRequest::new("Echo", "")).await;
+        pool.release(conn, &result).await;
+        assert_eq!(pool.stats().await.idle, 1);
+
+        let mut conn = pool.get_connection(Priority::Background).await.unwrap();
+        let result = conn.execute(RconRequest::new("Break", "")).await;
+        pool.release(conn, &result).await;
+        let stats = pool.stats().await;
+        assert_eq!((stats.idle, stats.in_use), (0, 0));
+    }
+
+    #[tokio::test]
+    async fn connections_with_an_expired_token_are_replaced() {
+        let mut pool = create_pool::<SharingClient>(|c| {
+            c.pool.max_size = 2;
+            c.pool.reserved_interactive = 0;
+        });
+
+        // Both slots share the same connection.
+        let first = pool.get_connection(Priority::Background).await.unwrap();
+        let mut second = pool.get_connection(Priority::Background).await.unwrap();
+        let expired = first.id();
+        assert_eq!(second.id(), expired);
+        pool.return_connection(first).await;
+
+        let result = second.execute(RconRequest::new("Expire", "")).await;
+        pool.release(second, &result).await;
+        let stats = pool.stats().await;
+        assert_eq!((stats.idle, stats.in_use), (0, 0));
+
+        // Neither the idle handle nor the shared one is used again.
+        let conn = pool.get_connection(Priority::Background).await.unwrap();
+        assert_ne!(conn.id(), expired);
     }
 }
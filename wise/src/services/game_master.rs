@@ -1,11 +1,28 @@
-use std::{collections::HashMap, sync::Arc};
+use std::{
+    collections::{HashMap, HashSet},
+    sync::Arc,
+};
 
-use rcon::parsing::{playerinfo::PlayerData, showlog::LogLine};
+use chrono::Utc;
+use rcon::{
+    client::RconClient,
+    parsing::{
+        gamestate::GameState,
+        player::Player,
+        playerinfo::PlayerData,
+        showlog::{LogKind, LogLine},
+    },
+};
+use serde::{Deserialize, Serialize};
 use tokio::sync::Mutex;
-use tracing::trace;
-use wise_api::events::{PlayerChanges, RconEvent};
+use tracing::error;
+use wise_api::events::{
+    GameStateChanges, KillDetails, KillParticipant, PlayerChanges, RconEvent, Squad,
+};
 
-use super::DiContainer;
+use super::{
+    connection_pool::Priority, inference::Inference, squads::Squads, stats::MatchStats, DiContainer,
+};
 
 /// Central entity which knows about the current state of the game.
 /// Acts like a state machine emitting events when it changes state.
@@ -15,57 +32,340 @@ pub struct GameMaster {
     // di: Arc<DiContainer>,
     /// The individual states for all players.
     players: Arc<Mutex<HashMap<String, PlayerData>>>,
+
+    /// The state of the match, [`None`] until first received.
+    game_state: Arc<Mutex<Option<GameState>>>,
+
+    /// The statistics of the current match.
+    stats: Arc<Mutex<MatchStats>>,
+
+    /// What is inferred from the movement of players.
+    inference: Arc<Mutex<Inference>>,
+
+    /// The squads of both teams.
+    squads: Arc<Mutex<Squads>>,
+
+    /// The amount of players in the latest poll, [`None`] until first polled.
+    population: Arc<Mutex<Option<u64>>>,
+
+    /// The logs of the current match from before polling started, sent to clients
+    /// connecting later. Cleared once the next match starts.
+    history: Arc<Mutex<Vec<LogLine>>>,
+}
+
+/// The full state known by the game master at one point in time.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct GameSnapshot {
+    /// All players currently on the server.
+    pub players: Vec<PlayerData>,
+
+    /// The state of the match, if known.
+    #[serde(default)]
+    pub game_state: Option<GameState>,
+
+    /// The squads of both teams.
+    #[serde(default)]
+    pub squads: Vec<Squad>,
 }
 
 /// Incoming new state to the game master.
 #[derive(Debug)]
 pub enum IncomingState {
-    /// New players have been received.
-    Players(Vec<PlayerData>),
+    /// New players have been received, polled at the unix timestamp in milliseconds.
+    Players {
+        players: Vec<PlayerData>,
+        polled_at: u64,
+    },
 
     /// New game game state.
-    GameState(()),
+    GameState(GameState),
 
     /// New logs.
     Logs(Vec<LogLine>),
+
+    /// Logs which took place before polling started. These rebuild the statistics of the
+    /// match and who is connected, the movement of players is only known from polling.
+    HistoricalLogs(Vec<LogLine>),
+}
+
+impl Default for GameMaster {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl GameMaster {
     pub fn new() -> Self {
         Self {
             players: Default::default(),
+            game_state: Default::default(),
+            stats: Default::default(),
+            inference: Default::default(),
+            squads: Default::default(),
+            population: Default::default(),
+            history: Default::default(),
         }
     }
 
     /// Update the internal state of the game master. If during this process changes
     /// are detected emit these using the channels in the [`DiContainer`].
-    pub async fn update_state(&mut self, incoming: IncomingState, di: &DiContainer) {
+    pub async fn update_state<C: RconClient>(
+        &mut self,
+        incoming: IncomingState,
+        di: &DiContainer<C>,
+    ) {
         match incoming {
-            IncomingState::Players(player_datas) => {
-                for player in player_datas {
-                    self.update_player(player, di).await;
-                }
+            IncomingState::Players { players, polled_at } => {
+                self.update_players(players, polled_at, di).await;
+            }
+            IncomingState::GameState(game_state) => {
+                self.update_gamestate(game_state, di).await;
             }
-            IncomingState::GameState(_) => todo!(),
             IncomingState::Logs(logs) => {
                 for log in logs {
-                    self.update_logs(log, di).await;
+                    self.update_logs(log, false, di).await;
                 }
             }
+            IncomingState::HistoricalLogs(logs) => {
+                for log in &logs {
+                    self.update_logs(log.clone(), true, di).await;
+                }
+                self.rebuild_players(&logs, di).await;
+
+                // Set before sending, clients given the history skip the logs still being sent.
+                *self.history.lock().await = logs.clone();
+                let events = logs.into_iter().map(RconEvent::HistoricalLog);
+                di.game_events.send_rcon_paced(events).await;
+            }
         }
     }
 
+    /// Update all players, polled at once at the unix timestamp in milliseconds.
+    async fn update_players<C: RconClient>(
+        &mut self,
+        players: Vec<PlayerData>,
+        polled_at: u64,
+        di: &DiContainer<C>,
+    ) {
+        // Players are always polled all at once, those missing have left.
+        *self.population.lock().await = Some(players.len() as u64);
+        self.update_squads(&players, di).await;
+        let polled: HashSet<_> = players.iter().map(|p| p.id.clone()).collect();
+        for player in players {
+            self.update_player(player, polled_at, di).await;
+        }
+
+        let mut inference = self.inference.lock().await;
+        self.players.lock().await.retain(|id, _| {
+            let connected = polled.contains(id);
+            if !connected {
+                inference.forget(id);
+            }
+            connected
+        });
+    }
+
+    /// Remove the players which left according to the historical logs and poll those still
+    /// connected should they not be known yet.
+    async fn rebuild_players<C: RconClient>(&mut self, logs: &[LogLine], di: &DiContainer<C>) {
+        let mut connected = HashMap::new();
+        for log in logs {
+            if let LogKind::Connect {
+                player,
+                has_connected,
+            } = &log.kind
+            {
+                connected.insert(player.id.to_string(), *has_connected);
+            }
+        }
+
+        let unknown = {
+            let mut players = self.players.lock().await;
+            players.retain(|id, _| connected.get(id) != Some(&false));
+            connected
+                .iter()
+                .any(|(id, connected)| *connected && !players.contains_key(id))
+        };
+        if !unknown {
+            return;
+        }
+
+        let mut pool = di.connection_pool.clone();
+        let Ok(mut conn) = pool.get_connection(Priority::Background).await else {
+            error!("Failed to get a connection to poll the players connected before");
+            return;
+        };
+        let polled_at = Utc::now().timestamp_millis() as u64;
+        let players = conn.fetch_players().await;
+        pool.release(conn, &players).await;
+
+        match players {
+            Ok(players) => self.update_players(players, polled_at, di).await,
+            Err(e) => error!("Failed to poll the players connected before. << {e}"),
+        }
+    }
+
+    /// The logs of the current match from before polling started.
+    pub async fn history(&self) -> Vec<LogLine> {
+        self.history.lock().await.clone()
+    }
+
     /// Get the current state.
-    pub fn current_state(&self) {}
+    pub async fn current_state(&self) -> GameSnapshot {
+        let players = self.players.lock().await;
+        GameSnapshot {
+            players: players.values().cloned().collect(),
+            game_state: self.game_state.lock().await.clone(),
+            squads: self.squads.lock().await.current(),
+        }
+    }
+
+    /// The amount of players on the server, [`None`] until first polled.
+    pub async fn population(&self) -> Option<u64> {
+        *self.population.lock().await
+    }
+
+    /// Update the state from a new log. Historical logs took place before polling started.
+    pub async fn update_logs<C: RconClient>(
+        &mut self,
+        new_log: LogLine,
+        historical: bool,
+        di: &DiContainer<C>,
+    ) {
+        // What players did before polling started says nothing about their current movement.
+        if !historical {
+            let mut inference = self.inference.lock().await;
+            match &new_log.kind {
+                LogKind::Kill { victim, .. } => {
+                    inference.record_death(&victim.id.to_string(), new_log.timestamp as f64)
+                }
+                LogKind::Connect {
+                    player,
+                    has_connected: false,
+                } => {
+                    let id = player.id.to_string();
+                    inference.forget(&id);
+                    self.players.lock().await.remove(&id);
+                }
+                _ => {}
+            }
+        }
+
+        let summary = self.stats.lock().await.record_log(&new_log);
+        if historical {
+            // Matches which ended before polling started are incomplete, not worth a summary.
+            // Historical logs are emitted all at once, see `IncomingState::HistoricalLogs`.
+            return;
+        }
+
+        if matches!(new_log.kind, LogKind::MatchStart { .. }) {
+            self.history.lock().await.clear();
+        }
 
-    /// Update the state from a new log.
-    pub async fn update_logs(&mut self, new_log: LogLine, di: &DiContainer) {
-        // TODO: eventually extend our knowledge of the game with these logs
+        let details = self.kill_details(&new_log).await;
         di.game_events.send_rcon(RconEvent::Log(new_log));
+        if let Some(details) = details {
+            di.game_events.send_rcon(RconEvent::KillDetails(details));
+        }
+        if let Some(summary) = summary {
+            di.game_events.send_rcon(RconEvent::MatchSummary(summary));
+        }
+    }
+
+    /// Enrich a kill with the latest known state of both players, [`None`] for other logs.
+    async fn kill_details(&self, log: &LogLine) -> Option<KillDetails> {
+        let LogKind::Kill {
+            killer,
+            killer_faction,
+            victim,
+            victim_faction,
+            is_teamkill,
+            weapon,
+        } = &log.kind
+        else {
+            return None;
+        };
+
+        let players = self.players.lock().await;
+        let participant = |player: &Player, faction: &String| {
+            let id = player.id.to_string();
+            let data = players.get(&id);
+            KillParticipant {
+                name: player.name.clone(),
+                faction: faction.clone(),
+                position: data
+                    .map(|d| d.world_position.clone())
+                    .filter(|p| !p.is_unknown()),
+                role: data.map(|d| d.role),
+                loadout: data.map(|d| d.loadout.clone()),
+                id,
+            }
+        };
+        let killer = participant(killer, killer_faction);
+        let victim = participant(victim, victim_faction);
+
+        // Positions are in centimeters.
+        let distance = match (&killer.position, &victim.position) {
+            (Some(k), Some(v)) => Some(k.distance(v) / 100.0),
+            _ => None,
+        };
+
+        Some(KillDetails {
+            timestamp: log.timestamp,
+            killer,
+            victim,
+            weapon: weapon.clone(),
+            is_teamkill: *is_teamkill,
+            distance,
+        })
     }
 
-    /// Update the state of a single player.
-    pub async fn update_player(&mut self, new_data: PlayerData, di: &DiContainer) {
+    /// Update the squads from all players on the server.
+    pub async fn update_squads<C: RconClient>(
+        &mut self,
+        players: &[PlayerData],
+        di: &DiContainer<C>,
+    ) {
+        for event in self.squads.lock().await.update(players) {
+            di.game_events.send_rcon(RconEvent::Squad(event));
+        }
+    }
+
+    /// Update the state of the match.
+    pub async fn update_gamestate<C: RconClient>(
+        &mut self,
+        new_state: GameState,
+        di: &DiContainer<C>,
+    ) {
+        let mut game_state = self.game_state.lock().await;
+        let changes = match &*game_state {
+            Some(old_state) => detect_gamestate_changes(old_state, &new_state),
+            None => vec![],
+        };
+        if game_state.is_some() && changes.is_empty() {
+            return;
+        }
+
+        di.game_events.send_rcon(RconEvent::Game {
+            changes,
+            new_state: new_state.clone(),
+        });
+        *game_state = Some(new_state);
+    }
+
+    /// Update the state of a single player, polled at the unix timestamp in milliseconds.
+    pub async fn update_player<C: RconClient>(
+        &mut self,
+        new_data: PlayerData,
+        polled_at: u64,
+        di: &DiContainer<C>,
+    ) {
+        self.stats.lock().await.record_player(&new_data);
+        let inferred = self
+            .inference
+            .lock()
+            .await
+            .observe(&new_data, polled_at as f64 / 1000.0);
         let mut players = self.players.lock().await;
 
         let Some(old_data) = players.get_mut(&new_data.id) else {
@@ -73,7 +373,8 @@ impl GameMaster {
             return;
         };
 
-        let changes = detect_player_changes(&old_data, &new_data);
+        let mut changes = detect_player_changes(old_data, &new_data);
+        changes.extend(inferred);
         if changes.is_empty() {
             return;
         }
@@ -90,17 +391,26 @@ impl GameMaster {
 }
 
 macro_rules! quick_check {
-    ($changes:expr, $field_type:ident, $field_name:ident, $old:ident, $new:ident) => {{
+    ($changes:expr, $kind:ident::$field_type:ident, $field_name:ident, $old:ident, $new:ident) => {{
         detect(
             &mut $changes,
             &$old.$field_name,
             &$new.$field_name,
-            PlayerChanges::$field_type {
+            $kind::$field_type {
                 old: $old.$field_name.clone(),
                 new: $new.$field_name.clone(),
             },
         );
     }};
+    ($changes:expr, $field_type:ident, $field_name:ident, $old:ident, $new:ident) => {{
+        quick_check!(
+            $changes,
+            PlayerChanges::$field_type,
+            $field_name,
+            $old,
+            $new
+        )
+    }};
 }
 
 fn detect_player_changes(old: &PlayerData, new: &PlayerData) -> Vec<PlayerChanges> {
@@ -121,6 +431,38 @@ fn detect_player_changes(old: &PlayerData, new: &PlayerData) -> Vec<PlayerChange
     changes
 }
 
+fn detect_gamestate_changes(old: &GameState, new: &GameState) -> Vec<GameStateChanges> {
+    let mut changes = vec![];
+
+    // The remaining time changes constantly and is not worth an event on its own.
+    quick_check!(
+        changes,
+        GameStateChanges::AlliedPlayers,
+        allied_players,
+        old,
+        new
+    );
+    quick_check!(
+        changes,
+        GameStateChanges::AxisPlayers,
+        axis_players,
+        old,
+        new
+    );
+    quick_check!(
+        changes,
+        GameStateChanges::AlliedScore,
+        allied_score,
+        old,
+        new
+    );
+    quick_check!(changes, GameStateChanges::AxisScore, axis_score, old, new);
+    quick_check!(changes, GameStateChanges::Map, map, old, new);
+    quick_check!(changes, GameStateChanges::NextMap, next_map, old, new);
+
+    changes
+}
+
 /// Detect a change bewteen old and new and
 pub fn detect<T, C>(v: &mut Vec<C>, old: &T, new: &T, c: C)
 where
@@ -132,3 +474,110 @@ where
 
     v.push(c);
 }
+
+#[cfg(test)]
+mod tests {
+    use rcon::offline::OfflineConnection;
+    use wise_api::messages::ServerWsMessage;
+
+    use crate::testing::{config, connect, file_config, log, player_data};
+
+    use super::*;
+
+    #[tokio::test]
+    async fn backfills_history_without_skipping_and_rebuilds_players() {
+        let di = DiContainer::<OfflineConnection>::create(config(file_config()));
+        let mut game_master = di.game_master.clone();
+        let players = vec![player_data(1), player_data(2)];
+        game_master
+            .update_state(
+                IncomingState::Players {
+                    players,
+                    polled_at: 0,
+                },
+                &di,
+            )
+            .await;
+
+        let mut logs: Vec<_> = (0..2000)
+            .map(|t| log(t, LogKind::Unknown { raw: t.to_string() }))
+            .collect();
+        logs.push(connect(2000, 2, false));
+
+        let mut rx = di.game_events.receiver();
+        let receiving = tokio::spawn(async move {
+            let mut received = 0;
+            loop {
+                let ServerWsMessage::Rcon(RconEvent::HistoricalLog(log)) = rx.receive().await
+                else {
+                    continue;
+                };
+                received += 1;
+                if log.timestamp == 2000 {
+                    return received;
+                }
+            }
+        });
+        game_master
+            .update_state(IncomingState::HistoricalLogs(logs), &di)
+            .await;
+
+        assert_eq!(receiving.await.unwrap(), 2001);
+        assert_eq!(game_master.history().await.len(), 2001);
+        let ids: Vec<_> = game_master
+            .current_state()
+            .await
+            .players
+            .into_iter()
+            .map(|p| p.id)
+            .collect();
+        assert_eq!(ids, vec!["1".to_string()]);
+
+        // The history only covers the current match.
+        let start = log(
+            3000,
+            LogKind::MatchStart {
+                map: "CARENTAN".to_string(),
+            },
+        );
+        game_master
+            .update_state(IncomingState::Logs(vec![start]), &di)
+            .await;
+        assert!(game_master.history().await.is_empty());
+    }
+
+    async fn player_ids(game_master: &GameMaster) -> Vec<String> {
+        let mut ids: Vec<_> = game_master
+            .current_state()
+            .await
+            .players
+            .into_iter()
+            .map(|p| p.id)
+            .collect();
+        ids.sort();
+        ids
+    }
+
+    #[tokio::test]
+    async fn only_keeps_connected_players() {
+        let di = DiContainer::<OfflineConnection>::create(config(file_config()));
+        let mut game_master = di.game_master.clone();
+        for players in [vec![player_data(1), player_data(2)], vec![player_data(2)]] {
+            game_master
+                .update_state(
+                    IncomingState::Players {
+                        players,
+                        polled_at: 0,
+                    },
+                    &di,
+                )
+                .await;
+        }
+        assert_eq!(player_ids(&game_master).await, vec!["2".to_string()]);
+
+        game_master
+            .update_state(IncomingState::Logs(vec![connect(1, 2, false)]), &di)
+            .await;
+        assert!(player_ids(&game_master).await.is_empty());
+    }
+}
@@ -0,0 +1,278 @@
+//! Inferring what RCON does not tell from the positions of players over time.
+//!
+//! Positions are only known once per poll, as such everything inferred is an estimate
+//! accompanied by a confidence between 0 and 1. Times are unix timestamps in seconds.
+use std::collections::{HashMap, VecDeque};
+
+use rcon::parsing::playerinfo::{PlayerData, WorldPosition};
+use wise_api::events::PlayerChanges;
+
+/// Meters per second a sprinting player does not exceed, anything slower is on foot.
+const RUNNING_SPEED: f64 = 6.0;
+
+/// Meters per second from which a player is certainly in a vehicle.
+const VEHICLE_SPEED: f64 = 12.0;
+
+/// Meters per second no vehicle reaches, anything faster is a respawn or redeploy.
+const TELEPORT_SPEED: f64 = 40.0;
+
+/// Meters a player has to reappear away from where they died to have respawned.
+const RESPAWN_DISTANCE: f64 = 30.0;
+
+/// Meters a player has to move away from where they died to be alive again without
+/// respawning, such as after being revived.
+const REVIVE_DISTANCE: f64 = 2.0;
+
+/// Seconds of positions the speed is averaged over.
+const SPEED_WINDOW: f64 = 15.0;
+
+/// The movement of every player.
+#[derive(Debug, Default)]
+pub struct Inference {
+    players: HashMap<String, Movement>,
+}
+
+#[derive(Debug, Default)]
+struct Movement {
+    /// Recent known positions and when they were polled, oldest first.
+    history: VecDeque<(f64, WorldPosition)>,
+    in_vehicle: bool,
+    deaths: u64,
+
+    /// When the player was last killed according to the logs.
+    killed_at: Option<f64>,
+
+    /// When and where the player died, until they respawned.
+    pending_death: Option<(f64, Option<WorldPosition>)>,
+}
+
+impl Inference {
+    /// Record the player being killed, refining when deaths seen in polls took place.
+    pub fn record_death(&mut self, id: &str, at: f64) {
+        self.players.entry(id.to_string()).or_default().killed_at = Some(at);
+    }
+
+    /// Forget the movement of a player, for example once they disconnected.
+    pub fn forget(&mut self, id: &str) {
+        self.players.remove(id);
+    }
+
+    /// Infer changes from a new snapshot of the player, polled at the time.
+    pub fn observe(&mut self, player: &PlayerData, at: f64) -> Vec<PlayerChanges> {
+        let movement = self.players.entry(player.id.clone()).or_default();
+        let previous = movement.history.back().cloned();
+        let mut changes = vec![];
+
+        if player.deaths > movement.deaths && previous.is_some() {
+            // The player died since the previous poll, at the latest where they were seen last.
+            let (seen_at, seen_position) = previous.clone().unwrap();
+            let died_at = movement
+                .killed_at
+                .filter(|killed_at| *killed_at >= seen_at.floor())
+                .unwrap_or(seen_at);
+            let position = movement
+                .pending_death
+                .take()
+                .and_then(|(_, p)| p)
+                .or(Some(seen_position));
+            movement.pending_death = Some((died_at, position));
+        }
+        movement.deaths = player.deaths;
+
+        let position = &player.world_position;
+        if position.is_unknown() {
+            // Dead players or those yet to spawn have no position, wherever they reappear
+            // they respawned.
+            if let Some((_, died_position)) = &mut movement.pending_death {
+                *died_position = None;
+            }
+            movement.history.clear();
+            return changes;
+        }
+
+        if let Some((died_at, died_position)) = movement.pending_death.clone() {
+            let distance = died_position.map(|p| p.distance(position) / 100.0);
+            let confidence = match distance {
+                Some(distance) if distance >= RESPAWN_DISTANCE => {
+                    Some(0.75 + 0.25 * (distance / 500.0).min(1.0))
+                }
+                // Moving a little, rather than lying where they died, means they were revived.
+                Some(distance) if distance > REVIVE_DISTANCE => {
+                    movement.pending_death = None;
+                    None
+                }
+                Some(_) => None,
+                None => Some(0.9),
+            };
+
+            if let Some(confidence) = confidence {
+                changes.push(PlayerChanges::Respawned {
+                    position: position.clone(),
+                    respawn_secs: (at - died_at).max(0.0),
+                    confidence,
+                });
+                movement.pending_death = None;
+                movement.history.clear();
+
+                if movement.in_vehicle {
+                    changes.push(PlayerChanges::InVehicle {
+                        old: true,
+                        new: false,
+                        speed: 0.0,
+                        confidence,
+                    });
+                    movement.in_vehicle = false;
+                }
+            }
+        }
+
+        movement.history.push_back((at, position.clone()));
+        while movement
+            .history
+            .front()
+            .is_some_and(|(t, _)| at - t > SPEED_WINDOW)
+        {
+            movement.history.pop_front();
+        }
+
+        if let Some(speed) = movement.speed() {
+            let confidence =
+                ((speed - RUNNING_SPEED) / (VEHICLE_SPEED - RUNNING_SPEED)).clamp(0.0, 1.0);
+            let in_vehicle = confidence >= 0.5;
+            if in_vehicle != movement.in_vehicle {
+                changes.push(PlayerChanges::InVehicle {
+                    old: movement.in_vehicle,
+                    new: in_vehicle,
+                    speed,
+                    confidence: if in_vehicle {
+                        confidence
+                    } else {
+                        1.0 - confidence
+                    },
+                });
+                movement.in_vehicle = in_vehicle;
+            }
+        }
+
+        changes
+    }
+}
+
+impl Movement {
+    /// The average speed in meters per second over the recent positions, [`None`] if
+    /// unknown or the player teleported, such as when redeploying.
+    fn speed(&mut self) -> Option<f64> {
+        let (last_at, last) = self.history.back()?;
+        let (second_at, second) = self.history.iter().nth_back(1)?;
+        if *last_at <= *second_at {
+            return None;
+        }
+
+        let latest_speed = second.distance(last) / 100.0 / (last_at - second_at);
+        if latest_speed > TELEPORT_SPEED {
+            let newest = self.history.pop_back();
+            self.history.clear();
+            self.history.extend(newest);
+            return None;
+        }
+
+        let (first_at, _) = self.history.front()?;
+        let distance: f64 = self
+            .history
+            .iter()
+            .zip(self.history.iter().skip(1))
+            .map(|((_, a), (_, b))| a.distance(b) / 100.0)
+            .sum();
+        Some(distance / (last_at - first_at))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::testing::player_data;
+
+    use super::*;
+
+    /// A player at `x` meters along the x axis, at an unknown position if [`None`].
+    fn player(x: Option<f64>, deaths: u64) -> PlayerData {
+        let mut player = player_data(1);
+        player.deaths = deaths;
+        if let Some(x) = x {
+            player.world_position = WorldPosition {
+                x: x * 100.0,
+                y: 100.0,
+                z: 0.0,
+            };
+        }
+        player
+    }
+
+    fn in_vehicle(changes: &[PlayerChanges]) -> Option<bool> {
+        changes.iter().find_map(|change| match change {
+            PlayerChanges::InVehicle { new, .. } => Some(*new),
+            _ => None,
+        })
+    }
+
+    fn respawn_secs(changes: &[PlayerChanges]) -> Option<f64> {
+        changes.iter().find_map(|change| match change {
+            PlayerChanges::Respawned { respawn_secs, .. } => Some(*respawn_secs),
+            _ => None,
+        })
+    }
+
+    #[test]
+    fn detects_entering_and_leaving_vehicles() {
+        let mut inference = Inference::default();
+        assert!(inference.observe(&player(Some(0.0), 0), 0.0).is_empty());
+        assert_eq!(
+            in_vehicle(&inference.observe(&player(Some(20.0), 0), 1.0)),
+            Some(true)
+        );
+
+        // Standing still lowers the average speed until the player is on foot again.
+        let left = (2..10)
+            .map(|t| inference.observe(&player(Some(20.0), 0), t as f64))
+            .find_map(|changes| in_vehicle(&changes));
+        assert_eq!(left, Some(false));
+    }
+
+    #[test]
+    fn running_is_not_driving() {
+        let mut inference = Inference::default();
+        for t in 0..10 {
+            let changes = inference.observe(&player(Some(t as f64 * 5.0), 0), t as f64);
+            assert!(in_vehicle(&changes).is_none());
+        }
+    }
+
+    #[test]
+    fn teleports_are_not_driving() {
+        let mut inference = Inference::default();
+        inference.observe(&player(Some(0.0), 0), 0.0);
+        let changes = inference.observe(&player(Some(1000.0), 0), 1.0);
+        assert!(in_vehicle(&changes).is_none());
+    }
+
+    #[test]
+    fn detects_respawns_away_from_the_death() {
+        let mut inference = Inference::default();
+        inference.observe(&player(Some(0.0), 0), 0.0);
+        inference.record_death("1", 3.0);
+        assert!(inference.observe(&player(None, 1), 5.0).is_empty());
+
+        let changes = inference.observe(&player(Some(500.0), 1), 20.0);
+        assert_eq!(respawn_secs(&changes), Some(17.0));
+    }
+
+    #[test]
+    fn revives_are_not_respawns() {
+        let mut inference = Inference::default();
+        inference.observe(&player(Some(0.0), 0), 0.0);
+        inference.observe(&player(Some(0.0), 1), 1.0);
+        inference.observe(&player(Some(5.0), 1), 2.0);
+
+        let changes = inference.observe(&player(Some(100.0), 1), 30.0);
+        assert!(respawn_secs(&changes).is_none());
+    }
+}
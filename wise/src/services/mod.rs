@@ -1,40 +1,62 @@
 pub mod connection_pool;
 pub mod game_master;
+pub mod inference;
+pub mod moderation;
 pub mod polling_manager;
+pub mod replay;
+pub mod simulator;
+pub mod squads;
+pub mod stats;
 
 use connection_pool::ConnectionPool;
 use game_master::GameMaster;
+use rcon::{client::RconClient, connection::RconConnection};
+use replay::ReplayHandle;
 
+#[cfg(feature = "sqlite")]
+use crate::exporting::sqlite::SqliteStore;
 use crate::{config::AppConfig, exporting::queue::EventSender};
 
-pub struct DiContainer {
-    pub connection_pool: ConnectionPool,
+/// The services of wise, generic over the client used to talk to the HLL server.
+pub struct DiContainer<C = RconConnection> {
+    pub connection_pool: ConnectionPool<C>,
     pub game_master: GameMaster,
     pub config: AppConfig,
 
     pub game_events: EventSender,
+
+    /// Controls the replayed game, should wise replay one instead of polling a server.
+    pub replay: Option<ReplayHandle>,
+
+    /// The persisted events, should persisting them be enabled.
+    #[cfg(feature = "sqlite")]
+    pub store: Option<SqliteStore>,
 }
 
-impl DiContainer {
+impl<C: RconClient> DiContainer<C> {
     pub fn create(config: AppConfig) -> Self {
-        let this = Self {
+        Self {
             connection_pool: ConnectionPool::new(config.clone()),
             game_master: GameMaster::new(),
             game_events: EventSender::new(),
+            replay: None,
+            #[cfg(feature = "sqlite")]
+            store: None,
             config,
-        };
-
-        this
+        }
     }
 }
 
-impl Clone for DiContainer {
+impl<C> Clone for DiContainer<C> {
     fn clone(&self) -> Self {
         Self {
             connection_pool: self.connection_pool.clone(),
             game_master: self.game_master.clone(),
             config: self.config.clone(),
             game_events: self.game_events.clone(),
+            replay: self.replay.clone(),
+            #[cfg(feature = "sqlite")]
+            store: self.store.clone(),
         }
     }
 }
@@ -0,0 +1,486 @@
+//! Moderating players automatically by the rules in the config.
+//!
+//! Every rule has a trigger, such as teamkilling three times within ten minutes, and a list
+//! of escalating actions. The first time a rule triggers for a player the first action is
+//! taken, the next time the second and so on, repeating the last. Windows, cooldowns and
+//! escalation are measured by the timestamps of the logs.
+//!
+//! What counts towards a trigger is forgotten once its window passed or the player left.
+//! Escalation is only forgotten once it would start over, as such leaving the server does
+//! not save a player from the next, harsher action.
+use std::collections::{HashMap, VecDeque};
+
+use rcon::{
+    client::RconClient,
+    parsing::{player::Player, showlog::LogKind},
+};
+use tracing::{debug, error, info};
+use wise_api::{events::RconEvent, messages::ServerWsMessage};
+
+use crate::config::{ActionConfig, ModerationConfig, RuleConfig, TriggerConfig};
+
+use super::{
+    connection_pool::{PoolError, Priority},
+    DiContainer,
+};
+
+/// An action a rule decided to take on a player.
+#[derive(Debug)]
+struct Verdict {
+    rule: String,
+    player: Player,
+    action: ActionConfig,
+}
+
+/// How often a rule acted on a player and when it last did.
+#[derive(Debug, Default)]
+struct Escalation {
+    level: usize,
+    last_acted: Option<u64>,
+}
+
+/// What the rules have seen and done so far.
+#[derive(Debug, Default)]
+struct Moderator {
+    /// The amount of players on the server, [`None`] until first polled.
+    population: Option<u64>,
+
+    /// Timestamps of the events counting towards a trigger, by rule and player id.
+    hits: HashMap<(String, String), VecDeque<u64>>,
+
+    /// By rule and player id.
+    escalations: HashMap<(String, String), Escalation>,
+}
+
+/// Apply the moderation rules to every event until wise stops.
+pub async fn run_moderation<C: RconClient>(di: DiContainer<C>) {
+    let mut event_rx = di.game_events.receiver();
+    let mut moderator = Moderator::default();
+
+    loop {
+        let ServerWsMessage::Rcon(event) = event_rx.receive().await else {
+            continue;
+        };
+
+        moderator.population = di.game_master.population().await;
+        let (verdicts, dry_run) = {
+            let config = di.config.borrow();
+            let config = &config.moderation;
+            if !config.enabled {
+                continue;
+            }
+            (moderator.judge(config, &event), config.dry_run)
+        };
+
+        for verdict in verdicts {
+            if dry_run {
+                info!(
+                    "Rule {} would {:?} {} ({}), dry run",
+                    verdict.rule, verdict.action, verdict.player.name, verdict.player.id
+                );
+                continue;
+            }
+
+            _ = tokio::spawn(execute_verdict(di.clone(), verdict));
+        }
+    }
+}
+
+impl Moderator {
+    /// Apply all rules to the event and get the actions to take.
+    fn judge(&mut self, config: &ModerationConfig, event: &RconEvent) -> Vec<Verdict> {
+        // Historical logs have already been moderated, if at all.
+        let RconEvent::Log(log) = event else {
+            return vec![];
+        };
+
+        self.prune(config, log.timestamp);
+        if let LogKind::Connect {
+            player,
+            has_connected: false,
+        } = &log.kind
+        {
+            let id = player.id.to_string();
+            self.hits.retain(|(_, player_id), _| *player_id != id);
+            return vec![];
+        }
+
+        let mut verdicts = vec![];
+        for rule in &config.rules {
+            if !self.conditions_met(rule) {
+                continue;
+            }
+
+            let triggered_by = match (&rule.trigger, &log.kind) {
+                (
+                    TriggerConfig::Kill {
+                        weapons,
+                        teamkill,
+                        count,
+                        within_ms,
+                    },
+                    LogKind::Kill {
+                        killer,
+                        is_teamkill,
+                        weapon,
+                        ..
+                    },
+                ) => {
+                    let weapon_matches = weapons.is_empty()
+                        || weapons.iter().any(|w| w.eq_ignore_ascii_case(weapon));
+                    let kind_matches = teamkill.is_none_or(|teamkill| teamkill == *is_teamkill);
+                    if !weapon_matches || !kind_matches {
+                        continue;
+                    }
+
+                    let key = (rule.name.clone(), killer.id.to_string());
+                    let hits = self.hits.entry(key).or_default();
+                    hits.push_back(log.timestamp);
+                    let within = within_ms.as_secs();
+                    while hits
+                        .front()
+                        .is_some_and(|t| within > 0 && log.timestamp.saturating_sub(*t) > within)
+                    {
+                        hits.pop_front();
+                    }
+
+                    if hits.len() < *count {
+                        continue;
+                    }
+                    hits.clear();
+                    killer
+                }
+                (
+                    TriggerConfig::Chat { words },
+                    LogKind::Chat {
+                        sender, content, ..
+                    },
+                ) => {
+                    if !contains_any_word(content, words) {
+                        continue;
+                    }
+                    sender
+                }
+                _ => continue,
+            };
+
+            if let Some(verdict) = self.act(rule, triggered_by, log.timestamp) {
+                verdicts.push(verdict);
+            }
+        }
+
+        verdicts
+    }
+
+    /// Forget hits outside their window and escalations which would start over, as well as
+    /// everything of rules no longer configured.
+    fn prune(&mut self, config: &ModerationConfig, now: u64) {
+        let rule = |name: &str| config.rules.iter().find(|rule| rule.name == name);
+
+        self.hits.retain(|(name, _), hits| {
+            let Some(TriggerConfig::Kill { within_ms, .. }) = rule(name).map(|r| &r.trigger) else {
+                return false;
+            };
+            let within = within_ms.as_secs();
+            within == 0
+                || hits
+                    .back()
+                    .is_some_and(|t| now.saturating_sub(*t) <= within)
+        });
+
+        self.escalations.retain(|(name, _), escalation| {
+            let Some(rule) = rule(name) else {
+                return false;
+            };
+            let reset = rule.escalation_reset_ms.as_secs();
+            reset == 0
+                || escalation
+                    .last_acted
+                    .is_some_and(|t| now.saturating_sub(t) < reset)
+        });
+    }
+
+    fn conditions_met(&self, rule: &RuleConfig) -> bool {
+        let conditions = &rule.conditions;
+        if conditions.min_players.is_none() && conditions.max_players.is_none() {
+            return true;
+        }
+
+        let Some(population) = self.population else {
+            return false;
+        };
+        conditions.min_players.is_none_or(|min| population >= min)
+            && conditions.max_players.is_none_or(|max| population <= max)
+    }
+
+    /// Decide on the action to take for the triggered rule, [`None`] during the cooldown.
+    fn act(&mut self, rule: &RuleConfig, player: &Player, now: u64) -> Option<Verdict> {
+        let key = (rule.name.clone(), player.id.to_string());
+        let escalation = self.escalations.entry(key).or_default();
+
+        if let Some(last_acted) = escalation.last_acted {
+            let since = now.saturating_sub(last_acted);
+            if since < rule.cooldown_ms.as_secs() {
+                debug!(
+                    "Rule {} triggered for {} during its cooldown",
+                    rule.name, player.name
+                );
+                return None;
+            }
+
+            let reset = rule.escalation_reset_ms.as_secs();
+            if reset > 0 && since >= reset {
+                escalation.level = 0;
+            }
+        }
+
+        let index = escalation.level.min(rule.actions.len().checked_sub(1)?);
+        escalation.level += 1;
+        escalation.last_acted = Some(now);
+
+        Some(Verdict {
+            rule: rule.name.clone(),
+            player: player.clone(),
+            action: rule.actions[index].clone(),
+        })
+    }
+}
+
+/// Whether the message contains any of the words, phrases of multiple words are matched
+/// anywhere in the message.
+fn contains_any_word(message: &str, words: &[String]) -> bool {
+    let message = message.to_lowercase();
+    let tokens: Vec<_> = message
+        .split(|c: char| !c.is_alphanumeric())
+        .filter(|t| !t.is_empty())
+        .collect();
+
+    words.iter().any(|word| {
+        let word = word.to_lowercase();
+        match word.contains(char::is_whitespace) {
+            true => message.contains(&word),
+            false => tokens.contains(&word.as_str()),
+        }
+    })
+}
+
+/// Take the action on the server.
+async fn execute_verdict<C: RconClient>(mut di: DiContainer<C>, verdict: Verdict) {
+    let mut conn = match di
+        .connection_pool
+        .get_connection(Priority::Background)
+        .await
+        .map_err(PoolError::into_inner)
+    {
+        Ok(conn) => conn,
+        Err(e) => {
+            error!("Rule {} failed to get a connection: {e}", verdict.rule);
+            return;
+        }
+    };
+
+    let id = verdict.player.id.to_string();
+    let fill = |text: &str| {
+        text.replace("{name}", &verdict.player.name)
+            .replace("{rule}", &verdict.rule)
+    };
+    let result = match &verdict.action {
+        ActionConfig::Message { text } => conn.individual_message(&id, &fill(text)).await,
+        ActionConfig::Punish { reason } => conn.punish_player(&id, &fill(reason)).await,
+        ActionConfig::Kick { reason } => conn.kick_player(&id, &fill(reason)).await,
+        ActionConfig::TempBan { hours, reason } => {
+            conn.temp_ban_player(&id, *hours, &fill(reason)).await
+        }
+    };
+    di.connection_pool.release(conn, &result).await;
+
+    match result {
+        Ok(()) => info!(
+            "Rule {} took {:?} on {} ({id})",
+            verdict.rule, verdict.action, verdict.player.name
+        ),
+        Err(e) => error!(
+            "Rule {} failed to take {:?} on {} ({id}): {e}",
+            verdict.rule, verdict.action, verdict.player.name
+        ),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use rcon::parsing::showlog::LogLine;
+
+    use crate::{
+        config::ConditionsConfig,
+        testing::{connect, player},
+    };
+
+    use super::*;
+
+    fn teamkill(timestamp: u64, killer: u64) -> RconEvent {
+        RconEvent::Log(LogLine {
+            timestamp,
+            kind: LogKind::Kill {
+                killer: player(killer),
+                killer_faction: "Allies".to_string(),
+                victim: player(killer + 100),
+                victim_faction: "Allies".to_string(),
+                is_teamkill: true,
+                weapon: "M1 GARAND".to_string(),
+            },
+        })
+    }
+
+    fn disconnect(timestamp: u64, id: u64) -> RconEvent {
+        RconEvent::Log(connect(timestamp, id, false))
+    }
+
+    fn message(text: &str) -> ActionConfig {
+        ActionConfig::Message {
+            text: text.to_string(),
+        }
+    }
+
+    /// Three teamkills within a minute, first warned then kicked.
+    fn teamkill_rule() -> RuleConfig {
+        RuleConfig {
+            name: "teamkills".to_string(),
+            trigger: TriggerConfig::Kill {
+                weapons: vec![],
+                teamkill: Some(true),
+                count: 3,
+                within_ms: Duration::from_secs(60),
+            },
+            conditions: ConditionsConfig::default(),
+            actions: vec![
+                message("warning"),
+                ActionConfig::Kick {
+                    reason: "teamkilling".to_string(),
+                },
+            ],
+            cooldown_ms: Duration::from_secs(10),
+            escalation_reset_ms: Duration::from_secs(3600),
+        }
+    }
+
+    fn config(rules: Vec<RuleConfig>) -> ModerationConfig {
+        ModerationConfig {
+            enabled: true,
+            dry_run: false,
+            rules,
+        }
+    }
+
+    #[test]
+    fn kills_trigger_only_within_their_window() {
+        let config = config(vec![teamkill_rule()]);
+        let mut moderator = Moderator::default();
+
+        for timestamp in [0, 50, 70] {
+            assert!(moderator.judge(&config, &teamkill(timestamp, 1)).is_empty());
+        }
+        let verdicts = moderator.judge(&config, &teamkill(80, 1));
+        assert_eq!(verdicts.len(), 1);
+        assert!(matches!(&verdicts[0].action, ActionConfig::Message { text } if text == "warning"));
+        assert_eq!(verdicts[0].player, player(1));
+
+        // Other players count on their own.
+        assert!(moderator.judge(&config, &teamkill(81, 2)).is_empty());
+    }
+
+    #[test]
+    fn chat_triggers_on_words() {
+        let mut rule = teamkill_rule();
+        rule.trigger = TriggerConfig::Chat {
+            words: vec!["cheater".to_string()],
+        };
+        let config = config(vec![rule]);
+        let mut moderator = Moderator::default();
+
+        let chat = |content: &str| {
+            RconEvent::Log(LogLine {
+                timestamp: 0,
+                kind: LogKind::Chat {
+                    sender: player(1),
+                    team: "Allies".to_string(),
+                    reach: "Team".to_string(),
+                    content: content.to_string(),
+                },
+            })
+        };
+        assert!(moderator.judge(&config, &chat("nice shot")).is_empty());
+        assert_eq!(moderator.judge(&config, &chat("what a CHEATER!")).len(), 1);
+    }
+
+    #[test]
+    fn conditions_require_a_known_population() {
+        let mut rule = teamkill_rule();
+        rule.trigger = TriggerConfig::Kill {
+            weapons: vec![],
+            teamkill: None,
+            count: 1,
+            within_ms: Duration::ZERO,
+        };
+        rule.conditions.min_players = Some(50);
+        let config = config(vec![rule]);
+        let mut moderator = Moderator::default();
+
+        assert!(moderator.judge(&config, &teamkill(0, 1)).is_empty());
+        moderator.population = Some(49);
+        assert!(moderator.judge(&config, &teamkill(0, 1)).is_empty());
+        moderator.population = Some(50);
+        assert_eq!(moderator.judge(&config, &teamkill(0, 1)).len(), 1);
+    }
+
+    #[test]
+    fn actions_escalate_after_the_cooldown_and_start_over() {
+        let rule = teamkill_rule();
+        let mut moderator = Moderator::default();
+        let action = |moderator: &mut Moderator, now| {
+            moderator
+                .act(&rule, &player(1), now)
+                .map(|verdict| matches!(verdict.action, ActionConfig::Kick { .. }))
+        };
+
+        assert_eq!(action(&mut moderator, 0), Some(false));
+        assert_eq!(action(&mut moderator, 5), None);
+        assert_eq!(action(&mut moderator, 10), Some(true));
+        // The last action is repeated.
+        assert_eq!(action(&mut moderator, 20), Some(true));
+        assert_eq!(action(&mut moderator, 20 + 3600), Some(false));
+    }
+
+    #[test]
+    fn forgets_expired_hits_and_escalations() {
+        let config = config(vec![teamkill_rule()]);
+        let mut moderator = Moderator::default();
+
+        moderator.judge(&config, &teamkill(0, 1));
+        moderator.judge(&config, &teamkill(0, 2));
+        moderator.act(&config.rules[0], &player(2), 0);
+        moderator.judge(&config, &disconnect(1, 2));
+        assert_eq!(moderator.hits.len(), 1);
+        // Leaving does not reset the escalation.
+        assert_eq!(moderator.escalations.len(), 1);
+
+        moderator.judge(&config, &disconnect(61, 3));
+        assert!(moderator.hits.is_empty());
+        assert_eq!(moderator.escalations.len(), 1);
+
+        moderator.judge(&config, &disconnect(3600, 3));
+        assert!(moderator.escalations.is_empty());
+    }
+
+    #[test]
+    fn matches_words_and_phrases() {
+        let words = ["noob".to_string(), "get good".to_string()];
+
+        assert!(contains_any_word("You NOOB.", &words));
+        assert!(contains_any_word("just get good", &words));
+        assert!(!contains_any_word("noobs", &words));
+        assert!(!contains_any_word("good game", &words));
+        assert!(!contains_any_word("noob", &[]));
+    }
+}
@@ -1,9 +1,11 @@
+use rcon::client::RconClient;
+
 use crate::polling::{playerinfo::poll_players, showlog::poll_showlog};
 
 use super::DiContainer;
 
 /// Start the pollers.
-pub fn start_polling(di: &DiContainer) {
+pub fn start_polling<C: RconClient>(di: &DiContainer<C>) {
     let di_copy = di.clone();
     tokio::spawn(async move { _ = poll_players(di_copy).await });
 
@@ -0,0 +1,559 @@
+//! Replaying a recorded game as if it took place live.
+use std::{
+    collections::{HashMap, HashSet},
+    sync::Arc,
+    time::Duration,
+};
+
+use rcon::parsing::{
+    player::{Player, PlayerId},
+    showlog::{LogKind, LogLine},
+};
+use tokio::{
+    sync::{mpsc, oneshot, Notify},
+    time::{sleep_until, Instant},
+};
+use tracing::{debug, info};
+use wise_api::{
+    events::{RconEvent, SquadEvent},
+    messages::{ReplayControl, ReplayStatus},
+};
+
+use crate::exporting::{queue::EventSender, recording::RecordingEntry};
+
+use super::game_master::GameSnapshot;
+
+/// A lightweight handle to control the running replay.
+#[derive(Debug, Clone)]
+pub struct ReplayHandle {
+    tx: mpsc::Sender<(ReplayControl, oneshot::Sender<ReplayStatus>)>,
+
+    /// Notified once a client is connected, the replay only starts afterwards.
+    connected: Arc<Notify>,
+}
+
+impl ReplayHandle {
+    /// Apply the control to the replay and get its status afterwards.
+    /// Returns [`None`] if the replay stopped.
+    pub async fn control(&self, control: ReplayControl) -> Option<ReplayStatus> {
+        let (tx, rx) = oneshot::channel();
+        self.tx.send((control, tx)).await.ok()?;
+        rx.await.ok()
+    }
+
+    /// Tell the replay a client receiving its events connected.
+    pub fn client_connected(&self) {
+        self.connected.notify_one();
+    }
+}
+
+/// The events of a recording and how far they have been replayed.
+struct Replay {
+    /// The events and their timestamps in the order they took place.
+    events: Vec<(u64, RconEvent)>,
+
+    /// The snapshots in the order they were taken.
+    snapshots: Vec<Snapshot>,
+
+    /// The index of the next event to emit.
+    position: usize,
+
+    /// The timestamp of the previously emitted event, the next is delayed relative to it.
+    last_emitted: Option<u64>,
+
+    /// When to emit the next event, [`None`] if not yet scheduled.
+    deadline: Option<Instant>,
+
+    /// Whether the first client connected, nothing is emitted before.
+    started: bool,
+
+    /// What the events emitted so far have shown to clients.
+    shown: Shown,
+
+    paused: bool,
+    speed: f64,
+    start: u64,
+    end: u64,
+}
+
+/// The full state of the game at one point in the recording.
+struct Snapshot {
+    timestamp: u64,
+
+    /// The index of the first event after the snapshot.
+    position: usize,
+
+    state: GameSnapshot,
+}
+
+/// The players and squads clients have been shown, those not restored by the snapshot are
+/// cleared when seeking.
+#[derive(Debug, Default)]
+struct Shown {
+    /// The names of the players by their id.
+    players: HashMap<String, String>,
+
+    /// The squads by their team and name.
+    squads: HashSet<(i32, String)>,
+}
+
+impl Shown {
+    fn apply(&mut self, event: &RconEvent) {
+        match event {
+            RconEvent::Player { new, .. } => {
+                self.players.insert(new.id.clone(), new.name.clone());
+            }
+            RconEvent::Log(LogLine {
+                kind:
+                    LogKind::Connect {
+                        player,
+                        has_connected: false,
+                    },
+                ..
+            }) => {
+                self.players.remove(&player.id.to_string());
+            }
+            RconEvent::Squad(SquadEvent::Created { squad }) => {
+                self.squads.insert((squad.team, squad.name.clone()));
+            }
+            RconEvent::Squad(SquadEvent::Disbanded { team, name }) => {
+                self.squads.remove(&(*team, name.clone()));
+            }
+            _ => {}
+        }
+    }
+
+    /// The events removing everything shown which is not shown by the `restored` as well.
+    /// Players leave by disconnecting at the unix timestamp in seconds.
+    fn clear(&self, restored: &Shown, timestamp: u64) -> Vec<RconEvent> {
+        let players = self
+            .players
+            .iter()
+            .filter(|(id, _)| !restored.players.contains_key(*id))
+            .map(|(id, name)| {
+                RconEvent::Log(LogLine {
+                    timestamp,
+                    kind: LogKind::Connect {
+                        player: Player::new(name.clone(), PlayerId::parse(id)),
+                        has_connected: false,
+                    },
+                })
+            });
+        let squads = self
+            .squads
+            .difference(&restored.squads)
+            .map(|(team, name)| {
+                RconEvent::Squad(SquadEvent::Disbanded {
+                    team: *team,
+                    name: name.clone(),
+                })
+            });
+
+        players.chain(squads).collect()
+    }
+}
+
+/// Start replaying the entries of a recording as events once the first client connects.
+/// The state of the game is conveyed by the snapshots, emitted on start and when seeking.
+pub fn start_replay(
+    entries: Vec<RecordingEntry>,
+    speed: f64,
+    paused: bool,
+    game_events: EventSender,
+) -> ReplayHandle {
+    let replay = Replay::new(entries, speed, paused);
+    let connected = Arc::new(Notify::new());
+
+    let (tx, rx) = mpsc::channel(16);
+    _ = tokio::spawn(run_replay(replay, rx, connected.clone(), game_events));
+    ReplayHandle { tx, connected }
+}
+
+async fn run_replay(
+    mut replay: Replay,
+    mut rx: mpsc::Receiver<(ReplayControl, oneshot::Sender<ReplayStatus>)>,
+    connected: Arc<Notify>,
+    game_events: EventSender,
+) {
+    info!(
+        "Replaying {} events at {}x speed once a client connects",
+        replay.events.len(),
+        replay.speed
+    );
+
+    loop {
+        let received = match replay.schedule() {
+            Some(deadline) => tokio::select! {
+                _ = sleep_until(deadline) => {
+                    replay.emit_next(&game_events);
+                    continue;
+                }
+                received = rx.recv() => received,
+            },
+            None if !replay.started => tokio::select! {
+                _ = connected.notified() => {
+                    replay.start(&game_events).await;
+                    continue;
+                }
+                received = rx.recv() => received,
+            },
+            None => rx.recv().await,
+        };
+
+        let Some((control, respond)) = received else {
+            debug!("All replay handles dropped, stopping the replay");
+            return;
+        };
+
+        replay.apply(control, &game_events).await;
+        _ = respond.send(replay.status());
+    }
+}
+
+impl Replay {
+    fn new(entries: Vec<RecordingEntry>, speed: f64, paused: bool) -> Self {
+        let mut events = Vec::new();
+        let mut snapshots = Vec::new();
+        for entry in entries {
+            match entry {
+                RecordingEntry::Event { timestamp, event } => events.push((timestamp, event)),
+                RecordingEntry::Snapshot { timestamp, state } => snapshots.push(Snapshot {
+                    timestamp,
+                    position: events.len(),
+                    state,
+                }),
+                RecordingEntry::Header { .. } => {}
+            }
+        }
+
+        Self {
+            start: events.first().map(|(t, _)| *t).unwrap_or_default(),
+            end: events.last().map(|(t, _)| *t).unwrap_or_default(),
+            events,
+            snapshots,
+            position: 0,
+            last_emitted: None,
+            deadline: None,
+            started: false,
+            shown: Shown::default(),
+            paused,
+            speed,
+        }
+    }
+
+    /// Start the replay with the state of the game at its first event.
+    async fn start(&mut self, game_events: &EventSender) {
+        debug!("Client connected, starting the replay");
+        self.started = true;
+        self.seek(self.start, game_events).await;
+    }
+
+    /// Get when to emit the next event, [`None`] if not started, paused or finished.
+    fn schedule(&mut self) -> Option<Instant> {
+        if !self.started || self.paused {
+            return None;
+        }
+
+        let (timestamp, _) = self.events.get(self.position)?;
+        if self.deadline.is_none() {
+            let delay = self
+                .last_emitted
+                .map(|last| timestamp.saturating_sub(last) as f64 / self.speed)
+                .unwrap_or_default();
+            self.deadline = Some(Instant::now() + Duration::from_secs_f64(delay / 1000.0));
+        }
+
+        self.deadline
+    }
+
+    fn emit_next(&mut self, game_events: &EventSender) {
+        self.deadline = None;
+        let Some((timestamp, event)) = self.events.get(self.position) else {
+            return;
+        };
+
+        let timestamp = *timestamp;
+        self.send(event.clone(), game_events);
+        self.last_emitted = Some(timestamp);
+        self.position += 1;
+
+        if self.position == self.events.len() {
+            info!("Replay finished, seek to replay again");
+        }
+    }
+
+    /// Emit the event and remember what it shows.
+    fn send(&mut self, event: RconEvent, game_events: &EventSender) {
+        self.shown.apply(&event);
+        game_events.send_rcon(event);
+    }
+
+    /// Continue from the first event at or after the timestamp. The closest snapshot before
+    /// it is emitted, followed by the events in between, paced rather than delayed. Players
+    /// and squads shown before, yet not part of the snapshot, are removed first.
+    async fn seek(&mut self, timestamp: u64, game_events: &EventSender) {
+        let snapshot = self
+            .snapshots
+            .iter()
+            .rev()
+            .find(|s| s.timestamp <= timestamp);
+        let (position, restoring) = match snapshot {
+            Some(snapshot) => (snapshot.position, snapshot_events(&snapshot.state)),
+            None => (0, vec![]),
+        };
+
+        let mut restored = Shown::default();
+        restoring.iter().for_each(|e| restored.apply(e));
+        let mut sending = self.shown.clear(&restored, timestamp / 1000);
+        self.shown = restored;
+        sending.extend(restoring);
+
+        self.position = position;
+        while let Some((_, event)) = self
+            .events
+            .get(self.position)
+            .filter(|(t, _)| *t < timestamp)
+        {
+            self.shown.apply(event);
+            sending.push(event.clone());
+            self.position += 1;
+        }
+        self.last_emitted = None;
+
+        // A snapshot may hold hundreds of events, pace them to not lag clients behind.
+        game_events.send_rcon_paced(sending).await;
+    }
+
+    async fn apply(&mut self, control: ReplayControl, game_events: &EventSender) {
+        debug!("Applying replay control {control:?}");
+        // Controlling the replay requires a client, as such it starts if it has not yet.
+        if !self.started && !matches!(control, ReplayControl::Status | ReplayControl::Seek(_)) {
+            self.start(game_events).await;
+        }
+
+        match control {
+            ReplayControl::Pause => self.paused = true,
+            ReplayControl::Resume => {
+                self.paused = false;
+                self.last_emitted = None;
+            }
+            ReplayControl::Step => {
+                self.paused = true;
+                self.emit_next(game_events);
+            }
+            ReplayControl::SetSpeed(speed) if speed.is_finite() && speed > 0.0 => {
+                self.speed = speed
+            }
+            ReplayControl::SetSpeed(_) => {}
+            ReplayControl::Seek(timestamp) => {
+                self.started = true;
+                self.seek(timestamp, game_events).await;
+            }
+            ReplayControl::Status => return,
+        }
+
+        self.deadline = None;
+    }
+
+    fn status(&self) -> ReplayStatus {
+        ReplayStatus {
+            position: self
+                .events
+                .get(self.position)
+                .map(|(t, _)| *t)
+                .unwrap_or(self.end),
+            start: self.start,
+            end: self.end,
+            paused: self.paused,
+            speed: self.speed,
+            finished: self.position >= self.events.len(),
+        }
+    }
+}
+
+/// Describe the full state of the game as events, as if polling just started.
+fn snapshot_events(snapshot: &GameSnapshot) -> Vec<RconEvent> {
+    let game = snapshot.game_state.iter().map(|state| RconEvent::Game {
+        changes: vec![],
+        new_state: state.clone(),
+    });
+    let players = snapshot.players.iter().map(|player| RconEvent::Player {
+        old: player.clone(),
+        new: player.clone(),
+        changes: vec![],
+    });
+    let squads = snapshot.squads.iter().map(|squad| {
+        RconEvent::Squad(SquadEvent::Created {
+            squad: squad.clone(),
+        })
+    });
+
+    game.chain(players).chain(squads).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use wise_api::{events::Squad, events::SquadKind, messages::ServerWsMessage};
+
+    use crate::{exporting::queue::EventReceiver, testing::player_data};
+
+    use super::*;
+
+    fn event(timestamp: u64, raw: &str) -> RecordingEntry {
+        RecordingEntry::Event {
+            timestamp,
+            event: RconEvent::Log(LogLine {
+                timestamp: timestamp / 1000,
+                kind: LogKind::Unknown {
+                    raw: raw.to_string(),
+                },
+            }),
+        }
+    }
+
+    fn squad(name: &str) -> Squad {
+        Squad {
+            team: 1,
+            name: name.to_string(),
+            kind: SquadKind::Infantry,
+            leader: None,
+            members: vec![],
+        }
+    }
+
+    fn snapshot(timestamp: u64, name: &str) -> RecordingEntry {
+        RecordingEntry::Snapshot {
+            timestamp,
+            state: GameSnapshot {
+                players: vec![],
+                game_state: None,
+                squads: vec![squad(name)],
+            },
+        }
+    }
+
+    fn replay() -> Replay {
+        let entries = vec![
+            RecordingEntry::Header {
+                version: 1,
+                started_at: 0,
+                map: "map".to_string(),
+            },
+            snapshot(0, "able"),
+            event(1000, "a"),
+            event(2000, "b"),
+            snapshot(2500, "baker"),
+            event(3000, "c"),
+            event(4000, "d"),
+        ];
+        Replay::new(entries, 1.0, false)
+    }
+
+    /// Describe the emitted events, the names of squads and raw logs.
+    async fn emitted(rx: &mut EventReceiver) -> Vec<String> {
+        let mut emitted = vec![];
+        while let Ok(message) = tokio::time::timeout(Duration::from_millis(10), rx.receive()).await
+        {
+            match message {
+                ServerWsMessage::Rcon(RconEvent::Squad(SquadEvent::Created { squad })) => {
+                    emitted.push(squad.name)
+                }
+                ServerWsMessage::Rcon(RconEvent::Squad(SquadEvent::Disbanded { name, .. })) => {
+                    emitted.push(format!("-{name}"))
+                }
+                ServerWsMessage::Rcon(RconEvent::Log(LogLine {
+                    kind: LogKind::Unknown { raw },
+                    ..
+                })) => emitted.push(raw),
+                ServerWsMessage::Rcon(RconEvent::Log(LogLine {
+                    kind:
+                        LogKind::Connect {
+                            player,
+                            has_connected,
+                        },
+                    ..
+                })) => emitted.push(format!(
+                    "{}{}",
+                    if has_connected { "+" } else { "-" },
+                    player.name
+                )),
+                // Players are only described when they leave.
+                ServerWsMessage::Rcon(RconEvent::Player { .. }) => {}
+                other => panic!("Unexpected message {other:?}"),
+            }
+        }
+        emitted
+    }
+
+    #[tokio::test]
+    async fn waits_for_a_client_before_emitting() {
+        let game_events = EventSender::new();
+        let mut rx = game_events.receiver();
+        let mut replay = replay();
+        assert!(replay.schedule().is_none());
+
+        replay.start(&game_events).await;
+        assert_eq!(emitted(&mut rx).await, vec!["able"]);
+        assert!(replay.schedule().is_some());
+    }
+
+    #[tokio::test]
+    async fn seeking_emits_the_closest_snapshot_and_catches_up() {
+        let game_events = EventSender::new();
+        let mut rx = game_events.receiver();
+        let mut replay = replay();
+
+        replay.apply(ReplayControl::Seek(3500), &game_events).await;
+        assert_eq!(emitted(&mut rx).await, vec!["baker", "c"]);
+        assert_eq!(replay.status().position, 4000);
+
+        replay.apply(ReplayControl::Seek(2000), &game_events).await;
+        assert_eq!(emitted(&mut rx).await, vec!["-baker", "able", "a"]);
+        assert_eq!(replay.status().position, 2000);
+    }
+
+    #[tokio::test]
+    async fn seeking_backwards_clears_what_the_snapshot_does_not_restore() {
+        let game_events = EventSender::new();
+        let mut rx = game_events.receiver();
+        let mut replay = Replay::new(
+            vec![
+                snapshot(0, "able"),
+                RecordingEntry::Event {
+                    timestamp: 1000,
+                    event: RconEvent::Player {
+                        old: player_data(1),
+                        new: player_data(1),
+                        changes: vec![],
+                    },
+                },
+                RecordingEntry::Event {
+                    timestamp: 2000,
+                    event: RconEvent::Squad(SquadEvent::Created {
+                        squad: squad("charlie"),
+                    }),
+                },
+                event(3000, "c"),
+            ],
+            1.0,
+            false,
+        );
+
+        replay.apply(ReplayControl::Seek(3000), &game_events).await;
+        assert_eq!(emitted(&mut rx).await, vec!["able", "charlie"]);
+
+        // Back before the player and squad were shown, both are removed again.
+        replay.apply(ReplayControl::Seek(500), &game_events).await;
+        assert_eq!(
+            emitted(&mut rx).await,
+            vec!["-player 1", "-charlie", "able"]
+        );
+
+        // Playing on shows them again.
+        while replay.position < 3 {
+            replay.emit_next(&game_events);
+        }
+        assert_eq!(emitted(&mut rx).await, vec!["charlie", "c"]);
+        assert_eq!(replay.shown.players.len(), 1);
+    }
+}
@@ -0,0 +1,475 @@
+//! Simulating a plausible match as a source of events, to develop against without a server.
+use std::time::Duration;
+
+use rand::{rngs::StdRng, seq::SliceRandom, Rng, SeedableRng};
+use rcon::{
+    client::RconClient,
+    parsing::{
+        gamestate::GameState,
+        player::{Player, PlayerId},
+        playerinfo::{PlayerData, ScoreData, WorldPosition},
+        showlog::{LogKind, LogLine},
+    },
+};
+use tokio::time::{interval, MissedTickBehavior};
+use tracing::info;
+
+use super::{game_master::IncomingState, DiContainer};
+
+/// Seconds a match lasts unless a team captures all sectors before.
+const MATCH_DURATION: u64 = 30 * 60;
+
+/// Seconds between the end of a match and the start of the next.
+const INTERMISSION: u64 = 30;
+
+/// Sectors needed to win a match of warfare.
+const WINNING_SCORE: u64 = 5;
+
+/// The shortest and longest time between ticks in seconds, bounding the speed.
+const TICK_PERIOD: (f64, f64) = (0.001, 3600.0);
+
+/// Half the width of the maps in centimeters, positions range from `-MAP_EXTENT` to `MAP_EXTENT`.
+const MAP_EXTENT: f64 = 100_000.0;
+
+const MAPS: [&str; 6] = [
+    "CARENTAN WARFARE",
+    "SAINTE-MÈRE-ÉGLISE WARFARE",
+    "FOY WARFARE",
+    "HURTGEN FOREST WARFARE",
+    "UTAH BEACH WARFARE",
+    "STALINGRAD WARFARE",
+];
+
+const NAMES: [&str; 12] = [
+    "Baker", "Dog", "Easy", "Fox", "George", "How", "Item", "Jig", "King", "Love", "Mike", "Nan",
+];
+
+const CLAN_TAGS: [&str; 4] = ["", "", "[7DV]", "[HLL]"];
+
+const PLATOONS: [&str; 6] = ["ABLE", "BAKER", "CHARLIE", "DOG", "EASY", "FOX"];
+
+const ALLIED_WEAPONS: [&str; 6] = [
+    "M1 GARAND",
+    "M1A1 THOMPSON",
+    "BROWNING M1919",
+    "M1 CARBINE",
+    "BAZOOKA",
+    "M2 FLAMETHROWER",
+];
+
+const AXIS_WEAPONS: [&str; 6] = [
+    "KARABINER 98K",
+    "MP40",
+    "MG42",
+    "GEWEHR 43",
+    "PANZERSCHRECK",
+    "STG44",
+];
+
+const MESSAGES: [&str; 6] = [
+    "gg",
+    "need a garrison at the middle point",
+    "tank on the left flank",
+    "who has the nodes?",
+    "push push push",
+    "medic!",
+];
+
+/// The simulated state of the server, advanced one second per tick.
+struct Simulation {
+    rng: StdRng,
+
+    /// The simulated unix timestamp in seconds.
+    time: u64,
+
+    /// The amount of players the server fills up to.
+    capacity: usize,
+    players: Vec<PlayerData>,
+
+    map: String,
+    next_map: String,
+    allied_score: u64,
+    axis_score: u64,
+    remaining_seconds: u64,
+
+    /// Seconds until the next match starts, [`None`] while a match is in progress.
+    intermission: Option<u64>,
+}
+
+/// Everything which took place in a single tick.
+#[derive(Debug, PartialEq)]
+struct Tick {
+    /// The simulated unix timestamp in seconds.
+    time: u64,
+    logs: Vec<LogLine>,
+    players: Vec<PlayerData>,
+    game_state: GameState,
+}
+
+/// Simulate matches on a server with up to `capacity` players until wise stops, feeding
+/// everything through the [`super::game_master::GameMaster`]. The same seed always
+/// results in the same matches, starting at the current time. A `speed` of `2.0` simulates two seconds every second, at
+/// most a thousand seconds are simulated every second.
+pub async fn run_simulation<C: RconClient>(
+    mut di: DiContainer<C>,
+    seed: u64,
+    capacity: usize,
+    speed: f64,
+) {
+    info!("Simulating matches with {capacity} players using seed {seed}");
+    let start = chrono::Utc::now().timestamp() as u64;
+    let mut simulation = Simulation::new(seed, capacity, start);
+    let (shortest, longest) = TICK_PERIOD;
+    // Unlike `clamp`, these never return NaN.
+    let period = (1.0 / speed).max(shortest).min(longest);
+    let mut ticks = interval(Duration::from_secs_f64(period));
+    ticks.set_missed_tick_behavior(MissedTickBehavior::Delay);
+
+    loop {
+        ticks.tick().await;
+        let tick = simulation.tick();
+
+        let di_copy = di.clone();
+        di.game_master
+            .update_state(IncomingState::Logs(tick.logs), &di_copy)
+            .await;
+        di.game_master
+            .update_state(
+                IncomingState::Players {
+                    players: tick.players,
+                    polled_at: tick.time * 1000,
+                },
+                &di_copy,
+            )
+            .await;
+        di.game_master
+            .update_state(IncomingState::GameState(tick.game_state), &di_copy)
+            .await;
+    }
+}
+
+impl Simulation {
+    /// Start simulating at the unix timestamp `start` in seconds.
+    fn new(seed: u64, capacity: usize, start: u64) -> Self {
+        let mut rng = StdRng::seed_from_u64(seed);
+        let next_map = MAPS.choose(&mut rng).unwrap().to_string();
+
+        Self {
+            rng,
+            time: start,
+            capacity,
+            players: vec![],
+            map: String::new(),
+            next_map,
+            allied_score: 0,
+            axis_score: 0,
+            remaining_seconds: 0,
+            // Start the first match right away.
+            intermission: Some(0),
+        }
+    }
+
+    /// Advance the simulation by one second.
+    fn tick(&mut self) -> Tick {
+        self.time += 1;
+        let mut logs = vec![];
+
+        match self.intermission {
+            Some(0) => self.start_match(&mut logs),
+            Some(seconds) => self.intermission = Some(seconds - 1),
+            None => self.play(&mut logs),
+        }
+
+        self.connections(&mut logs);
+        self.movement();
+
+        Tick {
+            time: self.time,
+            logs,
+            players: self.players.clone(),
+            game_state: self.game_state(),
+        }
+    }
+
+    fn start_match(&mut self, logs: &mut Vec<LogLine>) {
+        self.map = self.next_map.clone();
+        self.next_map = MAPS.choose(&mut self.rng).unwrap().to_string();
+        self.allied_score = 2;
+        self.axis_score = 2;
+        self.remaining_seconds = MATCH_DURATION;
+        self.intermission = None;
+
+        for i in 0..self.players.len() {
+            let player = &mut self.players[i];
+            player.kills = 0;
+            player.deaths = 0;
+            player.score = ScoreData {
+                combat: 0,
+                defense: 0,
+                support: 0,
+                offense: 0,
+            };
+            self.respawn(i);
+        }
+
+        self.log(
+            logs,
+            LogKind::MatchStart {
+                map: self.map.clone(),
+            },
+        );
+    }
+
+    fn end_match(&mut self, logs: &mut Vec<LogLine>) {
+        self.intermission = Some(INTERMISSION);
+        self.log(
+            logs,
+            LogKind::MatchEnded {
+                map: self.map.clone(),
+                allied_score: self.allied_score,
+                axis_score: self.axis_score,
+            },
+        );
+    }
+
+    /// Progress the match in progress.
+    fn play(&mut self, logs: &mut Vec<LogLine>) {
+        self.remaining_seconds = self.remaining_seconds.saturating_sub(1);
+
+        // Roughly every other minute a sector changes hands. The neutral sector in the
+        // middle is taken without the other team losing one.
+        if self.rng.gen_bool(1.0 / 120.0) {
+            let contested = self.allied_score + self.axis_score == WINNING_SCORE;
+            if self.rng.gen_bool(0.5) {
+                self.allied_score += 1;
+                self.axis_score -= contested as u64;
+            } else {
+                self.allied_score -= contested as u64;
+                self.axis_score += 1;
+            }
+        }
+
+        if self.players.len() >= 2
+            && self
+                .rng
+                .gen_bool((self.players.len() as f64 / 200.0).min(1.0))
+        {
+            self.kill(logs);
+        }
+
+        if !self.players.is_empty() && self.rng.gen_bool(0.02) {
+            self.chat(logs);
+        }
+
+        let decided = self.allied_score == WINNING_SCORE || self.axis_score == WINNING_SCORE;
+        if decided || self.remaining_seconds == 0 {
+            self.end_match(logs);
+        }
+    }
+
+    /// Let players join until the server is full and occasionally leave.
+    fn connections(&mut self, logs: &mut Vec<LogLine>) {
+        if self.players.len() < self.capacity && self.rng.gen_bool(0.3) {
+            let player = self.new_player();
+            let log_player = log_player(&player);
+            self.players.push(player);
+            self.respawn(self.players.len() - 1);
+            self.log(
+                logs,
+                LogKind::Connect {
+                    player: log_player,
+                    has_connected: true,
+                },
+            );
+        }
+
+        if !self.players.is_empty() && self.rng.gen_bool(0.005) {
+            let index = self.rng.gen_range(0..self.players.len());
+            let player = self.players.remove(index);
+            self.log(
+                logs,
+                LogKind::Connect {
+                    player: log_player(&player),
+                    has_connected: false,
+                },
+            );
+        }
+    }
+
+    /// Let every player wander around the map, slowly drifting towards the enemy.
+    fn movement(&mut self) {
+        for player in &mut self.players {
+            let direction = if player.team == 0 { 1.0 } else { -1.0 };
+            let position = &mut player.world_position;
+            position.x += direction * 50.0 + self.rng.gen_range(-400.0..400.0);
+            position.y += self.rng.gen_range(-400.0..400.0);
+            position.x = position.x.clamp(-MAP_EXTENT, MAP_EXTENT);
+            position.y = position.y.clamp(-MAP_EXTENT, MAP_EXTENT);
+        }
+    }
+
+    fn kill(&mut self, logs: &mut Vec<LogLine>) {
+        let killer = self.rng.gen_range(0..self.players.len());
+        let is_teamkill = self.rng.gen_bool(0.03);
+        let candidates: Vec<_> = (0..self.players.len())
+            .filter(|&i| i != killer)
+            .filter(|&i| (self.players[i].team == self.players[killer].team) == is_teamkill)
+            .collect();
+        let Some(&victim) = candidates.choose(&mut self.rng) else {
+            return;
+        };
+
+        let weapons = match self.players[killer].team {
+            0 => ALLIED_WEAPONS,
+            _ => AXIS_WEAPONS,
+        };
+        let weapon = weapons.choose(&mut self.rng).unwrap().to_string();
+
+        if !is_teamkill {
+            self.players[killer].kills += 1;
+            self.players[killer].score.combat += 3;
+        }
+        self.players[victim].deaths += 1;
+        self.players[victim].score.defense += 1;
+        self.respawn(victim);
+
+        let kind = LogKind::Kill {
+            killer: log_player(&self.players[killer]),
+            killer_faction: faction(self.players[killer].team).to_string(),
+            victim: log_player(&self.players[victim]),
+            victim_faction: faction(self.players[victim].team).to_string(),
+            is_teamkill,
+            weapon,
+        };
+        self.log(logs, kind);
+    }
+
+    fn chat(&mut self, logs: &mut Vec<LogLine>) {
+        let sender = self.players.choose(&mut self.rng).unwrap();
+        let reach = if self.rng.gen_bool(0.7) {
+            "Team"
+        } else {
+            "Unit"
+        };
+
+        let kind = LogKind::Chat {
+            sender: log_player(sender),
+            team: faction(sender.team).to_string(),
+            reach: reach.to_string(),
+            content: MESSAGES.choose(&mut self.rng).unwrap().to_string(),
+        };
+        self.log(logs, kind);
+    }
+
+    /// Place the player at the spawn of their team.
+    fn respawn(&mut self, index: usize) {
+        let spawn = if self.players[index].team == 0 {
+            -MAP_EXTENT * 0.8
+        } else {
+            MAP_EXTENT * 0.8
+        };
+
+        self.players[index].world_position = WorldPosition {
+            x: spawn + self.rng.gen_range(-2000.0..2000.0),
+            y: self.rng.gen_range(-MAP_EXTENT * 0.5..MAP_EXTENT * 0.5),
+            z: self.rng.gen_range(0.0..1000.0),
+        };
+    }
+
+    fn new_player(&mut self) -> PlayerData {
+        let allies = self.players.iter().filter(|p| p.team == 0).count();
+        let team = if allies * 2 <= self.players.len() {
+            0
+        } else {
+            1
+        };
+        let id = 76561190000000000 + self.rng.gen::<u32>() as u64;
+        let name = format!(
+            "{}{}",
+            NAMES.choose(&mut self.rng).unwrap(),
+            self.rng.gen_range(1..1000)
+        );
+
+        PlayerData {
+            name,
+            clan_tag: CLAN_TAGS.choose(&mut self.rng).unwrap().to_string(),
+            id: id.to_string(),
+            platform: "steam".to_string(),
+            level: self.rng.gen_range(1..400),
+            team,
+            eosid: format!("{:032x}", self.rng.gen::<u128>()),
+            role: self.rng.gen_range(0..12),
+            platoon: PLATOONS.choose(&mut self.rng).unwrap().to_string(),
+            kills: 0,
+            deaths: 0,
+            score: ScoreData {
+                combat: 0,
+                defense: 0,
+                support: 0,
+                offense: 0,
+            },
+            world_position: WorldPosition {
+                x: 0.0,
+                y: 0.0,
+                z: 0.0,
+            },
+            loadout: "standard issue".to_string(),
+        }
+    }
+
+    fn game_state(&self) -> GameState {
+        let allied_players = self.players.iter().filter(|p| p.team == 0).count() as u64;
+        GameState {
+            allied_players,
+            axis_players: self.players.len() as u64 - allied_players,
+            allied_score: self.allied_score,
+            axis_score: self.axis_score,
+            remaining_seconds: self.remaining_seconds,
+            map: self.map.clone(),
+            next_map: self.next_map.clone(),
+        }
+    }
+
+    fn log(&self, logs: &mut Vec<LogLine>, kind: LogKind) {
+        logs.push(LogLine {
+            timestamp: self.time,
+            kind,
+        });
+    }
+}
+
+fn log_player(player: &PlayerData) -> Player {
+    Player::new(player.name.clone(), PlayerId::parse(&player.id))
+}
+
+fn faction(team: i32) -> &'static str {
+    match team {
+        0 => "Allies",
+        _ => "Axis",
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// An hour covers a full match, its intermission and the start of the next.
+    fn simulate(seed: u64) -> Vec<Tick> {
+        let mut simulation = Simulation::new(seed, 50, 1718212472);
+        (0..3600).map(|_| simulation.tick()).collect()
+    }
+
+    #[test]
+    fn the_same_seed_simulates_the_same_matches() {
+        let ticks = simulate(7);
+        assert_eq!(ticks, simulate(7));
+        assert_ne!(ticks, simulate(8));
+
+        let logs: Vec<_> = ticks.iter().flat_map(|t| &t.logs).collect();
+        assert!(logs.iter().any(|l| matches!(l.kind, LogKind::Kill { .. })));
+        assert!(logs
+            .iter()
+            .any(|l| matches!(l.kind, LogKind::MatchEnded { .. })));
+        assert_eq!(ticks[0].time, 1718212473);
+    }
+}
@@ -0,0 +1,232 @@
+//! Assembling the squads of every team from the platoons and roles of players.
+use std::collections::BTreeMap;
+
+use rcon::parsing::playerinfo::PlayerData;
+use wise_api::events::{Squad, SquadEvent, SquadKind};
+
+/// The squads and commanders of every team.
+#[derive(Debug, Default)]
+pub struct Squads {
+    /// Squads by their team and name.
+    squads: BTreeMap<(i32, String), Squad>,
+
+    /// The id of the commander of every team which has one.
+    commanders: BTreeMap<i32, String>,
+}
+
+impl Squads {
+    /// Rebuild the squads from all players on the server and get what changed.
+    pub fn update(&mut self, players: &[PlayerData]) -> Vec<SquadEvent> {
+        let (squads, commanders) = assemble(players);
+        let mut events = vec![];
+
+        for (key, old) in &self.squads {
+            if !squads.contains_key(key) {
+                events.push(SquadEvent::Disbanded {
+                    team: old.team,
+                    name: old.name.clone(),
+                });
+            }
+        }
+
+        for (key, new) in &squads {
+            let Some(old) = self.squads.get(key) else {
+                events.push(SquadEvent::Created { squad: new.clone() });
+                continue;
+            };
+
+            if old.members != new.members || old.kind != new.kind {
+                events.push(SquadEvent::Changed { squad: new.clone() });
+            }
+            if old.leader != new.leader {
+                events.push(SquadEvent::LeaderChanged {
+                    team: new.team,
+                    name: new.name.clone(),
+                    old: old.leader.clone(),
+                    new: new.leader.clone(),
+                });
+            }
+        }
+
+        let mut teams: Vec<_> = self.commanders.keys().chain(commanders.keys()).collect();
+        teams.sort();
+        teams.dedup();
+        for team in teams {
+            let old = self.commanders.get(team);
+            let new = commanders.get(team);
+            if old != new {
+                events.push(SquadEvent::CommanderChanged {
+                    team: *team,
+                    old: old.cloned(),
+                    new: new.cloned(),
+                });
+            }
+        }
+
+        self.squads = squads;
+        self.commanders = commanders;
+        events
+    }
+
+    /// All current squads, ordered by team and name.
+    pub fn current(&self) -> Vec<Squad> {
+        self.squads.values().cloned().collect()
+    }
+}
+
+/// Group the players into squads and find the commander of every team.
+fn assemble(players: &[PlayerData]) -> (BTreeMap<(i32, String), Squad>, BTreeMap<i32, String>) {
+    let mut squads = BTreeMap::new();
+    let mut commanders = BTreeMap::new();
+
+    let mut players: Vec<_> = players.iter().collect();
+    players.sort_by(|a, b| a.id.cmp(&b.id));
+
+    for player in players {
+        let role = player.role_name();
+        if role == Some("armycommander") {
+            commanders.entry(player.team).or_insert(player.id.clone());
+            continue;
+        }
+        if player.platoon.is_empty() {
+            continue;
+        }
+
+        let squad = squads
+            .entry((player.team, player.platoon.clone()))
+            .or_insert_with(|| Squad {
+                team: player.team,
+                name: player.platoon.clone(),
+                kind: SquadKind::Infantry,
+                leader: None,
+                members: vec![],
+            });
+        squad.members.push(player.id.clone());
+
+        match role {
+            Some("crewman") => squad.kind = SquadKind::Armor,
+            Some("tankcommander") => {
+                squad.kind = SquadKind::Armor;
+                squad.leader.get_or_insert(player.id.clone());
+            }
+            Some("spotter") => {
+                squad.kind = SquadKind::Recon;
+                squad.leader.get_or_insert(player.id.clone());
+            }
+            Some("sniper") => squad.kind = SquadKind::Recon,
+            Some("officer") => {
+                squad.leader.get_or_insert(player.id.clone());
+            }
+            _ => {}
+        }
+    }
+
+    (squads, commanders)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::testing::player_data;
+
+    use super::*;
+
+    const RIFLEMAN: i32 = 0;
+    const SPOTTER: i32 = 4;
+    const OFFICER: i32 = 9;
+    const SNIPER: i32 = 10;
+    const CREWMAN: i32 = 11;
+    const TANK_COMMANDER: i32 = 12;
+    const ARMY_COMMANDER: i32 = 13;
+
+    fn player(id: u64, role: i32, platoon: &str) -> PlayerData {
+        PlayerData {
+            role,
+            platoon: platoon.to_string(),
+            ..player_data(id)
+        }
+    }
+
+    fn players() -> Vec<PlayerData> {
+        vec![
+            player(1, OFFICER, "ABLE"),
+            player(2, RIFLEMAN, "ABLE"),
+            player(3, TANK_COMMANDER, "BAKER"),
+            player(4, CREWMAN, "BAKER"),
+            player(5, SNIPER, "CHARLIE"),
+            player(6, SPOTTER, "CHARLIE"),
+            player(7, ARMY_COMMANDER, ""),
+            player(8, RIFLEMAN, ""),
+        ]
+    }
+
+    #[test]
+    fn assembles_squads_from_platoons_and_roles() {
+        let mut squads = Squads::default();
+        let events = squads.update(&players());
+
+        let created = events
+            .iter()
+            .filter(|e| matches!(e, SquadEvent::Created { .. }))
+            .count();
+        assert_eq!(created, 3);
+        assert!(events.iter().any(|e| matches!(
+            e,
+            SquadEvent::CommanderChanged { team: 0, old: None, new: Some(id) } if id == "7"
+        )));
+
+        let current = squads.current();
+        let summary: Vec<_> = current
+            .iter()
+            .map(|s| {
+                (
+                    s.name.as_str(),
+                    s.kind,
+                    s.leader.as_deref(),
+                    s.members.len(),
+                )
+            })
+            .collect();
+        assert_eq!(
+            summary,
+            vec![
+                ("ABLE", SquadKind::Infantry, Some("1"), 2),
+                ("BAKER", SquadKind::Armor, Some("3"), 2),
+                ("CHARLIE", SquadKind::Recon, Some("6"), 2),
+            ]
+        );
+    }
+
+    #[test]
+    fn unchanged_squads_emit_nothing() {
+        let mut squads = Squads::default();
+        squads.update(&players());
+        assert!(squads.update(&players()).is_empty());
+    }
+
+    #[test]
+    fn reports_leaving_leaders_and_disbanded_squads() {
+        let mut squads = Squads::default();
+        squads.update(&players());
+
+        let mut remaining = players();
+        remaining.retain(|p| p.id != "1" && p.id != "7");
+        let events = squads.update(&remaining);
+        assert!(events.iter().any(
+            |e| matches!(e, SquadEvent::Changed { squad } if squad.members == vec!["2".to_string()])
+        ));
+        assert!(events.iter().any(|e| matches!(
+            e,
+            SquadEvent::LeaderChanged { name, new: None, .. } if name == "ABLE"
+        )));
+        assert!(events
+            .iter()
+            .any(|e| matches!(e, SquadEvent::CommanderChanged { new: None, .. })));
+
+        remaining.retain(|p| p.id != "2");
+        let events = squads.update(&remaining);
+        assert!(matches!(
+            events.as_slice(),
+            [SquadEvent::Disbanded { name, .. }] if name == "ABLE"
+        ));
+    }
+}
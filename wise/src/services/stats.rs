@@ -0,0 +1,365 @@
+//! Aggregating the statistics of every player over a match.
+use std::collections::{BTreeMap, HashMap};
+
+use rcon::parsing::{
+    playerinfo::{PlayerData, ScoreData},
+    showlog::{LogKind, LogLine},
+};
+use wise_api::events::{MatchSummary, Nemesis, PlayerStats};
+
+/// The statistics of the current match, built from logs and player snapshots.
+/// Time is measured by the timestamps of the logs.
+#[derive(Debug, Default)]
+pub struct MatchStats {
+    /// The map of the match, [`None`] in between matches or if the start was not seen.
+    map: Option<String>,
+    started_at: Option<u64>,
+
+    /// The timestamp of the most recent log.
+    clock: u64,
+
+    players: HashMap<String, PlayerTally>,
+}
+
+/// The running statistics of a single player.
+#[derive(Debug, Default)]
+struct PlayerTally {
+    name: String,
+    kills: u64,
+    deaths: u64,
+    teamkills: u64,
+    weapons: BTreeMap<String, u64>,
+    killstreak: u64,
+    longest_killstreak: u64,
+
+    /// Names and kills of every victim by their id.
+    victims: HashMap<String, (String, u64)>,
+
+    /// Seconds connected before `connected_since`.
+    playtime_secs: u64,
+
+    /// When the player connected, [`None`] while disconnected.
+    connected_since: Option<u64>,
+    score: Option<ScoreData>,
+}
+
+impl PlayerTally {
+    /// A fresh tally for a player still connected since `now`.
+    fn carry_over(&self, now: u64) -> Option<Self> {
+        self.connected_since?;
+        Some(Self {
+            name: self.name.clone(),
+            connected_since: Some(now),
+            ..Default::default()
+        })
+    }
+
+    fn playtime_secs(&self, now: u64) -> u64 {
+        let connected = self
+            .connected_since
+            .map(|since| now.saturating_sub(since))
+            .unwrap_or_default();
+        self.playtime_secs + connected
+    }
+}
+
+impl MatchStats {
+    /// Update the statistics from a log, returning the summary of the match once it ended.
+    pub fn record_log(&mut self, log: &LogLine) -> Option<MatchSummary> {
+        let now = log.timestamp;
+        self.clock = self.clock.max(now);
+
+        match &log.kind {
+            LogKind::MatchStart { map } => {
+                self.reset(now);
+                self.map = Some(map.clone());
+                self.started_at = Some(now);
+            }
+            LogKind::MatchEnded {
+                map,
+                allied_score,
+                axis_score,
+            } => {
+                let summary = self.summarize(map, now, *allied_score, *axis_score);
+                self.reset(now);
+                return Some(summary);
+            }
+            LogKind::Connect {
+                player,
+                has_connected: true,
+            } => {
+                let tally = self.tally(&player.id.to_string(), &player.name);
+                tally.connected_since.get_or_insert(now);
+            }
+            LogKind::Connect {
+                player,
+                has_connected: false,
+            } => {
+                let tally = self.tally(&player.id.to_string(), &player.name);
+                if let Some(since) = tally.connected_since.take() {
+                    tally.playtime_secs += now.saturating_sub(since);
+                }
+            }
+            LogKind::Kill {
+                killer,
+                victim,
+                is_teamkill,
+                weapon,
+                ..
+            } => {
+                let victim_id = victim.id.to_string();
+                let killer = self.tally(&killer.id.to_string(), &killer.name);
+                if *is_teamkill {
+                    killer.teamkills += 1;
+                } else {
+                    killer.kills += 1;
+                    killer.killstreak += 1;
+                    killer.longest_killstreak = killer.longest_killstreak.max(killer.killstreak);
+                    *killer.weapons.entry(weapon.clone()).or_default() += 1;
+
+                    let (_, kills) = killer
+                        .victims
+                        .entry(victim_id.clone())
+                        .or_insert_with(|| (victim.name.clone(), 0));
+                    *kills += 1;
+                }
+
+                let victim = self.tally(&victim_id, &victim.name);
+                victim.deaths += 1;
+                victim.killstreak = 0;
+            }
+            LogKind::TeamSwitch { .. } | LogKind::Chat { .. } | LogKind::Unknown { .. } => {}
+        }
+
+        None
+    }
+
+    /// Update the statistics from a snapshot of a player.
+    pub fn record_player(&mut self, player: &PlayerData) {
+        let clock = self.clock;
+        let tally = self.tally(&player.id, &player.name);
+        tally.score = Some(player.score.clone());
+
+        // Players connected before wise started have not been seen connecting.
+        if tally.connected_since.is_none() && clock > 0 {
+            tally.connected_since = Some(clock);
+        }
+    }
+
+    /// Start over, keeping the players which are still connected.
+    fn reset(&mut self, now: u64) {
+        self.map = None;
+        self.started_at = None;
+        self.players = self
+            .players
+            .iter()
+            .filter_map(|(id, tally)| Some((id.clone(), tally.carry_over(now)?)))
+            .collect();
+    }
+
+    fn tally(&mut self, id: &str, name: &str) -> &mut PlayerTally {
+        let tally = self.players.entry(id.to_string()).or_default();
+        tally.name = name.to_string();
+        tally
+    }
+
+    fn summarize(&self, map: &str, now: u64, allied_score: u64, axis_score: u64) -> MatchSummary {
+        let mut players: Vec<_> = self
+            .players
+            .iter()
+            .map(|(id, tally)| {
+                let playtime_secs = tally.playtime_secs(now);
+                let nemesis = tally
+                    .victims
+                    .iter()
+                    .max_by_key(|(id, (_, kills))| (*kills, *id))
+                    .map(|(id, (name, kills))| Nemesis {
+                        id: id.clone(),
+                        name: name.clone(),
+                        kills: *kills,
+                    });
+
+                PlayerStats {
+                    id: id.clone(),
+                    name: tally.name.clone(),
+                    kills: tally.kills,
+                    deaths: tally.deaths,
+                    teamkills: tally.teamkills,
+                    kill_death_ratio: tally.kills as f64 / tally.deaths.max(1) as f64,
+                    kills_per_minute: match playtime_secs {
+                        0 => 0.0,
+                        secs => tally.kills as f64 * 60.0 / secs as f64,
+                    },
+                    weapons: tally.weapons.clone(),
+                    longest_killstreak: tally.longest_killstreak,
+                    nemesis,
+                    playtime_secs,
+                    score: tally.score.clone(),
+                }
+            })
+            .collect();
+        players.sort_by(|a, b| {
+            b.kills
+                .cmp(&a.kills)
+                .then(a.deaths.cmp(&b.deaths))
+                .then(a.id.cmp(&b.id))
+        });
+
+        MatchSummary {
+            map: self.map.clone().unwrap_or_else(|| map.to_string()),
+            started_at: self.started_at,
+            ended_at: now,
+            allied_score,
+            axis_score,
+            players,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::testing::{connect, log, player};
+
+    use super::*;
+
+    fn kill(timestamp: u64, killer: u64, victim: u64, is_teamkill: bool) -> LogLine {
+        log(
+            timestamp,
+            LogKind::Kill {
+                killer: player(killer),
+                killer_faction: "Allies".to_string(),
+                victim: player(victim),
+                victim_faction: "Axis".to_string(),
+                is_teamkill,
+                weapon: "M1 GARAND".to_string(),
+            },
+        )
+    }
+
+    fn start(timestamp: u64) -> LogLine {
+        log(
+            timestamp,
+            LogKind::MatchStart {
+                map: "CARENTAN".to_string(),
+            },
+        )
+    }
+
+    fn end(timestamp: u64) -> LogLine {
+        log(
+            timestamp,
+            LogKind::MatchEnded {
+                map: "CARENTAN".to_string(),
+                allied_score: 5,
+                axis_score: 0,
+            },
+        )
+    }
+
+    /// Record all logs and return the summary of the match they ended.
+    fn summarize(stats: &mut MatchStats, logs: &[LogLine]) -> MatchSummary {
+        let summaries: Vec<_> = logs.iter().filter_map(|l| stats.record_log(l)).collect();
+        assert_eq!(summaries.len(), 1);
+        summaries.into_iter().next().unwrap()
+    }
+
+    fn stats_of(summary: &MatchSummary, id: u64) -> &PlayerStats {
+        let id = id.to_string();
+        summary.players.iter().find(|p| p.id == id).unwrap()
+    }
+
+    #[test]
+    fn summarizes_kills_deaths_and_nemesis() {
+        let mut stats = MatchStats::default();
+        let summary = summarize(
+            &mut stats,
+            &[
+                start(0),
+                connect(0, 1, true),
+                connect(0, 2, true),
+                kill(10, 1, 2, false),
+                kill(20, 1, 2, false),
+                kill(30, 2, 1, false),
+                kill(40, 1, 3, true),
+                end(600),
+            ],
+        );
+
+        assert_eq!(summary.map, "CARENTAN");
+        assert_eq!(summary.started_at, Some(0));
+        assert_eq!(summary.players[0].id, "1");
+
+        let first = stats_of(&summary, 1);
+        assert_eq!((first.kills, first.deaths, first.teamkills), (2, 1, 1));
+        assert_eq!(first.weapons.get("M1 GARAND"), Some(&2));
+        assert_eq!(first.longest_killstreak, 2);
+        assert_eq!(first.nemesis.as_ref().map(|n| n.kills), Some(2));
+        assert_eq!(first.kills_per_minute, 0.2);
+
+        let second = stats_of(&summary, 2);
+        assert_eq!((second.kills, second.deaths), (1, 2));
+        assert_eq!(second.kill_death_ratio, 0.5);
+    }
+
+    #[test]
+    fn killstreaks_end_on_death() {
+        let mut stats = MatchStats::default();
+        let summary = summarize(
+            &mut stats,
+            &[
+                start(0),
+                kill(1, 1, 2, false),
+                kill(2, 2, 1, false),
+                kill(3, 1, 2, false),
+                kill(4, 1, 2, false),
+                kill(5, 1, 2, false),
+                kill(6, 2, 1, false),
+                kill(7, 1, 2, false),
+                end(10),
+            ],
+        );
+
+        assert_eq!(stats_of(&summary, 1).longest_killstreak, 3);
+    }
+
+    #[test]
+    fn playtime_only_counts_while_connected() {
+        let mut stats = MatchStats::default();
+        let summary = summarize(
+            &mut stats,
+            &[
+                start(0),
+                connect(0, 1, true),
+                connect(100, 1, false),
+                connect(400, 1, true),
+                connect(50, 2, true),
+                end(600),
+            ],
+        );
+
+        assert_eq!(stats_of(&summary, 1).playtime_secs, 300);
+        assert_eq!(stats_of(&summary, 2).playtime_secs, 550);
+    }
+
+    #[test]
+    fn only_connected_players_carry_over_to_the_next_match() {
+        let mut stats = MatchStats::default();
+        summarize(
+            &mut stats,
+            &[
+                start(0),
+                connect(0, 1, true),
+                connect(0, 2, true),
+                kill(10, 1, 2, false),
+                connect(20, 2, false),
+                end(600),
+            ],
+        );
+
+        // The intermission between both matches does not count as playtime.
+        let summary = summarize(&mut stats, &[start(630), end(930)]);
+        assert_eq!(summary.players.len(), 1);
+        let first = stats_of(&summary, 1);
+        assert_eq!((first.kills, first.playtime_secs), (0, 300));
+    }
+}
@@ -0,0 +1,78 @@
+//! Fixtures shared by the tests of all modules.
+use std::path::Path;
+
+use config::{Config, File};
+use rcon::parsing::{
+    player::{Player, PlayerId},
+    playerinfo::{PlayerData, ScoreData, WorldPosition},
+    showlog::{LogKind, LogLine},
+};
+use tokio::sync::watch;
+
+use crate::config::{AppConfig, FileConfig};
+
+/// The example config shipped with wise, with a server address filled in.
+pub fn file_config() -> FileConfig {
+    let path = Path::new(env!("CARGO_MANIFEST_DIR")).join("config.toml");
+    Config::builder()
+        .add_source(File::from(path))
+        .set_override("rcon.address", "127.0.0.1:7779")
+        .unwrap()
+        .build()
+        .unwrap()
+        .try_deserialize()
+        .unwrap()
+}
+
+/// A config which never changes.
+pub fn config(file_config: FileConfig) -> AppConfig {
+    watch::channel(file_config).1
+}
+
+/// A player on Steam, named after their id.
+pub fn player(id: u64) -> Player {
+    Player::new(format!("player {id}"), PlayerId::Steam(id))
+}
+
+/// The polled data of [`player`], a rifleman on team `0` without a known position.
+pub fn player_data(id: u64) -> PlayerData {
+    PlayerData {
+        name: format!("player {id}"),
+        clan_tag: String::new(),
+        id: PlayerId::Steam(id).to_string(),
+        platform: "steam".to_string(),
+        level: 1,
+        team: 0,
+        eosid: String::new(),
+        role: 0,
+        platoon: String::new(),
+        kills: 0,
+        deaths: 0,
+        score: ScoreData {
+            combat: 0,
+            defense: 0,
+            support: 0,
+            offense: 0,
+        },
+        world_position: WorldPosition {
+            x: 0.0,
+            y: 0.0,
+            z: 0.0,
+        },
+        loadout: String::new(),
+    }
+}
+
+pub fn log(timestamp: u64, kind: LogKind) -> LogLine {
+    LogLine { timestamp, kind }
+}
+
+pub fn connect(timestamp: u64, id: u64, has_connected: bool) -> LogLine {
+    log(
+        timestamp,
+        LogKind::Connect {
+            player: player(id),
+            has_connected,
+        },
+    )
+}
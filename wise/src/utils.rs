@@ -6,6 +6,6 @@ pub const fn get_levelfilter(num: i32) -> LevelFilter {
         -1 => LevelFilter::WARN,
         1 => LevelFilter::DEBUG,
         2 => LevelFilter::TRACE,
-        0 | _ => LevelFilter::INFO,
+        _ => LevelFilter::INFO,
     }
 }
@@ -1,13 +1,16 @@
 //! Objects related to RCON events emitted.
 
+use std::collections::BTreeMap;
+
 use rcon::parsing::{
     gamestate::GameState,
-    playerinfo::{PlayerData, WorldPosition},
-    showlog::LogLine,
+    playerinfo::{PlayerData, ScoreData, WorldPosition},
+    showlog::{LogKind, LogLine},
 };
 use serde::{Deserialize, Serialize};
 
 /// Any type of event that took place on the Hell Let Loose server.
+#[allow(clippy::large_enum_variant)]
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub enum RconEvent {
     /// An event related to a player took place. Should changes be empty
@@ -21,11 +24,207 @@ pub enum RconEvent {
     /// A single new log message. All logs are individual.
     Log(LogLine),
 
+    /// A log message of the current match which took place before wise started. These are
+    /// emitted once on startup, websocket clients connecting later receive them first.
+    HistoricalLog(LogLine),
+
     /// An event related to the match itself took place.
     Game {
         changes: Vec<GameStateChanges>,
         new_state: GameState,
     },
+
+    /// The statistics of a match which just ended, emitted after its `MatchEnded` log.
+    MatchSummary(MatchSummary),
+
+    /// A kill with what was known about both players, emitted after its `Kill` log.
+    KillDetails(KillDetails),
+
+    /// The composition or leadership of squads changed.
+    Squad(SquadEvent),
+}
+
+/// Changes to the squads of a team, players are referred to by their ids.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum SquadEvent {
+    /// The first player joined the squad.
+    Created { squad: Squad },
+
+    /// Players joined or left the squad, or its kind changed.
+    Changed { squad: Squad },
+
+    /// The last player left the squad.
+    Disbanded { team: i32, name: String },
+
+    /// The player leading the squad changed, [`None`] if the squad has no leader.
+    LeaderChanged {
+        team: i32,
+        name: String,
+        old: Option<String>,
+        new: Option<String>,
+    },
+
+    /// The commander of the team changed, [`None`] if the team has no commander.
+    CommanderChanged {
+        team: i32,
+        old: Option<String>,
+        new: Option<String>,
+    },
+}
+
+/// A squad, or platoon as RCON calls them, of a team.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Squad {
+    pub team: i32,
+    pub name: String,
+    pub kind: SquadKind,
+
+    /// The officer, tank commander or spotter of the squad.
+    pub leader: Option<String>,
+
+    /// The ids of all members, including the leader, ordered by id.
+    pub members: Vec<String>,
+}
+
+/// The kind of squad, inferred from the roles of its members.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum SquadKind {
+    Infantry,
+    Armor,
+    Recon,
+}
+
+/// A kill enriched with the latest known state of the killer and victim.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct KillDetails {
+    /// Unix timestamp in seconds of the kill.
+    pub timestamp: u64,
+    pub killer: KillParticipant,
+    pub victim: KillParticipant,
+    pub weapon: String,
+    pub is_teamkill: bool,
+
+    /// The distance between both players in meters, [`None`] if either position is unknown.
+    pub distance: Option<f64>,
+}
+
+/// The killer or victim of a kill.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct KillParticipant {
+    pub id: String,
+    pub name: String,
+    pub faction: String,
+
+    /// The latest known position, role and loadout, [`None`] if the player was never polled
+    /// or, for the position, has not spawned.
+    pub position: Option<WorldPosition>,
+    pub role: Option<i32>,
+    pub loadout: Option<String>,
+}
+
+/// The scoreboard and statistics of every player of a match.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct MatchSummary {
+    pub map: String,
+
+    /// Unix timestamps in seconds of the start and end of the match.
+    /// The start is [`None`] if wise started polling mid match.
+    pub started_at: Option<u64>,
+    pub ended_at: u64,
+
+    pub allied_score: u64,
+    pub axis_score: u64,
+
+    /// The statistics of every player which took part, most kills first.
+    pub players: Vec<PlayerStats>,
+}
+
+/// The statistics of a single player over a match.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PlayerStats {
+    pub id: String,
+    pub name: String,
+
+    /// Kills of enemies, teamkills are counted separately.
+    pub kills: u64,
+    pub deaths: u64,
+    pub teamkills: u64,
+
+    /// Kills per death, the kills themselves if the player never died.
+    pub kill_death_ratio: f64,
+    pub kills_per_minute: f64,
+
+    /// Kills by weapon.
+    pub weapons: BTreeMap<String, u64>,
+
+    /// The most kills without dying in between.
+    pub longest_killstreak: u64,
+
+    /// The player killed most often by this player.
+    pub nemesis: Option<Nemesis>,
+
+    /// Seconds the player was connected during the match.
+    pub playtime_secs: u64,
+
+    /// The last known score, [`None`] if the player was never polled.
+    pub score: Option<ScoreData>,
+}
+
+/// The player killed most often by another player.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Nemesis {
+    pub id: String,
+    pub name: String,
+    pub kills: u64,
+}
+
+/// The kinds of [`RconEvent`], logs are distinguished by the kind of log.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub enum EventKind {
+    Player,
+    Game,
+    Connect,
+    Disconnect,
+    TeamSwitch,
+    Kill,
+    MatchStart,
+    MatchEnded,
+    Chat,
+    Unknown,
+    MatchSummary,
+    KillDetails,
+    Squad,
+}
+
+impl RconEvent {
+    /// Get the kind of event, historical logs are of the same kind as live ones.
+    pub fn kind(&self) -> EventKind {
+        let log = match self {
+            RconEvent::Player { .. } => return EventKind::Player,
+            RconEvent::Game { .. } => return EventKind::Game,
+            RconEvent::MatchSummary(_) => return EventKind::MatchSummary,
+            RconEvent::KillDetails(_) => return EventKind::KillDetails,
+            RconEvent::Squad(_) => return EventKind::Squad,
+            RconEvent::Log(log) | RconEvent::HistoricalLog(log) => log,
+        };
+
+        match log.kind {
+            LogKind::Connect {
+                has_connected: true,
+                ..
+            } => EventKind::Connect,
+            LogKind::Connect {
+                has_connected: false,
+                ..
+            } => EventKind::Disconnect,
+            LogKind::TeamSwitch { .. } => EventKind::TeamSwitch,
+            LogKind::Kill { .. } => EventKind::Kill,
+            LogKind::MatchStart { .. } => EventKind::MatchStart,
+            LogKind::MatchEnded { .. } => EventKind::MatchEnded,
+            LogKind::Chat { .. } => EventKind::Chat,
+            LogKind::Unknown { .. } => EventKind::Unknown,
+        }
+    }
 }
 
 /// All the values that can change for a [`GameState`].
@@ -87,6 +286,23 @@ pub enum PlayerChanges {
         old: WorldPosition,
         new: WorldPosition,
     },
+
+    /// Inferred from the speed, in meters per second, the player recently moved at.
+    /// The confidence, between 0 and 1, is that of the new value.
+    InVehicle {
+        old: bool,
+        new: bool,
+        speed: f64,
+        confidence: f64,
+    },
+
+    /// Inferred from a death followed by the player reappearing away from where they died.
+    /// The respawn time is an upper bound, the respawn took place since the previous poll.
+    Respawned {
+        position: WorldPosition,
+        respawn_secs: f64,
+        confidence: f64,
+    },
 }
 
 /// The different kinds of scores.
@@ -6,9 +6,10 @@ use rcon::{
 };
 use serde::{Deserialize, Serialize};
 
-use super::events::RconEvent;
+use super::events::{EventKind, RconEvent};
 
 /// All possible messages emitted over the websocket.
+#[allow(clippy::large_enum_variant)]
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub enum ServerWsMessage {
     /// An RCON event has taken place.
@@ -37,6 +38,112 @@ pub enum ClientWsMessage {
 pub enum ClientWsRequest {
     /// Execute a command on the HLL server and return the response.
     Execute(CommandRequestKind),
+
+    /// Control the replay of a recorded game, only available when wise replays one.
+    /// The replay is shared, as such all clients are affected and `write_rcon` is required.
+    Replay(ReplayControl),
+
+    /// Query previously emitted events, only available when wise persists them to SQLite.
+    QueryEvents(EventQuery),
+}
+
+/// Filters for previously emitted events, all of which must match.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct EventQuery {
+    /// Unix timestamp in seconds of the earliest event, inclusive.
+    #[serde(default)]
+    pub from: Option<u64>,
+
+    /// Unix timestamp in seconds of the latest event, exclusive.
+    #[serde(default)]
+    pub to: Option<u64>,
+
+    /// Only events which took place in the match, ids are those of the persisted matches.
+    #[serde(default)]
+    pub match_id: Option<i64>,
+
+    /// Only events involving the player, such as kills, chat and changes to the player.
+    #[serde(default)]
+    pub player_id: Option<String>,
+
+    /// Only events of these kinds, all kinds if empty.
+    #[serde(default)]
+    pub kinds: Vec<EventKind>,
+
+    /// Only chat messages containing the text, case insensitive.
+    #[serde(default)]
+    pub text: Option<String>,
+
+    /// Continue after the event with this id, the `next` of the previous page.
+    #[serde(default)]
+    pub after: Option<u64>,
+
+    /// The maximum number of events to return, capped by the server.
+    #[serde(default)]
+    pub limit: Option<u32>,
+}
+
+/// A page of events matching an [`EventQuery`], oldest first.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct EventPage {
+    pub events: Vec<StoredEvent>,
+
+    /// Pass as `after` to get the next page, [`None`] if this is the last one.
+    pub next: Option<u64>,
+}
+
+/// An event as it was persisted.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct StoredEvent {
+    /// Uniquely identifies the event, ascending in the order events were emitted.
+    pub id: u64,
+
+    /// Unix timestamp in seconds the event took place at.
+    pub timestamp: u64,
+
+    /// The match the event took place in, if known.
+    pub match_id: Option<i64>,
+
+    pub event: RconEvent,
+}
+
+/// Controls for the replay of a recorded game.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum ReplayControl {
+    /// Stop emitting events until resumed.
+    Pause,
+
+    /// Continue emitting events at the current speed.
+    Resume,
+
+    /// Emit only the next event, the replay is paused afterwards.
+    Step,
+
+    /// Change the speed, `2.0` replays twice as fast as the game took place.
+    SetSpeed(f64),
+
+    /// Continue the replay from the first event at or after the unix timestamp in milliseconds.
+    Seek(u64),
+
+    /// Get the current status without changing it.
+    Status,
+}
+
+/// The current state of a replay.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ReplayStatus {
+    /// Unix timestamp in milliseconds of the next event to emit.
+    pub position: u64,
+
+    /// Unix timestamps in milliseconds of the first and last entry of the recording.
+    pub start: u64,
+    pub end: u64,
+
+    pub paused: bool,
+    pub speed: f64,
+
+    /// Whether all events have been emitted.
+    pub finished: bool,
 }
 
 /// The server responds to a previously send request by the client.
@@ -51,6 +158,24 @@ pub enum ServerWsResponse {
         /// The response from the HLL server, None if failed.
         response: Option<CommandResponseKind>,
     },
+
+    /// The status of the replay after applying the control.
+    Replay {
+        /// Indicates whether wise is not replaying a game.
+        failure: bool,
+
+        /// The status of the replay, None if failed.
+        status: Option<ReplayStatus>,
+    },
+
+    /// The events matching the query.
+    QueryEvents {
+        /// Indicates whether events are not persisted or could not be queried.
+        failure: bool,
+
+        /// The matching events, None if failed.
+        page: Option<EventPage>,
+    },
 }
 
 /// All commands that a client can wish to execute.
@@ -92,14 +217,12 @@ pub enum CommandRequestKind {
     KickPlayer(String, String),
 
     /// Temporarily ban a player off the server.
-    ///
-    /// If enabled this will be done with CRCON.
-    TemporaryBan(),
+    /// Provide the player id, the duration in hours and the reason.
+    TemporaryBan(String, u32, String),
 
     /// Remove a temporary ban for a player.
-    ///
-    /// If enabled this will be done with CRCON.
-    RemoveTemporaryBan(),
+    /// Provide the player id.
+    RemoveTemporaryBan(String),
 }
 
 /// For each request what the server responds with.